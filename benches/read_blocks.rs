@@ -0,0 +1,106 @@
+// Copyright (c) 2020 zenoxygen
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Benchmark reading blocks of pieces from a peer, counting allocations per block.
+//!
+//! Run with `cargo bench --bench read_blocks`.
+
+use xerus::client::*;
+use xerus::message::*;
+use xerus::peer::*;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Instant;
+
+const BLOCK_LEN: usize = 16384;
+const NB_BLOCKS: usize = 20_000;
+
+// Allocator counting allocations, to tell how many are made per block
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn main() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut remote = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (conn, _) = listener.accept().unwrap();
+    let mut client = Client::from_stream(Peer::new(), vec![], vec![], 1, conn);
+
+    // Send blocks from another thread, encoded before measuring
+    let mut message = vec![];
+    message.extend_from_slice(&(9 + BLOCK_LEN as u32).to_be_bytes());
+    message.push(MESSAGE_PIECE);
+    message.extend_from_slice(&[0; 8]);
+    message.extend_from_slice(&[0xAB; BLOCK_LEN]);
+    let sender = thread::spawn(move || {
+        for _ in 0..NB_BLOCKS {
+            remote.write_all(&message).unwrap();
+        }
+    });
+
+    // Read blocks, copying them into a piece buffer as a worker does
+    let mut piece = vec![0; BLOCK_LEN];
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..NB_BLOCKS {
+        match client.read_message().unwrap() {
+            MessageKind::Piece { block, .. } => {
+                piece.copy_from_slice(&block);
+                client.recycle_block(block);
+            }
+            _ => panic!("expected MESSAGE_PIECE"),
+        }
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    sender.join().unwrap();
+
+    println!(
+        "Read {:?} blocks of {:?} bytes in {:?} ms, {:.0} MiB/s, {:.2} allocations per block",
+        NB_BLOCKS,
+        BLOCK_LEN,
+        elapsed.as_millis(),
+        (NB_BLOCKS * BLOCK_LEN) as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0),
+        allocations as f64 / NB_BLOCKS as f64
+    );
+}
@@ -29,16 +29,244 @@ pub fn parse_args<'a>() -> clap::ArgMatches<'a> {
         .arg(
             Arg::with_name("torrent")
                 .short("t")
-                .help("The path to the torrent")
+                .help("The path to the torrent, repeat to download several torrents concurrently")
                 .number_of_values(1)
+                .multiple(true)
                 .required(true),
         )
         .arg(
             Arg::with_name("file")
                 .short("f")
-                .help("The path where to save the file")
+                .help("The path where to save the file, or directory for a multi-file torrent, repeat once per torrent")
                 .number_of_values(1)
-                .required(true),
+                .multiple(true)
+                .required_unless_one(&["discard", "list-peers", "stdout", "recompute-hash"]),
+        )
+        .arg(
+            Arg::with_name("force")
+                .long("force")
+                .help("Overwrite existing files that are not a previous download of the torrent, rather than resuming them"),
+        )
+        .arg(
+            Arg::with_name("discard")
+                .long("discard")
+                .help("Verify downloaded pieces without saving them, to benchmark network")
+                .conflicts_with_all(&["file", "listen", "on-complete", "force"]),
+        )
+        .arg(
+            Arg::with_name("stdout")
+                .long("stdout")
+                .help("Write downloaded data to stdout in order without saving it, to pipe it to another program")
+                .conflicts_with_all(&["file", "discard", "listen", "on-complete", "in-memory", "preallocate", "priority-file", "retries", "force"]),
+        )
+        .arg(
+            Arg::with_name("list-peers")
+                .long("list-peers")
+                .help("Print peers announced by trackers and exit, without connecting to them")
+                .conflicts_with_all(&["file", "discard", "stdout", "listen", "on-complete"]),
+        )
+        .arg(
+            Arg::with_name("recompute-hash")
+                .long("recompute-hash")
+                .help("Print the info hash computed from decoded keys and from raw bytes of the torrent, and exit")
+                .hidden(true)
+                .conflicts_with_all(&["file", "discard", "stdout", "list-peers"]),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("Only print errors")
+                .conflicts_with("verbose"),
+        )
+        .arg(
+            Arg::with_name("no-progress")
+                .long("no-progress")
+                .help("Print progress as plain lines from time to time rather than as a bar, as done when stderr is not a terminal"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("Print informations, repeat for debug logs")
+                .multiple(true),
+        )
+        .arg(
+            Arg::with_name("trackers")
+                .long("trackers")
+                .help("The path to a list of additional trackers to announce to, one URL per line")
+                .value_name("file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("expect-hash")
+                .long("expect-hash")
+                .help("The info hash the torrent must have, in hexadecimal, checked before contacting trackers")
+                .value_name("hex")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bind")
+                .long("bind")
+                .help("The local IP address to connect to trackers and peers from")
+                .value_name("ip")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scrape")
+                .long("scrape")
+                .help("Report seeders and leechers from trackers before download, warning if there is no seeder"),
+        )
+        .arg(
+            Arg::with_name("require-seeders")
+                .long("require-seeders")
+                .help("Abort download when trackers report no seeder")
+                .requires("scrape"),
+        )
+        .arg(
+            Arg::with_name("check-peers")
+                .long("check-peers")
+                .help("Handshake with a sample of peers before download, reporting how many are reachable"),
+        )
+        .arg(
+            Arg::with_name("listen")
+                .long("listen")
+                .help("The port to accept incoming connections on")
+                .value_name("port")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-inflight-pieces")
+                .long("max-inflight-pieces")
+                .help("The maximum number of pieces downloaded simultaneously, across all peers [default: 16]")
+                .value_name("n")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-memory")
+                .long("max-memory")
+                .help("The maximum size in bytes of pieces being downloaded, limiting pieces downloaded simultaneously")
+                .value_name("bytes")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-connecting")
+                .long("max-connecting")
+                .help("The maximum number of connection attempts at once, across all torrents [default: 20]")
+                .value_name("n")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-peers")
+                .long("max-peers")
+                .help("The maximum number of peers connected at once [default: 50]")
+                .value_name("n")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-pieces-per-peer")
+                .long("max-pieces-per-peer")
+                .help("The maximum number of pieces downloaded at once from a single peer [default: 1]")
+                .value_name("n")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("choke-timeout")
+                .long("choke-timeout")
+                .help("The number of seconds a peer may keep choking before its pieces are given to other peers [default: 30]")
+                .value_name("secs")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-piece-failures")
+                .long("max-piece-failures")
+                .help("The number of peers allowed to send a corrupted piece [default: 3]")
+                .value_name("n")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("hash-threads")
+                .long("hash-threads")
+                .help("The number of threads verifying downloaded pieces [default: one per core, up to 4]")
+                .value_name("n")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("verify-batch")
+                .long("verify-batch")
+                .help("The number of pieces read at once to verify data saved before [default: 64]")
+                .value_name("n")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retries")
+                .long("retries")
+                .help("The number of times to announce and download again after a failure [default: 0]")
+                .value_name("n")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("priority-file")
+                .long("priority-file")
+                .help("The file to download first and in order, as listed in the torrent, to use it sooner")
+                .value_name("path")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("range")
+                .long("range")
+                .help("The byte range of a single-file torrent to download, as offsets of its first and last bytes, skipping other pieces")
+                .value_name("start-end")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-size")
+                .long("max-size")
+                .help("The maximum size of the torrent data in bytes")
+                .value_name("bytes")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .help("The seed to shuffle peers reproducibly, random by default")
+                .value_name("n")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("on-complete")
+                .long("on-complete")
+                .help("The command to run with the saved path once download is complete")
+                .value_name("command")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("preallocate")
+                .long("preallocate")
+                .help("Allocate disk space for the whole torrent before download")
+                .conflicts_with("discard"),
+        )
+        .arg(
+            Arg::with_name("in-memory")
+                .long("in-memory")
+                .help("Hold downloaded pieces in memory and write files once download completes, for small torrents")
+                .conflicts_with_all(&["discard", "listen"]),
+        )
+        .arg(
+            Arg::with_name("no-space-check")
+                .long("no-space-check")
+                .help("Skip checking available disk space before download")
+                .conflicts_with("discard"),
+        )
+        .arg(
+            Arg::with_name("no-prefer-local")
+                .long("no-prefer-local")
+                .help("Connect to peers in random order, rather than to peers on the local network first"),
+        )
+        .arg(
+            Arg::with_name("no-nodelay")
+                .long("no-nodelay")
+                .help("Keep Nagle's algorithm enabled on peer connections"),
         )
         .get_matches()
 }
@@ -0,0 +1,183 @@
+// Copyright (c) 2020 zenoxygen
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+/// Bitfield structure.
+///
+/// The high bit in the first byte corresponds to piece index 0.
+/// Bits that are cleared indicate a missing piece, and set bits indicate an available piece.
+///
+#[derive(Default, Debug, Clone)]
+pub struct Bitfield {
+    // Bitfield bytes
+    bytes: Vec<u8>,
+}
+
+impl Bitfield {
+    /// Build a new empty bitfield.
+    ///
+    /// # Arguments
+    ///
+    /// * `nb_pieces` - The number of pieces in torrent.
+    ///
+    pub fn new(nb_pieces: u32) -> Bitfield {
+        Bitfield {
+            bytes: vec![0; nb_pieces.div_ceil(8) as usize],
+        }
+    }
+
+    /// Build a bitfield from bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The bitfield bytes.
+    ///
+    pub fn from_bytes(bytes: Vec<u8>) -> Bitfield {
+        Bitfield { bytes }
+    }
+
+    /// Return bitfield bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Check if bitfield has a piece.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The piece index to check.
+    ///
+    pub fn has_piece(&self, index: u32) -> bool {
+        let byte_index = index / 8;
+        let offset = index % 8;
+
+        // Prevent unbounded values
+        if byte_index < self.bytes.len() as u32 {
+            // Check for piece index into bitfield
+            return self.bytes[byte_index as usize] >> (7 - offset) as u8 & 1 != 0;
+        }
+        false
+    }
+
+    /// Count the pieces set in bitfield, ignoring spare bits past the last piece.
+    ///
+    /// # Arguments
+    ///
+    /// * `nb_pieces` - The number of pieces in torrent.
+    ///
+    pub fn count_pieces(&self, nb_pieces: u32) -> u32 {
+        let full_bytes = (nb_pieces / 8) as usize;
+        let mut count: u32 = self
+            .bytes
+            .iter()
+            .take(full_bytes)
+            .map(|byte| byte.count_ones())
+            .sum();
+
+        // Count the pieces of the last byte, holding spare bits
+        let spare = nb_pieces % 8;
+        if spare > 0 {
+            if let Some(byte) = self.bytes.get(full_bytes) {
+                count += (byte & (0xFF << (8 - spare))).count_ones();
+            }
+        }
+
+        count
+    }
+
+    /// Set a piece into bitfield.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The piece index to set.
+    ///
+    pub fn set_piece(&mut self, index: u32) {
+        let byte_index = index / 8;
+        let offset = index % 8;
+
+        // Prevent unbounded values
+        if byte_index < self.bytes.len() as u32 {
+            // Set piece index into bitfield
+            self.bytes[byte_index as usize] |= (1 << (7 - offset)) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_check_pieces_across_bytes() {
+        let mut bitfield = Bitfield::new(16);
+        assert_eq!(bitfield.as_bytes().len(), 2);
+
+        for index in [0, 7, 8, 15] {
+            bitfield.set_piece(index);
+        }
+        assert_eq!(bitfield.as_bytes(), &[0b1000_0001, 0b1000_0001]);
+
+        for index in 0..16 {
+            assert_eq!(
+                bitfield.has_piece(index),
+                [0, 7, 8, 15].contains(&index),
+                "piece {}",
+                index
+            );
+        }
+    }
+
+    #[test]
+    fn count_pieces_without_spare_bits() {
+        let bitfield = Bitfield::from_bytes(vec![0b1111_1111, 0b1100_0011]);
+        assert_eq!(bitfield.count_pieces(16), 12);
+        assert_eq!(bitfield.count_pieces(10), 10);
+        assert_eq!(bitfield.count_pieces(24), 12);
+        assert_eq!(Bitfield::new(0).count_pieces(0), 0);
+    }
+
+    #[test]
+    fn check_pieces_in_spare_bits() {
+        // 14 pieces, the last 2 bits of the second byte are spare
+        let mut bitfield = Bitfield::new(14);
+        bitfield.set_piece(13);
+        assert!(bitfield.has_piece(13));
+        assert!(!bitfield.has_piece(14));
+        assert!(!bitfield.has_piece(15));
+
+        // Spare bits set by peer are still readable within last byte
+        let bitfield = Bitfield::from_bytes(vec![0b0000_0000, 0b0000_0001]);
+        assert!(bitfield.has_piece(15));
+        assert!(!bitfield.has_piece(14));
+    }
+
+    #[test]
+    fn ignore_out_of_bounds_pieces() {
+        let mut bitfield = Bitfield::new(16);
+        bitfield.set_piece(15);
+        bitfield.set_piece(16);
+        bitfield.set_piece(u32::MAX);
+
+        assert_eq!(bitfield.as_bytes(), &[0b0000_0000, 0b0000_0001]);
+        assert!(bitfield.has_piece(15));
+        assert!(!bitfield.has_piece(16));
+        assert!(!bitfield.has_piece(u32::MAX));
+        assert!(!Bitfield::new(0).has_piece(0));
+    }
+}
@@ -18,18 +18,39 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+use crate::bitfield::*;
 use crate::handshake::*;
 use crate::message::*;
 use crate::peer::*;
 use crate::piece::*;
+use crate::stats::*;
 
 use anyhow::{anyhow, Result};
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, ReadBytesExt};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
-use std::io::{Cursor, Read, Write};
+use std::io::{Cursor, ErrorKind, Read, Write};
 use std::net::{IpAddr, SocketAddr, TcpStream};
 use std::time::Duration;
 
+// Block size limit (2^14) in bytes, requested until peer rejects it
+pub const BLOCK_SIZE_MAX: u32 = 16384;
+
+// Smallest block size requested, when peer keeps rejecting blocks
+const BLOCK_SIZE_MIN: u32 = 1024;
+
+// Largest message accepted from peer, well above a block or the bitfield of a large torrent
+const MESSAGE_LEN_MAX: usize = 1 << 20;
+
+// Capacity of the read buffer kept between messages, so a large message is not held on to
+const READ_BUF_CAPACITY: usize = 1 << 15;
+
+// Time to wait for a connection to peer to be established
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Number of HAVE messages for pieces out of the torrent ignored before dropping peer
+const INVALID_HAVES_MAX: u32 = 3;
+
 /// Client structure.
 pub struct Client {
     // A peer
@@ -40,10 +61,36 @@ pub struct Client {
     info_hash: Vec<u8>,
     // Connection to peer
     conn: TcpStream,
+    // Number of pieces in torrent
+    nb_pieces: u32,
     // Bitfield of pieces
-    bitfield: Vec<u8>,
+    bitfield: Bitfield,
+    // Bitfield was restored from a previous connection to peer
+    restored: bool,
     // Peer has choked this client
     choked: bool,
+    // Peer has sent CHOKE or UNCHOKE since connection
+    choke_received: bool,
+    // Client is interested in pieces of peer
+    interested: bool,
+    // Peer is interested in pieces of this client
+    peer_interested: bool,
+    // Capabilities announced by peer in handshake
+    capabilities: PeerCapabilities,
+    // Pieces allowed to be requested while choked
+    allowed_fast: Vec<u32>,
+    // Size of blocks requested, halved when peer rejects blocks of this size
+    block_size: u32,
+    // Buffer reused to read messages from peer
+    read_buf: Vec<u8>,
+    // Buffer of a block already handled, reused for the next block received
+    block_buf: Vec<u8>,
+    // A read failed within a message, leaving the stream misaligned with messages
+    desynced: bool,
+    // Number of HAVE messages received for pieces out of the torrent
+    invalid_haves: u32,
+    // Messages exchanged with peer, counted in download stats
+    stats: Stats,
 }
 
 impl Client {
@@ -51,30 +98,166 @@ impl Client {
     ///
     /// # Arguments
     ///
+    /// * `peer` - A remote peer to connect to.
+    /// * `peer_id` - Urlencoded 20-byte string used as a unique ID for the client.
+    /// * `info_hash` - 20-byte SHA-1 hash of the info key in the metainfo file.
+    /// * `nb_pieces` - The number of pieces in torrent.
+    /// * `nodelay` - Disable Nagle's algorithm on the connection.
+    /// * `bind` - The local address to connect from, chosen by the system if not set.
+    ///
+    pub fn new(
+        peer: Peer,
+        peer_id: Vec<u8>,
+        info_hash: Vec<u8>,
+        nb_pieces: u32,
+        nodelay: bool,
+        bind: Option<IpAddr>,
+    ) -> Result<Client> {
+        Client::connect_within(
+            peer,
+            peer_id,
+            info_hash,
+            nb_pieces,
+            nodelay,
+            bind,
+            CONNECT_TIMEOUT,
+        )
+    }
+
+    /// Build a new client, giving up if connection is not established within a timeout.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - A remote peer to connect to.
     /// * `peer_id` - Urlencoded 20-byte string used as a unique ID for the client.
     /// * `info_hash` - 20-byte SHA-1 hash of the info key in the metainfo file.
+    /// * `nb_pieces` - The number of pieces in torrent.
+    /// * `nodelay` - Disable Nagle's algorithm on the connection.
+    /// * `bind` - The local address to connect from, chosen by the system if not set.
+    /// * `timeout` - The time to wait for the connection.
     ///
-    pub fn new(peer: Peer, peer_id: Vec<u8>, info_hash: Vec<u8>) -> Result<Client> {
+    pub fn connect_within(
+        peer: Peer,
+        peer_id: Vec<u8>,
+        info_hash: Vec<u8>,
+        nb_pieces: u32,
+        nodelay: bool,
+        bind: Option<IpAddr>,
+        timeout: Duration,
+    ) -> Result<Client> {
         // Open connection with remote peer
-        let peer_socket = SocketAddr::new(IpAddr::V4(peer.ip), peer.port);
-        let conn = match TcpStream::connect_timeout(&peer_socket, Duration::from_secs(15)) {
-            Ok(conn) => conn,
+        let peer_socket = peer.addr();
+        let conn = match bind {
+            Some(bind) => Client::connect_from(bind, peer_socket, timeout)?,
+            None => match TcpStream::connect_timeout(&peer_socket, timeout) {
+                Ok(conn) => conn,
+                Err(_) => return Err(anyhow!("could not connect to peer")),
+            },
+        };
+
+        info!("Connected to peer {}", peer);
+
+        // Send small messages without delay, keep connection on failure
+        if conn.set_nodelay(nodelay).is_err() {
+            warn!("Could not set TCP_NODELAY on connection to peer {}", peer);
+        }
+
+        Ok(Client::from_stream(
+            peer, peer_id, info_hash, nb_pieces, conn,
+        ))
+    }
+
+    /// Open a connection to a remote peer from a local address.
+    ///
+    /// # Arguments
+    ///
+    /// * `bind` - The local address to connect from.
+    /// * `peer_socket` - The address of the remote peer.
+    /// * `timeout` - The time to wait for the connection.
+    ///
+    fn connect_from(bind: IpAddr, peer_socket: SocketAddr, timeout: Duration) -> Result<TcpStream> {
+        // Peer must be reachable from the family of the local address
+        if bind.is_ipv4() != peer_socket.is_ipv4() {
+            return Err(anyhow!("could not connect to peer from {}", bind));
+        }
+
+        let socket = match Socket::new(
+            Domain::for_address(peer_socket),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        ) {
+            Ok(socket) => socket,
             Err(_) => return Err(anyhow!("could not connect to peer")),
         };
+        if socket
+            .bind(&SockAddr::from(SocketAddr::new(bind, 0)))
+            .is_err()
+        {
+            return Err(anyhow!("could not bind to address {}", bind));
+        }
+        if socket
+            .connect_timeout(&SockAddr::from(peer_socket), timeout)
+            .is_err()
+        {
+            return Err(anyhow!("could not connect to peer"));
+        }
 
-        info!("Connected to peer {:?}", peer.id);
+        Ok(TcpStream::from(socket))
+    }
 
-        // Return new client
-        let client = Client {
+    /// Build a new client from an established connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - The remote peer.
+    /// * `peer_id` - Urlencoded 20-byte string used as a unique ID for the client.
+    /// * `info_hash` - 20-byte SHA-1 hash of the info key in the metainfo file.
+    /// * `nb_pieces` - The number of pieces in torrent.
+    /// * `conn` - The connection to the remote peer.
+    ///
+    pub fn from_stream(
+        peer: Peer,
+        peer_id: Vec<u8>,
+        info_hash: Vec<u8>,
+        nb_pieces: u32,
+        conn: TcpStream,
+    ) -> Client {
+        Client {
             peer,
             peer_id,
             info_hash,
             conn,
-            bitfield: vec![],
+            nb_pieces,
+            bitfield: Default::default(),
+            restored: false,
             choked: true,
-        };
+            choke_received: false,
+            interested: false,
+            peer_interested: false,
+            capabilities: Default::default(),
+            allowed_fast: vec![],
+            block_size: BLOCK_SIZE_MAX,
+            read_buf: Vec::with_capacity(READ_BUF_CAPACITY),
+            block_buf: vec![],
+            desynced: false,
+            invalid_haves: 0,
+            stats: Stats::new(),
+        }
+    }
+
+    /// Count messages exchanged with peer in download stats.
+    ///
+    /// # Arguments
+    ///
+    /// * `stats` - The download stats.
+    ///
+    pub fn set_stats(&mut self, stats: Stats) {
+        self.stats = stats;
+    }
 
-        Ok(client)
+    /// Return the remote peer this client is connected to.
+    pub fn peer(&self) -> &Peer {
+        &self.peer
     }
 
     // Return choked value.
@@ -82,6 +265,26 @@ impl Client {
         self.choked
     }
 
+    // Return interested value.
+    pub fn is_interested(&self) -> bool {
+        self.interested
+    }
+
+    /// Check if a piece is allowed to be requested while choked.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The piece index to check.
+    ///
+    pub fn is_allowed_fast(&self, index: u32) -> bool {
+        self.allowed_fast.contains(&index)
+    }
+
+    /// Return capabilities announced by peer in handshake.
+    pub fn capabilities(&self) -> &PeerCapabilities {
+        &self.capabilities
+    }
+
     /// Check if peer has a piece.
     ///
     /// # Arguments
@@ -89,15 +292,30 @@ impl Client {
     /// * `index` - The piece index to check.
     ///
     pub fn has_piece(&self, index: u32) -> bool {
-        let byte_index = index / 8;
-        let offset = index % 8;
+        self.bitfield.has_piece(index)
+    }
 
-        // Prevent unbounded values
-        if byte_index < self.bitfield.len() as u32 {
-            // Check for piece index into bitfield
-            return self.bitfield[byte_index as usize] >> (7 - offset) as u8 & 1 != 0;
+    /// Return the bitfield of pieces that peer has.
+    pub fn bitfield(&self) -> &Bitfield {
+        &self.bitfield
+    }
+
+    /// Check if peer has a piece this client still needs.
+    ///
+    /// # Arguments
+    ///
+    /// * `have` - The bitfield of pieces this client already has.
+    ///
+    pub fn is_interesting(&self, have: &Bitfield) -> bool {
+        (0..self.nb_pieces).any(|index| self.bitfield.has_piece(index) && !have.has_piece(index))
+    }
+
+    /// Return the ratio of pieces of the torrent that peer has, from 0 to 1.
+    pub fn completion(&self) -> f64 {
+        if self.nb_pieces == 0 {
+            return 0.0;
         }
-        false
+        self.bitfield.count_pieces(self.nb_pieces) as f64 / self.nb_pieces as f64
     }
 
     /// Set a piece that peer has.
@@ -107,17 +325,32 @@ impl Client {
     /// * `index` - The piece index to update into bitfield.
     ///
     pub fn set_piece(&mut self, index: u32) {
-        let byte_index = index / 8;
-        let offset = index % 8;
+        self.bitfield.set_piece(index);
+    }
 
-        // Create a new bitfield
-        let mut bitfield: Vec<u8> = self.bitfield.to_vec();
+    /// Return the size of blocks to request from peer.
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// Restore the bitfield of peer seen on a previous connection.
+    ///
+    /// The restored bitfield is replaced by any bitfield sent by peer, or updated by its HAVE messages.
+    ///
+    /// # Arguments
+    ///
+    /// * `bitfield` - The last bitfield seen from peer.
+    ///
+    pub fn restore_bitfield(&mut self, bitfield: Bitfield) {
+        self.bitfield = bitfield;
+        self.restored = true;
+    }
 
-        // Prevent unbounded values
-        if byte_index < self.bitfield.len() as u32 {
-            // Set piece index into bitfield
-            bitfield[byte_index as usize] |= (1 << (7 - offset)) as u8;
-            self.bitfield = bitfield;
+    /// Get a new handle on the connection to the remote peer, to shut it down from another thread.
+    pub fn try_clone_stream(&self) -> Result<TcpStream> {
+        match self.conn.try_clone() {
+            Ok(conn) => Ok(conn),
+            Err(_) => Err(anyhow!("could not clone connection to peer")),
         }
     }
 
@@ -151,6 +384,31 @@ impl Client {
 
     /// Handshake with remote peer.
     pub fn handshake_with_peer(&mut self) -> Result<()> {
+        // Send handshake to remote peer
+        self.send_handshake()?;
+
+        // Read handshake received from remote peer
+        self.read_handshake()?;
+
+        Ok(())
+    }
+
+    /// Accept handshake from remote peer.
+    ///
+    /// The handshake of an incoming connection is received first, then replied.
+    ///
+    pub fn accept_handshake(&mut self) -> Result<()> {
+        // Read handshake received from remote peer
+        self.read_handshake()?;
+
+        // Send handshake to remote peer
+        self.send_handshake()?;
+
+        Ok(())
+    }
+
+    /// Send handshake to remote peer.
+    fn send_handshake(&mut self) -> Result<()> {
         // Create handshake
         let peer_id = self.peer_id.clone();
         let info_hash = self.info_hash.clone();
@@ -158,14 +416,19 @@ impl Client {
 
         // Send handshake to remote peer
         let handshake_encoded: Vec<u8> = handshake.serialize()?;
-        if self.conn.write(&handshake_encoded).is_err() {
+        if self.conn.write_all(&handshake_encoded).is_err() {
             return Err(anyhow!("could not send handshake to peer"));
         }
 
+        Ok(())
+    }
+
+    /// Read handshake from remote peer.
+    fn read_handshake(&mut self) -> Result<()> {
         // Read handshake received from remote peer
         let handshake_len: usize = self.read_handshake_len()?;
         let mut handshake_buf: Vec<u8> = vec![0; 48 + handshake_len];
-        if self.conn.read_exact(&mut handshake_buf).is_err() {
+        if self.read_exact(&mut handshake_buf, false).is_err() {
             return Err(anyhow!("could not read handshake received from peer"));
         }
 
@@ -175,6 +438,17 @@ impl Client {
             return Err(anyhow!("invalid handshake received from peer"));
         }
 
+        // Read capabilities from reserved bytes
+        self.capabilities = PeerCapabilities::from_reserved(&handshake_decoded.reserved);
+
+        info!(
+            "Peer {} supports DHT: {:?}, fast extension: {:?}, extension protocol: {:?}",
+            self.peer,
+            self.capabilities.dht,
+            self.capabilities.fast_extension,
+            self.capabilities.extension_protocol
+        );
+
         Ok(())
     }
 
@@ -182,7 +456,7 @@ impl Client {
     fn read_handshake_len(&mut self) -> Result<usize> {
         // Read 1 byte into buffer
         let mut buf = [0; 1];
-        if self.conn.read_exact(&mut buf).is_err() {
+        if self.read_exact(&mut buf, false).is_err() {
             return Err(anyhow!(
                 "could not read handshake length received from peer"
             ));
@@ -197,33 +471,151 @@ impl Client {
         Ok(handshake_len as usize)
     }
 
+    /// Read exactly enough bytes from remote peer to fill buffer.
+    ///
+    /// A read failing after part of a message was received leaves the stream misaligned,
+    /// so the connection is marked out of sync rather than parsing the rest of the message as a new one.
+    /// Only a timeout before the first byte of a message keeps the stream aligned.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - The buffer to fill.
+    /// * `boundary` - The read starts a message, so that nothing was received from it yet.
+    ///
+    fn read_exact(&mut self, buf: &mut [u8], boundary: bool) -> std::io::Result<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.conn.read(&mut buf[filled..]) {
+                Ok(0) => {
+                    self.desynced = true;
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    let timeout = matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut);
+                    if !(boundary && filled == 0 && timeout) {
+                        self.desynced = true;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     /// Read message from remote peer.
-    pub fn read_message(&mut self) -> Result<Message> {
-        let message_len: usize = self.read_message_len()?;
+    pub fn read_message(&mut self) -> Result<MessageKind> {
+        match self.read_message_or_timeout()? {
+            Some(message) => Ok(message),
+            None => Err(anyhow!("timed out waiting for message from peer")),
+        }
+    }
+
+    /// Read message from remote peer, or nothing if no message starts within a timeout.
+    ///
+    /// The timeout only applies until the message starts, the rest of the message being read
+    /// within the connection timeout.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The time to wait for a message to start.
+    ///
+    pub fn read_message_within(&mut self, timeout: Duration) -> Result<Option<MessageKind>> {
+        // Wait for a message to start, without consuming it
+        let read_timeout = match self.conn.read_timeout() {
+            Ok(read_timeout) => read_timeout,
+            Err(_) => return Err(anyhow!("could not get read timeout")),
+        };
+        if self.conn.set_read_timeout(Some(timeout)).is_err() {
+            return Err(anyhow!("could not set read timeout"));
+        }
+        let started = self.conn.peek(&mut [0; 1]);
+        if self.conn.set_read_timeout(read_timeout).is_err() {
+            return Err(anyhow!("could not set read timeout"));
+        }
+
+        match started {
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+            _ => self.read_message().map(Some),
+        }
+    }
+
+    /// Read message from remote peer, or nothing if no message starts within the read timeout.
+    fn read_message_or_timeout(&mut self) -> Result<Option<MessageKind>> {
+        // Check stream is aligned with messages, after a previous read failed
+        if self.desynced {
+            return Err(anyhow!(
+                "connection to peer is out of sync after a failed read"
+            ));
+        }
+
+        let message_len: usize = match self.read_message_len()? {
+            Some(message_len) => message_len,
+            None => return Ok(None),
+        };
 
         // If message length is 0, it's a keep-alive
         if message_len == 0 {
-            info!("Receive KEEP_ALIVE from peer {:?}", self.peer.id);
-            return Err(anyhow!("keep-alive"));
+            info!("Receive KEEP_ALIVE from peer {}", self.peer);
+            return Ok(Some(MessageKind::KeepAlive));
         }
 
-        // Read message
-        let mut message_buf: Vec<u8> = vec![0; message_len];
-        if self.conn.read_exact(&mut message_buf).is_err() {
+        // Check message length, rather than allocating whatever peer asks for
+        if message_len > MESSAGE_LEN_MAX {
+            self.desynced = true;
+            return Err(anyhow!(
+                "received message of {:?} bytes from peer, larger than {:?} bytes",
+                message_len,
+                MESSAGE_LEN_MAX
+            ));
+        }
+
+        // Read message into the reused buffer
+        let mut read_buf = std::mem::take(&mut self.read_buf);
+        read_buf.resize(message_len, 0);
+        let read = self.read_exact(&mut read_buf, false);
+        self.read_buf = read_buf;
+        if read.is_err() {
             return Err(anyhow!("could not read message received from peer"));
         }
 
-        // Deserialize message
-        let message: Message = deserialize_message(&message_buf, message_len)?;
+        // Deserialize message, copying its payload out of the buffer
+        let block_buf = std::mem::take(&mut self.block_buf);
+        let message: MessageKind = deserialize_message_into(&self.read_buf, block_buf)?;
+        if let Some(id) = message.id() {
+            self.stats.add_message_received(id);
+        }
+
+        // Release memory held after a large message
+        self.read_buf.clear();
+        self.read_buf.shrink_to(READ_BUF_CAPACITY);
 
-        Ok(message)
+        Ok(Some(message))
     }
 
-    /// Read message length.
-    fn read_message_len(&mut self) -> Result<usize> {
+    /// Give back the block of a MESSAGE_PIECE once handled, to read the next block into it.
+    ///
+    /// # Arguments
+    ///
+    /// * `block` - The block already copied into its piece.
+    ///
+    pub fn recycle_block(&mut self, block: Vec<u8>) {
+        // Keep only buffers sized for a block, not the payload of a larger message
+        if block.capacity() <= BLOCK_SIZE_MAX as usize {
+            self.block_buf = block;
+        }
+    }
+
+    /// Read message length, or nothing if no message starts within the read timeout.
+    fn read_message_len(&mut self) -> Result<Option<usize>> {
         // Read bytes into buffer
-        let mut buf = vec![0; 4];
-        if self.conn.read_exact(&mut buf).is_err() {
+        let mut buf = [0; 4];
+        if self.read_exact(&mut buf, true).is_err() {
+            if !self.desynced {
+                return Ok(None);
+            }
             return Err(anyhow!("could not read message length received from peer"));
         }
 
@@ -231,49 +623,93 @@ impl Client {
         let mut cursor = Cursor::new(buf);
         let message_len = cursor.read_u32::<BigEndian>()?;
 
-        Ok(message_len as usize)
+        Ok(Some(message_len as usize))
     }
 
     /// Read CHOKE message from remote peer.
     pub fn read_choke(&mut self) {
-        info!("Receive MESSAGE_CHOKE from peer {:?}", self.peer.id);
-        self.choked = true
+        info!("Receive MESSAGE_CHOKE from peer {}", self.peer);
+        self.choked = true;
+        self.choke_received = true;
     }
 
     /// Send UNCHOKE message to remote peer.
     pub fn send_unchoke(&mut self) -> Result<()> {
-        let message: Message = Message::new(MESSAGE_UNCHOKE);
-        let message_encoded = message.serialize()?;
+        let message_encoded = MessageKind::Unchoke.serialize()?;
 
-        info!("Send MESSAGE_UNCHOKE to peer {:?}", self.peer.id);
+        info!("Send MESSAGE_UNCHOKE to peer {}", self.peer);
 
-        if self.conn.write(&message_encoded).is_err() {
+        if self.conn.write_all(&message_encoded).is_err() {
             return Err(anyhow!("could not send MESSAGE_UNCHOKE to peer"));
         }
+        self.stats.add_messages_sent(MESSAGE_UNCHOKE, 1);
 
         Ok(())
     }
 
     /// Read UNCHOKE message from remote peer.
     pub fn read_unchoke(&mut self) {
-        info!("Receive MESSAGE_UNCHOKE from peer {:?}", self.peer.id);
-        self.choked = false
+        info!("Receive MESSAGE_UNCHOKE from peer {}", self.peer);
+        self.choked = false;
+        self.choke_received = true;
     }
 
     /// Send INTERESTED message to remote peer.
     pub fn send_interested(&mut self) -> Result<()> {
-        let message: Message = Message::new(MESSAGE_INTERESTED);
-        let message_encoded = message.serialize()?;
+        let message_encoded = MessageKind::Interested.serialize()?;
 
-        info!("Send MESSAGE_INTERESTED to peer {:?}", self.peer.id);
+        info!("Send MESSAGE_INTERESTED to peer {}", self.peer);
 
-        if self.conn.write(&message_encoded).is_err() {
+        if self.conn.write_all(&message_encoded).is_err() {
             return Err(anyhow!("could not send MESSAGE_INTERESTED to peer"));
         }
+        self.stats.add_messages_sent(MESSAGE_INTERESTED, 1);
+
+        self.interested = true;
+
+        Ok(())
+    }
+
+    /// Send NOT INTERESTED message to remote peer.
+    pub fn send_not_interested(&mut self) -> Result<()> {
+        let message_encoded = MessageKind::NotInterested.serialize()?;
+
+        info!("Send MESSAGE_NOT_INTERESTED to peer {}", self.peer);
+
+        if self.conn.write_all(&message_encoded).is_err() {
+            return Err(anyhow!("could not send MESSAGE_NOT_INTERESTED to peer"));
+        }
+        self.stats.add_messages_sent(MESSAGE_NOT_INTERESTED, 1);
+
+        self.interested = false;
+
+        Ok(())
+    }
+
+    /// Send KEEP-ALIVE message to remote peer, a message of length 0.
+    pub fn send_keep_alive(&mut self) -> Result<()> {
+        debug!("Send keep-alive to peer {}", self.peer);
+
+        let message_encoded = MessageKind::KeepAlive.serialize()?;
+        if self.conn.write_all(&message_encoded).is_err() {
+            return Err(anyhow!("could not send keep-alive to peer"));
+        }
 
         Ok(())
     }
 
+    /// Read INTERESTED message from remote peer.
+    pub fn read_interested(&mut self) {
+        info!("Receive MESSAGE_INTERESTED from peer {}", self.peer);
+        self.peer_interested = true
+    }
+
+    /// Read NOT INTERESTED message from remote peer.
+    pub fn read_not_interested(&mut self) {
+        info!("Receive MESSAGE_NOT_INTERESTED from peer {}", self.peer);
+        self.peer_interested = false
+    }
+
     /// Send HAVE message to remote peer.
     ///
     /// # Arguments
@@ -281,41 +717,64 @@ impl Client {
     /// * `index` - The index of a piece that has just been successfully downloaded and verified.
     ///
     pub fn send_have(&mut self, index: u32) -> Result<()> {
-        let mut payload: Vec<u8> = vec![];
-        payload.write_u32::<BigEndian>(index)?;
-
-        let message: Message = Message::new_with_payload(MESSAGE_HAVE, payload);
-        let message_encoded = message.serialize()?;
+        let message_encoded = MessageKind::Have(index).serialize()?;
 
-        info!("Send MESSAGE_HAVE to peer {:?}", self.peer.id);
+        info!("Send MESSAGE_HAVE to peer {}", self.peer);
 
-        if self.conn.write(&message_encoded).is_err() {
+        if self.conn.write_all(&message_encoded).is_err() {
             return Err(anyhow!("could not send MESSAGE_HAVE to peer"));
         }
+        self.stats.add_messages_sent(MESSAGE_HAVE, 1);
+
+        Ok(())
+    }
+
+    /// Send HAVE messages to remote peer for pieces that were not announced yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `have` - The bitfield of pieces saved to disk.
+    /// * `announced` - The bitfield of pieces already announced to remote peer, updated with sent pieces.
+    ///
+    pub fn send_new_haves(&mut self, have: &Bitfield, announced: &mut Bitfield) -> Result<()> {
+        for index in 0..self.nb_pieces {
+            if have.has_piece(index) && !announced.has_piece(index) {
+                self.send_have(index)?;
+                announced.set_piece(index);
+            }
+        }
 
         Ok(())
     }
 
     /// Read HAVE message from remote peer.
     ///
-    /// The message payload is the zero-based index of a piece that has just been successfully downloaded and verified via the hash.
+    /// A piece out of the torrent is ignored, and peer is dropped once it sent too many of them.
     ///
     /// # Arguments
     ///
-    /// * `message` - The message to parse.
+    /// * `index` - The zero-based index of a piece that has just been successfully downloaded and verified via the hash.
     ///
-    pub fn read_have(&mut self, message: Message) -> Result<()> {
-        info!("Receive MESSAGE_HAVE from peer {:?}", self.peer.id);
-
-        // Check if message id and payload are valid
-        if message.id != MESSAGE_HAVE || message.payload.to_vec().len() != 4 {
-            return Err(anyhow!("received invalid MESSAGE_HAVE from peer"));
+    pub fn read_have(&mut self, index: u32) -> Result<()> {
+        info!("Receive MESSAGE_HAVE from peer {}", self.peer);
+
+        // Check piece is in torrent, rather than setting a spare bit of bitfield
+        if index >= self.nb_pieces {
+            self.invalid_haves += 1;
+            if self.invalid_haves >= INVALID_HAVES_MAX {
+                return Err(anyhow!(
+                    "received MESSAGE_HAVE for piece {:?} from peer, torrent has {:?} pieces",
+                    index,
+                    self.nb_pieces
+                ));
+            }
+            warn!(
+                "Ignore MESSAGE_HAVE for piece {:?} from peer {}, torrent has {:?} pieces",
+                index, self.peer, self.nb_pieces
+            );
+            return Ok(());
         }
 
-        // Get piece index
-        let mut payload_cursor = Cursor::new(message.payload.to_vec());
-        let index = payload_cursor.read_u32::<BigEndian>()?;
-
         // Update bitfield
         self.set_piece(index);
 
@@ -329,23 +788,175 @@ impl Client {
     /// Bits that are cleared indicated a missing piece, and set bits indicate a valid and available piece.
     /// Spare bits at the end are set to zero.
     ///
+    /// If peer supports the fast extension, HAVE ALL or HAVE NONE message may be received instead.
+    ///
+    /// A peer with no piece may send no bitfield at all, and some peers send HAVE messages instead.
+    /// Such a peer starts with an empty bitfield, or the bitfield restored from a previous connection,
+    /// and its first message is applied to it.
+    ///
     pub fn read_bitfield(&mut self) -> Result<()> {
-        info!("Receive MESSAGE_BITFIELD from peer {:?}", self.peer.id);
+        // Skip keep-alives, which may come before the bitfield
+        let message = loop {
+            match self.read_message_or_timeout()? {
+                Some(MessageKind::KeepAlive) => continue,
+                Some(message) => break message,
+                None => {
+                    self.start_without_bitfield();
+                    return Ok(());
+                }
+            }
+        };
 
-        let message: Message = self.read_message()?;
-        if message.id != MESSAGE_BITFIELD {
-            return Err(anyhow!("received invalid MESSAGE_BITFIELD from peer"));
+        match message {
+            MessageKind::Bitfield(bitfield) => {
+                info!("Receive MESSAGE_BITFIELD from peer {}", self.peer);
+
+                // Update bitfield
+                self.bitfield = Bitfield::from_bytes(bitfield);
+            }
+            MessageKind::HaveAll if self.capabilities.fast_extension => self.read_have_all(),
+            MessageKind::HaveNone if self.capabilities.fast_extension => self.read_have_none(),
+            MessageKind::Have(index) => {
+                self.start_without_bitfield();
+                self.read_have(index)?;
+            }
+            MessageKind::Choke => {
+                self.start_without_bitfield();
+                self.read_choke();
+            }
+            MessageKind::Unchoke => {
+                self.start_without_bitfield();
+                self.read_unchoke();
+            }
+            MessageKind::Interested => {
+                self.start_without_bitfield();
+                self.read_interested();
+            }
+            MessageKind::NotInterested => {
+                self.start_without_bitfield();
+                self.read_not_interested();
+            }
+            MessageKind::AllowedFast(index) => {
+                self.start_without_bitfield();
+                self.read_allowed_fast(index)?;
+            }
+            MessageKind::Unknown(_) => {
+                info!("Ignore unknown message from peer {}", self.peer);
+                self.start_without_bitfield();
+            }
+            message => {
+                return Err(anyhow!(
+                    "received {} from peer instead of MESSAGE_BITFIELD",
+                    message.name()
+                ))
+            }
         }
 
-        // Update bitfield
-        self.bitfield = message.payload.to_vec();
+        Ok(())
+    }
+
+    /// Start with an empty bitfield when peer sent none, unless one was restored from a previous connection.
+    fn start_without_bitfield(&mut self) {
+        if self.restored {
+            info!(
+                "Peer {} sent no bitfield, keep bitfield from previous connection",
+                self.peer
+            );
+        } else {
+            info!(
+                "Peer {} sent no bitfield, start with an empty bitfield",
+                self.peer
+            );
+            self.bitfield = Bitfield::new(self.nb_pieces);
+        }
+    }
+
+    /// Read HAVE ALL message from remote peer.
+    ///
+    /// The peer has all pieces, bits of every piece are set into bitfield.
+    ///
+    fn read_have_all(&mut self) {
+        info!("Receive MESSAGE_HAVE_ALL from peer {}", self.peer);
+
+        // Create an empty bitfield
+        self.bitfield = Bitfield::new(self.nb_pieces);
+
+        // Set every piece index into bitfield
+        for index in 0..self.nb_pieces {
+            self.set_piece(index);
+        }
+    }
+
+    /// Read HAVE NONE message from remote peer.
+    ///
+    /// The peer has no pieces, bitfield is left empty.
+    ///
+    fn read_have_none(&mut self) {
+        info!("Receive MESSAGE_HAVE_NONE from peer {}", self.peer);
+
+        // Create an empty bitfield, to be updated with HAVE messages
+        self.bitfield = Bitfield::new(self.nb_pieces);
+    }
+
+    /// Send BITFIELD message to remote peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `bitfield` - The bitfield of pieces available to the remote peer.
+    ///
+    pub fn send_bitfield(&mut self, bitfield: &Bitfield) -> Result<()> {
+        let message_encoded = MessageKind::Bitfield(bitfield.as_bytes().to_vec()).serialize()?;
+
+        info!("Send MESSAGE_BITFIELD to peer {}", self.peer);
+
+        if self.conn.write_all(&message_encoded).is_err() {
+            return Err(anyhow!("could not send MESSAGE_BITFIELD to peer"));
+        }
+        self.stats.add_messages_sent(MESSAGE_BITFIELD, 1);
 
         Ok(())
     }
 
-    /// Send REQUEST message to remote peer.
+    /// Send a batch of REQUEST messages to remote peer.
     ///
     /// The request message is fixed length, and is used to request a block.
+    /// All requests are serialized into a single buffer and written at once.
+    ///
+    /// # Arguments
+    ///
+    /// * `requests` - The blocks to request, as `(index, begin, length)` tuples.
+    ///
+    pub fn send_requests(&mut self, requests: &[(u32, u32, u32)]) -> Result<()> {
+        let mut requests_encoded: Vec<u8> = vec![];
+
+        for &(index, begin, length) in requests {
+            let message = MessageKind::Request {
+                index,
+                begin,
+                length,
+            };
+            let mut message_encoded = message.serialize()?;
+            requests_encoded.append(&mut message_encoded);
+
+            info!(
+                "Send MESSAGE_REQUEST for piece {:?} [{:?}:{:?}] to peer {}",
+                index,
+                begin,
+                begin + length,
+                self.peer
+            );
+        }
+
+        if self.conn.write_all(&requests_encoded).is_err() {
+            return Err(anyhow!("could not send MESSAGE_REQUEST to peer"));
+        }
+        self.stats
+            .add_messages_sent(MESSAGE_REQUEST, requests.len() as u64);
+
+        Ok(())
+    }
+
+    /// Read REQUEST message from remote peer.
     ///
     /// # Arguments
     ///
@@ -353,96 +964,445 @@ impl Client {
     /// * `begin` - The zero-based byte offset within the piece.
     /// * `length` - The requested length.
     ///
-    pub fn send_request(&mut self, index: u32, begin: u32, length: u32) -> Result<()> {
-        let mut payload: Vec<u8> = vec![];
-        payload.write_u32::<BigEndian>(index)?;
-        payload.write_u32::<BigEndian>(begin)?;
-        payload.write_u32::<BigEndian>(length)?;
+    pub fn read_request(&mut self, index: u32, begin: u32, length: u32) {
+        info!(
+            "Receive MESSAGE_REQUEST for piece {:?} [{:?}:{:?}] from peer {}",
+            index,
+            begin,
+            begin.saturating_add(length),
+            self.peer
+        );
+    }
+
+    /// Send PIECE message to remote peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The zero-based piece index.
+    /// * `begin` - The zero-based byte offset within the piece.
+    /// * `block` - The block of data.
+    ///
+    pub fn send_piece(&mut self, index: u32, begin: u32, block: Vec<u8>) -> Result<()> {
+        let block_len: u32 = block.len() as u32;
 
-        let message: Message = Message::new_with_payload(MESSAGE_REQUEST, payload);
+        let message = MessageKind::Piece {
+            index,
+            begin,
+            block,
+        };
         let message_encoded = message.serialize()?;
 
         info!(
-            "Send MESSAGE_REQUEST for piece {:?} [{:?}:{:?}] to peer {:?}",
+            "Send MESSAGE_PIECE for piece {:?} [{:?}:{:?}] to peer {}",
             index,
             begin,
-            begin + length,
-            self.peer.id
+            begin + block_len,
+            self.peer
         );
 
-        if self.conn.write(&message_encoded).is_err() {
-            return Err(anyhow!("could not send MESSAGE_REQUEST to peer"));
+        if self.conn.write_all(&message_encoded).is_err() {
+            return Err(anyhow!("could not send MESSAGE_PIECE to peer"));
         }
+        self.stats.add_messages_sent(MESSAGE_PIECE, 1);
 
         Ok(())
     }
 
-    /// Read PIECE message from remote peer.
+    /// Read REJECT REQUEST message from remote peer.
     ///
-    /// The message payload contains the following information:
-    /// - index: integer specifying the zero-based piece index
-    /// - begin: integer specifying the zero-based byte offset within the piece
-    /// - block: block of data, which is a subset of the piece specified by index.
+    /// The rejected block, given as in the request message it rejects, is freed to be requested again.
     ///
     /// # Arguments
     ///
-    /// * `message` - The message to parse.
+    /// * `index` - The zero-based piece index.
+    /// * `begin` - The zero-based byte offset within the piece.
+    /// * `length` - The rejected length.
     /// * `piece_work` - A work piece.
     ///
-    pub fn read_piece(&mut self, message: Message, piece_work: &mut PieceWork) -> Result<()> {
-        info!("Receive MESSAGE_PIECE from peer {:?}", self.peer.id);
+    pub fn read_reject_request(
+        &mut self,
+        index: u32,
+        begin: u32,
+        length: u32,
+        piece_work: &mut PieceWork,
+    ) -> Result<()> {
+        info!("Receive MESSAGE_REJECT_REQUEST from peer {}", self.peer);
+
+        // Check if peer may reject requests
+        if !self.capabilities.fast_extension {
+            return Err(anyhow!("received invalid MESSAGE_REJECT_REQUEST from peer"));
+        }
+
+        // Ignore rejects of blocks that are not outstanding
+        if index != piece_work.index
+            || piece_work.requests == 0
+            || begin
+                .checked_add(length)
+                .is_none_or(|end| end > piece_work.requested)
+            || piece_work.rejected.contains(&(begin, length))
+        {
+            info!(
+                "Ignore MESSAGE_REJECT_REQUEST for piece {:?} [{:?}:{:?}] from peer {}",
+                index,
+                begin,
+                begin.saturating_add(length),
+                self.peer
+            );
+            return Ok(());
+        }
 
-        // Check if message id and payload are valid
-        if message.id != MESSAGE_PIECE || message.payload.to_vec().len() < 8 {
-            return Err(anyhow!("received invalid MESSAGE_HAVE from peer"));
+        // Free block to be requested again
+        piece_work.rejected.push((begin, length));
+
+        // Update requests counters
+        piece_work.requests -= 1;
+        piece_work.rejects += 1;
+
+        // Request smaller blocks, unless block was rejected because of choke or was already too large
+        if !self.choked && length >= self.block_size && self.block_size > BLOCK_SIZE_MIN {
+            self.block_size = (self.block_size / 2).max(BLOCK_SIZE_MIN);
+            info!(
+                "Peer {} rejected block of {:?} bytes, request blocks of {:?} bytes",
+                self.peer, length, self.block_size
+            );
         }
 
-        // Get message payload
-        let payload: Vec<u8> = message.payload.to_vec();
+        Ok(())
+    }
 
-        // Get piece index
-        let mut payload_cursor = Cursor::new(&payload[0..4]);
-        let index = payload_cursor.read_u32::<BigEndian>()?;
+    /// Read ALLOWED FAST message from remote peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of a piece that can be requested even while choked.
+    ///
+    pub fn read_allowed_fast(&mut self, index: u32) -> Result<()> {
+        info!("Receive MESSAGE_ALLOWED_FAST from peer {}", self.peer);
+
+        // Check if peer may allow pieces
+        if !self.capabilities.fast_extension {
+            return Err(anyhow!("received invalid MESSAGE_ALLOWED_FAST from peer"));
+        }
+
+        // Add piece to allowed fast set
+        if index < self.nb_pieces && !self.allowed_fast.contains(&index) {
+            self.allowed_fast.push(index);
+        }
+
+        Ok(())
+    }
+
+    /// Read PIECE message from remote peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The zero-based piece index.
+    /// * `begin` - The zero-based byte offset within the piece.
+    /// * `block` - The block of data, which is a subset of the piece specified by index.
+    /// * `piece_work` - A work piece.
+    ///
+    pub fn read_piece(
+        &mut self,
+        index: u32,
+        begin: u32,
+        block: &[u8],
+        piece_work: &mut PieceWork,
+    ) -> Result<()> {
+        info!("Receive MESSAGE_PIECE from peer {}", self.peer);
+
+        // Consider a peer sending data before any UNCHOKE as unchoking this client
+        if self.choked && !self.choke_received {
+            info!(
+                "Peer {} sent a block before MESSAGE_UNCHOKE, consider client unchoked",
+                self.peer
+            );
+            self.choked = false;
+        }
 
         // Check if piece index is valid
         if index != piece_work.index {
-            return Err(anyhow!("received invalid piece from peer"));
+            return Err(anyhow!(
+                "received MESSAGE_PIECE for piece {:?} from peer, expected piece {:?}",
+                index,
+                piece_work.index
+            ));
         }
 
-        // Get byte offset within piece
-        let mut payload_cursor = Cursor::new(&payload[4..8]);
-        let begin: u32 = payload_cursor.read_u32::<BigEndian>()?;
-
-        // Get piece block
-        let block: Vec<u8> = payload[8..].to_vec();
         let block_len: u32 = block.len() as u32;
 
-        // Check if byte offset is valid
-        if begin + block_len > piece_work.length {
+        // Check if byte offset is valid, without overflowing
+        if begin as u64 + block_len as u64 > piece_work.length as u64 {
             return Err(anyhow!(
-                "received invalid byte offset within piece from peer"
+                "received MESSAGE_PIECE with invalid byte offset within piece {:?} from peer",
+                index
             ));
         }
 
         info!(
-            "Download piece {:?} [{:?}:{:?}] from peer {:?}",
+            "Download piece {:?} [{:?}:{:?}] from peer {}",
             index,
             begin,
             begin + block_len,
-            self.peer.id
+            self.peer
         );
 
-        // Add block to piece data
-        for i in 0..block_len {
-            piece_work.data[begin as usize + i as usize] = block[i as usize];
+        // Keep a block that was not requested only if it follows requested data
+        if begin >= piece_work.requested {
+            if begin > piece_work.requested {
+                info!(
+                    "Ignore block of piece {:?} [{:?}:{:?}] not requested from peer {}",
+                    index,
+                    begin,
+                    begin + block_len,
+                    self.peer
+                );
+                return Ok(());
+            }
+            info!(
+                "Peer {} sent a block of piece {:?} that was not requested, keep it",
+                self.peer, index
+            );
+
+            // Skip block when requesting the rest of piece
+            piece_work.requested += block_len;
+        } else {
+            // Update requests counter
+            piece_work.requests = piece_work.requests.saturating_sub(1);
         }
 
+        // Add block to piece data
+        piece_work.data[begin as usize..(begin + block_len) as usize].copy_from_slice(block);
+
         // Update downloaded data counter
         piece_work.downloaded += block_len;
 
-        // Update requests counter
-        piece_work.requests -= 1;
-
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    #[test]
+    fn read_interleaved_messages_with_reused_buffer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut remote = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        let mut client = Client::from_stream(Peer::new(), vec![], vec![], 8, conn);
+
+        // Large, small and empty messages, each shorter than the previous one
+        let piece = MessageKind::Piece {
+            index: 1,
+            begin: 0,
+            block: vec![0xAB; 16384],
+        };
+        let have = MessageKind::Have(3);
+        let unchoke = MessageKind::Unchoke;
+        for message in [&piece, &have, &unchoke, &piece] {
+            remote.write_all(&message.serialize().unwrap()).unwrap();
+        }
+
+        for expected in [&piece, &have, &unchoke, &piece] {
+            assert_eq!(&client.read_message().unwrap(), expected);
+        }
+
+        // Read the next block into the buffer of the previous one
+        for _ in 0..2 {
+            remote.write_all(&piece.serialize().unwrap()).unwrap();
+        }
+        let block = match client.read_message().unwrap() {
+            MessageKind::Piece { block, .. } => block,
+            _ => panic!("expected MESSAGE_PIECE"),
+        };
+        let block_ptr = block.as_ptr();
+        client.recycle_block(block);
+        match client.read_message().unwrap() {
+            MessageKind::Piece { block, .. } => {
+                assert_eq!(block.as_ptr(), block_ptr);
+                assert_eq!(block, vec![0xAB; 16384]);
+            }
+            _ => panic!("expected MESSAGE_PIECE"),
+        }
+
+        // Reject a message too large to be buffered
+        remote.write_all(&[0x10, 0, 0, 0]).unwrap();
+        assert!(client.read_message().is_err());
+    }
+
+    #[test]
+    fn read_keep_alives_between_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut remote = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        let mut client = Client::from_stream(Peer::new(), vec![], vec![], 8, conn);
+
+        // Return keep-alives as messages, keeping the stream aligned
+        let keep_alive = MessageKind::KeepAlive.serialize().unwrap();
+        assert_eq!(keep_alive, vec![0; 4]);
+        remote.write_all(&keep_alive).unwrap();
+        remote
+            .write_all(&MessageKind::Have(3).serialize().unwrap())
+            .unwrap();
+        assert_eq!(client.read_message().unwrap(), MessageKind::KeepAlive);
+        assert_eq!(client.read_message().unwrap(), MessageKind::Have(3));
+
+        // Skip keep-alives sent before the bitfield
+        remote.write_all(&keep_alive).unwrap();
+        remote
+            .write_all(
+                &MessageKind::Bitfield(vec![0b1000_0000])
+                    .serialize()
+                    .unwrap(),
+            )
+            .unwrap();
+        client.read_bitfield().unwrap();
+        assert_eq!(client.bitfield().as_bytes(), &[0b1000_0000]);
+    }
+
+    #[test]
+    fn describe_connection_state() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _remote = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        let mut peer = Peer::new();
+        peer.port = 6881;
+        let mut client = Client::from_stream(peer, vec![], vec![], 10, conn);
+        assert_eq!(client.peer().port, 6881);
+        assert_eq!(client.completion(), 0.0);
+
+        // Peer with 4 of the 10 pieces and spare bits set, all pieces already downloaded
+        client.restore_bitfield(Bitfield::from_bytes(vec![0b1100_0000, 0b1111_1111]));
+        assert_eq!(client.completion(), 0.4);
+        assert!(client.is_interesting(&Bitfield::new(10)));
+        let mut have = Bitfield::new(10);
+        for index in [0, 1, 8, 9] {
+            have.set_piece(index);
+        }
+        assert!(!client.is_interesting(&have));
+    }
+
+    #[test]
+    fn ignore_haves_out_of_torrent() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _remote = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        let mut client = Client::from_stream(Peer::new(), vec![], vec![], 10, conn);
+        client.restore_bitfield(Bitfield::new(10));
+
+        // Keep spare bits of bitfield clear
+        client.read_have(10).unwrap();
+        client.read_have(9).unwrap();
+        client.read_have(u32::MAX).unwrap();
+        assert_eq!(client.bitfield().as_bytes(), &[0, 0b0100_0000]);
+
+        // Drop peer that keeps sending pieces out of the torrent
+        let error = client.read_have(15).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "received MESSAGE_HAVE for piece 15 from peer, torrent has 10 pieces"
+        );
+    }
+
+    #[test]
+    fn read_bitfield_from_peer_sending_haves() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut remote = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        conn.set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut client = Client::from_stream(Peer::new(), vec![], vec![], 8, conn);
+
+        // Apply a HAVE sent instead of a bitfield to an empty bitfield
+        remote
+            .write_all(&MessageKind::Have(2).serialize().unwrap())
+            .unwrap();
+        client.read_bitfield().unwrap();
+        assert_eq!(client.bitfield().as_bytes(), &[0b0010_0000]);
+
+        // Keep a peer that sends nothing, starting with an empty bitfield
+        client.read_bitfield().unwrap();
+        assert_eq!(client.bitfield().as_bytes(), &[0]);
+
+        // Apply an UNCHOKE sent instead of a bitfield
+        remote
+            .write_all(&MessageKind::Unchoke.serialize().unwrap())
+            .unwrap();
+        client.read_bitfield().unwrap();
+        assert!(!client.is_choked());
+
+        // Reject a block sent before anything was requested
+        let piece = MessageKind::Piece {
+            index: 0,
+            begin: 0,
+            block: vec![0xAB; 16],
+        };
+        remote.write_all(&piece.serialize().unwrap()).unwrap();
+        assert!(client.read_bitfield().is_err());
+    }
+
+    #[test]
+    fn read_messages_from_slow_writer_without_desync() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut remote = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        remote.set_nodelay(true).unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        conn.set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let mut client = Client::from_stream(Peer::new(), vec![], vec![], 8, conn);
+
+        // Write messages byte by byte, each byte arriving within the read timeout
+        let have = MessageKind::Have(3);
+        let request = MessageKind::Request {
+            index: 1,
+            begin: 0,
+            length: 16384,
+        };
+        let mut bytes = have.serialize().unwrap();
+        bytes.extend(request.serialize().unwrap());
+        let writer = std::thread::spawn(move || {
+            for byte in bytes {
+                remote.write_all(&[byte]).unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            remote
+        });
+        assert_eq!(client.read_message().unwrap(), have);
+        assert_eq!(client.read_message().unwrap(), request);
+        let mut remote = writer.join().unwrap();
+
+        // Keep stream aligned when no message starts within the read timeout
+        let error = client.read_message().unwrap_err();
+        assert_eq!(error.to_string(), "timed out waiting for message from peer");
+        remote.write_all(&have.serialize().unwrap()).unwrap();
+        assert_eq!(client.read_message().unwrap(), have);
+
+        // Wait for a message to start for a shorter time, then read it within the read timeout
+        let message = client.read_message_within(Duration::from_millis(50));
+        assert!(message.unwrap().is_none());
+        let bytes = have.serialize().unwrap();
+        remote.write_all(&bytes[..2]).unwrap();
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            remote.write_all(&bytes[2..]).unwrap();
+            remote
+        });
+        let message = client.read_message_within(Duration::from_millis(50));
+        assert_eq!(message.unwrap(), Some(MessageKind::Have(3)));
+        let mut remote = writer.join().unwrap();
+
+        // Drop connection when a message stalls midway, even once the rest is received
+        let bytes = request.serialize().unwrap();
+        remote.write_all(&bytes[..6]).unwrap();
+        assert!(client.read_message().is_err());
+        remote.write_all(&bytes[6..]).unwrap();
+        remote.write_all(&have.serialize().unwrap()).unwrap();
+        let error = client.read_message().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "connection to peer is out of sync after a failed read"
+        );
+    }
+}
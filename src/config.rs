@@ -0,0 +1,533 @@
+// Copyright (c) 2020 zenoxygen
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use anyhow::{anyhow, Result};
+
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::time::Duration;
+
+pub(crate) const MAX_PIECE_FAILURES: usize = 3;
+pub(crate) const MAX_PIECES_PER_PEER: usize = 1;
+pub(crate) const MAX_INFLIGHT_PIECES: usize = 16;
+pub(crate) const CHOKE_TIMEOUT: Duration = Duration::from_secs(30);
+pub(crate) const MAX_CONNECTING: usize = 20;
+pub(crate) const MAX_PEERS: usize = 50;
+pub(crate) const VERIFY_BATCH: usize = 64;
+
+/// Torrent configuration structure, the options of a download validated once when built.
+///
+/// Build it with `TorrentConfig::builder()`, options left unset keep their default value.
+///
+#[derive(Debug, Clone)]
+pub struct TorrentConfig {
+    // Disable Nagle's algorithm on peer connections
+    pub(crate) nodelay: bool,
+    // Connect to peers on the local network first
+    pub(crate) prefer_local: bool,
+    // Local address to connect to trackers and peers from
+    pub(crate) bind: Option<IpAddr>,
+    // Maximum number of pieces downloaded simultaneously
+    pub(crate) max_inflight_pieces: usize,
+    // Maximum size in bytes of data buffers of pieces being downloaded
+    pub(crate) max_memory: Option<u64>,
+    // Port number to accept incoming connections on
+    pub(crate) listen_port: Option<u16>,
+    // Number of peers allowed to send a corrupted piece
+    pub(crate) max_piece_failures: usize,
+    // Number of threads verifying downloaded pieces, one per core up to a limit by default
+    pub(crate) hash_threads: Option<usize>,
+    // Number of pieces read at once when verifying saved data on resume
+    pub(crate) verify_batch: usize,
+    // Maximum number of pieces downloaded at once from a single peer
+    pub(crate) max_pieces_per_peer: usize,
+    // Time a peer may keep this client choked before its pieces are given to other peers
+    pub(crate) choke_timeout: Duration,
+    // Maximum number of connection attempts at once
+    pub(crate) max_connecting: usize,
+    // Maximum number of peers connected at once
+    pub(crate) max_peers: usize,
+    // Seed to shuffle peers reproducibly, random when not set
+    pub(crate) seed: Option<u64>,
+    // Maximum size of the torrent data in bytes
+    pub(crate) max_size: Option<u64>,
+    // Verify downloaded pieces without saving them
+    pub(crate) discard: bool,
+    // Hold downloaded pieces in memory, writing output files once download completes
+    pub(crate) in_memory: bool,
+    // Write verified pieces to stdout in order, without saving them
+    pub(crate) stdout: bool,
+    // Allocate disk space for output files before download
+    pub(crate) preallocate: bool,
+    // Check there is enough disk space before download
+    pub(crate) check_space: bool,
+    // Only print errors
+    pub(crate) quiet: bool,
+    // Draw progress as a bar, rather than printing plain lines from time to time
+    pub(crate) progress_bar: bool,
+}
+
+impl Default for TorrentConfig {
+    fn default() -> Self {
+        TorrentConfig {
+            nodelay: true,
+            prefer_local: true,
+            bind: None,
+            max_inflight_pieces: MAX_INFLIGHT_PIECES,
+            max_memory: None,
+            listen_port: None,
+            max_piece_failures: MAX_PIECE_FAILURES,
+            hash_threads: None,
+            verify_batch: VERIFY_BATCH,
+            max_pieces_per_peer: MAX_PIECES_PER_PEER,
+            choke_timeout: CHOKE_TIMEOUT,
+            max_connecting: MAX_CONNECTING,
+            max_peers: MAX_PEERS,
+            seed: None,
+            max_size: None,
+            discard: false,
+            in_memory: false,
+            stdout: false,
+            preallocate: false,
+            check_space: true,
+            quiet: false,
+            progress_bar: true,
+        }
+    }
+}
+
+impl TorrentConfig {
+    /// Start building a torrent configuration, from default options.
+    pub fn builder() -> TorrentConfigBuilder {
+        TorrentConfigBuilder {
+            config: TorrentConfig::default(),
+        }
+    }
+
+    /// Check options are valid and do not conflict with each other.
+    fn validate(&self) -> Result<()> {
+        // Check limits allow download to progress
+        if self.max_inflight_pieces == 0 {
+            return Err(anyhow!(
+                "invalid maximum number of in-flight pieces, must not be zero"
+            ));
+        }
+        if self.max_memory == Some(0) {
+            return Err(anyhow!(
+                "invalid maximum memory for pieces data, must not be zero"
+            ));
+        }
+        if self.max_piece_failures == 0 {
+            return Err(anyhow!(
+                "invalid number of peers allowed to fail a piece, must not be zero"
+            ));
+        }
+        if self.max_pieces_per_peer == 0 {
+            return Err(anyhow!(
+                "invalid maximum number of pieces per peer, must not be zero"
+            ));
+        }
+        if self.max_connecting == 0 {
+            return Err(anyhow!(
+                "invalid maximum number of connection attempts, must not be zero"
+            ));
+        }
+        if self.max_peers == 0 {
+            return Err(anyhow!("invalid maximum number of peers, must not be zero"));
+        }
+        if self.choke_timeout.is_zero() {
+            return Err(anyhow!(
+                "invalid time to wait while choked, must not be zero"
+            ));
+        }
+        if self.hash_threads == Some(0) {
+            return Err(anyhow!(
+                "invalid number of hashing threads, must not be zero"
+            ));
+        }
+        if self.verify_batch == 0 {
+            return Err(anyhow!(
+                "invalid number of pieces to verify at once, must not be zero"
+            ));
+        }
+
+        // Check downloaded data is handled in a single way
+        let conflicts = [
+            (self.discard, "discard", self.stdout, "stdout"),
+            (self.discard, "discard", self.in_memory, "in-memory"),
+            (self.discard, "discard", self.preallocate, "preallocate"),
+            (
+                self.discard,
+                "discard",
+                self.listen_port.is_some(),
+                "listen",
+            ),
+            (self.stdout, "stdout", self.in_memory, "in-memory"),
+            (self.stdout, "stdout", self.preallocate, "preallocate"),
+            (self.stdout, "stdout", self.listen_port.is_some(), "listen"),
+            (
+                self.in_memory,
+                "in-memory",
+                self.listen_port.is_some(),
+                "listen",
+            ),
+        ];
+        for (first, first_name, second, second_name) in conflicts {
+            if first && second {
+                return Err(anyhow!(
+                    "invalid options, {} conflicts with {}",
+                    first_name,
+                    second_name
+                ));
+            }
+        }
+
+        // Check address can be bound to, before any connection is attempted
+        if let Some(bind) = self.bind {
+            if TcpListener::bind(SocketAddr::new(bind, 0)).is_err() {
+                return Err(anyhow!("could not bind to address {}", bind));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Torrent configuration builder structure, checking options when the configuration is built.
+#[derive(Debug, Clone)]
+pub struct TorrentConfigBuilder {
+    config: TorrentConfig,
+}
+
+impl TorrentConfigBuilder {
+    /// Set whether Nagle's algorithm is disabled on peer connections.
+    ///
+    /// # Arguments
+    ///
+    /// * `nodelay` - Disable Nagle's algorithm.
+    ///
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.config.nodelay = nodelay;
+        self
+    }
+
+    /// Set whether peers on the local network are connected to first.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefer_local` - Connect to local peers first.
+    ///
+    pub fn prefer_local(mut self, prefer_local: bool) -> Self {
+        self.config.prefer_local = prefer_local;
+        self
+    }
+
+    /// Set local address to connect to trackers and peers from.
+    ///
+    /// # Arguments
+    ///
+    /// * `bind` - The local IP address, which must be assigned to an interface.
+    ///
+    pub fn bind_address(mut self, bind: IpAddr) -> Self {
+        self.config.bind = Some(bind);
+        self
+    }
+
+    /// Set maximum number of pieces downloaded simultaneously, across all peers and web seeds.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_inflight_pieces` - The maximum number of in-flight pieces.
+    ///
+    pub fn max_inflight_pieces(mut self, max_inflight_pieces: usize) -> Self {
+        self.config.max_inflight_pieces = max_inflight_pieces;
+        self
+    }
+
+    /// Set maximum size of data buffers of pieces being downloaded, limiting in-flight pieces.
+    ///
+    /// At least one piece is always downloaded, even if it is larger than the limit.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_memory` - The maximum size in bytes.
+    ///
+    pub fn max_memory(mut self, max_memory: u64) -> Self {
+        self.config.max_memory = Some(max_memory);
+        self
+    }
+
+    /// Set port number to accept incoming connections on.
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The port number to listen on.
+    ///
+    pub fn listen_port(mut self, port: u16) -> Self {
+        self.config.listen_port = Some(port);
+        self
+    }
+
+    /// Set number of peers allowed to send a corrupted piece before aborting download.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_piece_failures` - The maximum number of failures per piece.
+    ///
+    pub fn max_piece_failures(mut self, max_piece_failures: usize) -> Self {
+        self.config.max_piece_failures = max_piece_failures;
+        self
+    }
+
+    /// Set number of threads verifying downloaded pieces.
+    ///
+    /// # Arguments
+    ///
+    /// * `hash_threads` - The number of hashing threads.
+    ///
+    pub fn hash_threads(mut self, hash_threads: usize) -> Self {
+        self.config.hash_threads = Some(hash_threads);
+        self
+    }
+
+    /// Set number of pieces read at once when verifying data saved before, on resume.
+    ///
+    /// # Arguments
+    ///
+    /// * `verify_batch` - The number of pieces read at once.
+    ///
+    pub fn verify_batch(mut self, verify_batch: usize) -> Self {
+        self.config.verify_batch = verify_batch;
+        self
+    }
+
+    /// Set maximum number of pieces downloaded at once from a single peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_pieces_per_peer` - The maximum number of pieces per peer.
+    ///
+    pub fn max_pieces_per_peer(mut self, max_pieces_per_peer: usize) -> Self {
+        self.config.max_pieces_per_peer = max_pieces_per_peer;
+        self
+    }
+
+    /// Set time a peer may keep this client choked, before pieces it was downloading are given back.
+    ///
+    /// # Arguments
+    ///
+    /// * `choke_timeout` - The time a peer may keep this client choked.
+    ///
+    pub fn choke_timeout(mut self, choke_timeout: Duration) -> Self {
+        self.config.choke_timeout = choke_timeout;
+        self
+    }
+
+    /// Set maximum number of connection attempts at once, until handshake completes or fails.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_connecting` - The maximum number of connection attempts.
+    ///
+    pub fn max_connecting(mut self, max_connecting: usize) -> Self {
+        self.config.max_connecting = max_connecting;
+        self
+    }
+
+    /// Set maximum number of peers downloaded from at once, and of incoming peers served at once.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_peers` - The maximum number of connected peers.
+    ///
+    pub fn max_peers(mut self, max_peers: usize) -> Self {
+        self.config.max_peers = max_peers;
+        self
+    }
+
+    /// Set seed to shuffle peers reproducibly before connecting to them.
+    ///
+    /// # Arguments
+    ///
+    /// * `seed` - The seed of the peers shuffle.
+    ///
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.config.seed = Some(seed);
+        self
+    }
+
+    /// Set maximum size of the torrent data, rejecting larger torrents before contacting peers.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_size` - The maximum size in bytes.
+    ///
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.config.max_size = Some(max_size);
+        self
+    }
+
+    /// Set whether downloaded pieces are verified without being saved to output files.
+    ///
+    /// # Arguments
+    ///
+    /// * `discard` - Discard downloaded data.
+    ///
+    pub fn discard(mut self, discard: bool) -> Self {
+        self.config.discard = discard;
+        self
+    }
+
+    /// Set whether downloaded pieces are held in memory, and written once download completes.
+    ///
+    /// Output files are written by runs of consecutive pieces, which is faster for small
+    /// torrents but holds the whole torrent data in memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `in_memory` - Hold downloaded pieces in memory.
+    ///
+    pub fn in_memory(mut self, in_memory: bool) -> Self {
+        self.config.in_memory = in_memory;
+        self
+    }
+
+    /// Set whether verified pieces are written to stdout in order, instead of output files.
+    ///
+    /// Pieces are downloaded in order, and pieces too far ahead of the last written piece are
+    /// held back, so only a bounded number of pieces is buffered.
+    ///
+    /// # Arguments
+    ///
+    /// * `stdout` - Write verified pieces to stdout.
+    ///
+    pub fn stdout(mut self, stdout: bool) -> Self {
+        self.config.stdout = stdout;
+        self
+    }
+
+    /// Set whether disk space is allocated for output files before download.
+    ///
+    /// # Arguments
+    ///
+    /// * `preallocate` - Allocate output files to their full length.
+    ///
+    pub fn preallocate(mut self, preallocate: bool) -> Self {
+        self.config.preallocate = preallocate;
+        self
+    }
+
+    /// Set whether available disk space is checked before download.
+    ///
+    /// # Arguments
+    ///
+    /// * `check_space` - Check there is room for the whole torrent.
+    ///
+    pub fn check_space(mut self, check_space: bool) -> Self {
+        self.config.check_space = check_space;
+        self
+    }
+
+    /// Set whether only errors are printed, hiding progress.
+    ///
+    /// # Arguments
+    ///
+    /// * `quiet` - Only print errors.
+    ///
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.config.quiet = quiet;
+        self
+    }
+
+    /// Set whether progress is drawn as a bar, or printed as plain lines suited to logs.
+    ///
+    /// # Arguments
+    ///
+    /// * `progress_bar` - Draw progress as a bar.
+    ///
+    pub fn progress_bar(mut self, progress_bar: bool) -> Self {
+        self.config.progress_bar = progress_bar;
+        self
+    }
+
+    /// Build the torrent configuration, rejecting invalid or conflicting options.
+    pub fn build(self) -> Result<TorrentConfig> {
+        self.config.validate()?;
+
+        Ok(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_config_with_defaults() {
+        let config = TorrentConfig::builder().build().unwrap();
+        assert!(config.nodelay);
+        assert!(config.check_space);
+        assert!(config.progress_bar);
+        assert_eq!(config.max_piece_failures, MAX_PIECE_FAILURES);
+        assert_eq!(config.choke_timeout, CHOKE_TIMEOUT);
+        assert_eq!(config.max_inflight_pieces, MAX_INFLIGHT_PIECES);
+
+        let config = TorrentConfig::builder()
+            .max_inflight_pieces(8)
+            .listen_port(6881)
+            .quiet(true)
+            .build()
+            .unwrap();
+        assert_eq!(config.max_inflight_pieces, 8);
+        assert_eq!(config.listen_port, Some(6881));
+        assert!(config.quiet);
+    }
+
+    #[test]
+    fn reject_invalid_config() {
+        // Limits that would stall download
+        assert!(TorrentConfig::builder().max_connecting(0).build().is_err());
+        assert!(TorrentConfig::builder().max_peers(0).build().is_err());
+        assert!(TorrentConfig::builder()
+            .max_inflight_pieces(0)
+            .build()
+            .is_err());
+        assert!(TorrentConfig::builder().max_memory(0).build().is_err());
+        assert!(TorrentConfig::builder()
+            .max_pieces_per_peer(0)
+            .build()
+            .is_err());
+        assert!(TorrentConfig::builder()
+            .choke_timeout(Duration::ZERO)
+            .build()
+            .is_err());
+
+        // Downloaded data handled in several ways
+        assert!(TorrentConfig::builder()
+            .discard(true)
+            .stdout(true)
+            .build()
+            .is_err());
+        assert!(TorrentConfig::builder()
+            .in_memory(true)
+            .listen_port(6881)
+            .build()
+            .is_err());
+    }
+}
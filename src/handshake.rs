@@ -22,12 +22,21 @@ use anyhow::Result;
 
 const PROTOCOL_ID: &str = "BitTorrent protocol";
 
+// Reserved bit for the extension protocol (BEP 10), in byte 5
+const RESERVED_EXTENSION_PROTOCOL: u8 = 0x10;
+
+// Reserved bit for the fast extension (BEP 6), in byte 7
+const RESERVED_FAST_EXTENSION: u8 = 0x04;
+
+// Reserved bit for the DHT protocol (BEP 5), in byte 7
+const RESERVED_DHT: u8 = 0x01;
+
 /// Handshake structure.
 pub struct Handshake {
     pub pstrlen: usize,
     // String identifier of the protocol
     pub pstr: Vec<u8>,
-    // 8 reserved bytes, all set to 0
+    // 8 reserved bytes, used to announce supported extensions
     pub reserved: Vec<u8>,
     // 20-byte SHA-1 hash of the info key in the metainfo file
     pub info_hash: Vec<u8>,
@@ -35,6 +44,38 @@ pub struct Handshake {
     pub peer_id: Vec<u8>,
 }
 
+/// PeerCapabilities structure.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct PeerCapabilities {
+    // Peer supports the DHT protocol
+    pub dht: bool,
+    // Peer supports the fast extension
+    pub fast_extension: bool,
+    // Peer supports the extension protocol
+    pub extension_protocol: bool,
+}
+
+impl PeerCapabilities {
+    /// Build peer capabilities from handshake reserved bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `reserved` - The 8 reserved bytes of a handshake message.
+    ///
+    pub fn from_reserved(reserved: &[u8]) -> Self {
+        // Prevent unbounded values
+        if reserved.len() != 8 {
+            return Default::default();
+        }
+
+        PeerCapabilities {
+            dht: reserved[7] & RESERVED_DHT != 0,
+            fast_extension: reserved[7] & RESERVED_FAST_EXTENSION != 0,
+            extension_protocol: reserved[5] & RESERVED_EXTENSION_PROTOCOL != 0,
+        }
+    }
+}
+
 impl Handshake {
     /// Build a new handshake message.
     ///
@@ -48,8 +89,9 @@ impl Handshake {
         let pstr = String::from(PROTOCOL_ID).into_bytes();
         // Get pstrlen
         let pstrlen = pstr.len();
-        // Get reserved
-        let reserved: Vec<u8> = vec![0; 8];
+        // Get reserved, announcing support of the fast extension
+        let mut reserved: Vec<u8> = vec![0; 8];
+        reserved[7] |= RESERVED_FAST_EXTENSION;
 
         Handshake {
             pstrlen,
@@ -115,3 +157,48 @@ pub fn deserialize_handshake(buf: &[u8], pstrlen: usize) -> Result<Handshake> {
 
     Ok(handshake)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_handshake() {
+        let peer_id: Vec<u8> = b"-XR0001-123456789012".to_vec();
+        let info_hash: Vec<u8> = vec![0xAB; 20];
+        let handshake = Handshake::new(peer_id.clone(), info_hash.clone());
+
+        let mut expected: Vec<u8> = vec![19];
+        expected.extend_from_slice(b"BitTorrent protocol");
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, RESERVED_FAST_EXTENSION]);
+        expected.extend_from_slice(&info_hash);
+        expected.extend_from_slice(&peer_id);
+        assert_eq!(handshake.serialize().unwrap(), expected);
+    }
+
+    #[test]
+    fn decode_peer_capabilities() {
+        // Reserved bytes sent by a peer supporting DHT, fast and extension protocols
+        let reserved: Vec<u8> = vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x05];
+        let capabilities = PeerCapabilities::from_reserved(&reserved);
+        assert_eq!(
+            capabilities,
+            PeerCapabilities {
+                dht: true,
+                fast_extension: true,
+                extension_protocol: true,
+            }
+        );
+
+        // Reserved bytes sent by a peer supporting only the fast extension
+        let reserved: Vec<u8> = vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04];
+        let capabilities = PeerCapabilities::from_reserved(&reserved);
+        assert!(!capabilities.dht);
+        assert!(capabilities.fast_extension);
+        assert!(!capabilities.extension_protocol);
+
+        // Reserved bytes all set to 0
+        let capabilities = PeerCapabilities::from_reserved(&[0; 8]);
+        assert_eq!(capabilities, PeerCapabilities::default());
+    }
+}
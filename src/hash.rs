@@ -0,0 +1,161 @@
+// Copyright (c) 2020 zenoxygen
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use anyhow::{anyhow, Result};
+
+/// Hash data with SHA-1.
+///
+/// # Arguments
+///
+/// * `data` - The data to hash.
+///
+#[cfg(all(feature = "boring", not(feature = "pure-rust")))]
+pub fn sha1(data: &[u8]) -> Result<Vec<u8>> {
+    use boring::sha::Sha1;
+    use std::panic;
+
+    // BoringSSL may fail to initialize on some platforms, report it instead of panicking
+    let hash = panic::catch_unwind(|| {
+        let mut hasher = Sha1::new();
+        hasher.update(data);
+        hasher.finish().to_vec()
+    });
+
+    match hash {
+        Ok(hash) => Ok(hash),
+        Err(_) => Err(anyhow!(
+            "could not initialize SHA-1 hasher from BoringSSL, \
+             try building with `--no-default-features --features pure-rust`"
+        )),
+    }
+}
+
+/// Hash data with SHA-1.
+///
+/// # Arguments
+///
+/// * `data` - The data to hash.
+///
+#[cfg(any(feature = "pure-rust", not(feature = "boring")))]
+pub fn sha1(data: &[u8]) -> Result<Vec<u8>> {
+    // Initial hash values
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    // Pad message with a single bit, zeros and the message length in bits
+    let nb_bits = match (data.len() as u64).checked_mul(8) {
+        Some(nb_bits) => nb_bits,
+        None => return Err(anyhow!("could not hash data, too large")),
+    };
+    let mut message: Vec<u8> = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&nb_bits.to_be_bytes());
+
+    // Process message in 512-bit chunks
+    for chunk in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in chunk.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (value, chunk_value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(chunk_value);
+        }
+    }
+
+    // Produce the 20-byte digest
+    let hash: Vec<u8> = h.iter().flat_map(|value| value.to_be_bytes()).collect();
+
+    Ok(hash)
+}
+
+/// Compare two hashes in a time independent of where they differ.
+///
+/// # Arguments
+///
+/// * `a` - The first hash.
+/// * `b` - The second hash.
+///
+pub fn hashes_match(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    // Accumulate differences of every byte, rather than returning at the first one
+    a.iter()
+        .zip(b.iter())
+        .fold(0, |diff, (x, y)| diff | (x ^ y))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_known_vectors() {
+        assert_eq!(
+            hex::encode(sha1(b"").unwrap()),
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709"
+        );
+        assert_eq!(
+            hex::encode(sha1(b"abc").unwrap()),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            hex::encode(sha1(&[b'a'; 1000]).unwrap()),
+            "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+        );
+    }
+
+    #[test]
+    fn compare_hashes() {
+        let hash = sha1(b"abc").unwrap();
+        assert!(hashes_match(&hash, &hash.clone()));
+        assert!(!hashes_match(&hash, &sha1(b"abd").unwrap()));
+        assert!(!hashes_match(&hash, &hash[..19]));
+    }
+}
@@ -0,0 +1,42 @@
+// Copyright (c) 2020 zenoxygen
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! A BitTorrent client library, used by the `xerus` command-line client.
+
+#[macro_use]
+extern crate log;
+
+pub mod bitfield;
+pub mod client;
+pub mod config;
+pub mod handshake;
+pub mod hash;
+pub mod listener;
+pub mod message;
+pub mod peer;
+pub mod piece;
+pub mod progress;
+pub mod resume;
+pub mod stats;
+pub mod storage;
+pub mod torrent;
+pub mod verify;
+pub mod webseed;
+pub mod worker;
@@ -0,0 +1,418 @@
+// Copyright (c) 2020 zenoxygen
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::bitfield::*;
+use crate::client::*;
+use crate::message::*;
+use crate::peer::*;
+use crate::torrent::*;
+
+use anyhow::{anyhow, Result};
+
+use std::io::ErrorKind;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Maximum length of a block requested by a remote peer (2^17) in bytes
+const REQUEST_LENGTH_MAX: u32 = 131072;
+
+/// Listener structure.
+pub struct Listener {
+    // Socket accepting incoming connections
+    listener: TcpListener,
+    // Torrent to serve
+    torrent: Arc<Torrent>,
+    // Torrent peer id
+    peer_id: Vec<u8>,
+    // Torrent info hash
+    info_hash: Vec<u8>,
+    // Number of pieces in torrent
+    nb_pieces: u32,
+    // Bitfield of pieces saved to disk
+    have: Arc<Mutex<Bitfield>>,
+    // Listener has been asked to stop
+    stop: Arc<AtomicBool>,
+}
+
+/// ListenerHandle structure, stopping the listener when dropped.
+pub struct ListenerHandle {
+    // Listener has been asked to stop
+    stop: Arc<AtomicBool>,
+    // Thread accepting incoming connections
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for ListenerHandle {
+    fn drop(&mut self) {
+        // Ask listener to stop and wait for it
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Error: could not stop listener");
+            }
+        }
+    }
+}
+
+impl Listener {
+    /// Build a new listener.
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - Port number to listen on.
+    /// * `torrent` - The torrent to serve.
+    /// * `peer_id` - Urlencoded 20-byte string used as a unique ID for the client.
+    /// * `info_hash` - 20-byte SHA-1 hash of the info key in the metainfo file.
+    /// * `nb_pieces` - The number of pieces in torrent.
+    /// * `have` - The bitfield of pieces saved to disk.
+    ///
+    pub fn new(
+        port: u16,
+        torrent: Arc<Torrent>,
+        peer_id: Vec<u8>,
+        info_hash: Vec<u8>,
+        nb_pieces: u32,
+        have: Arc<Mutex<Bitfield>>,
+    ) -> Result<Listener> {
+        // Bind socket to port
+        let listener = match TcpListener::bind(("0.0.0.0", port)) {
+            Ok(listener) => listener,
+            Err(_) => return Err(anyhow!("could not listen on port {:?}", port)),
+        };
+
+        // Accept connections without blocking, to watch for stop requests
+        if listener.set_nonblocking(true).is_err() {
+            return Err(anyhow!("could not listen on port {:?}", port));
+        }
+
+        info!("Listening on port {:?}", port);
+
+        let listener = Listener {
+            listener,
+            torrent,
+            peer_id,
+            info_hash,
+            nb_pieces,
+            have,
+            stop: Arc::new(AtomicBool::new(false)),
+        };
+
+        Ok(listener)
+    }
+
+    /// Start accepting incoming connections in a new thread.
+    pub fn start(self) -> ListenerHandle {
+        let stop = self.stop.clone();
+        let thread = thread::spawn(move || {
+            self.accept_connections();
+        });
+
+        ListenerHandle {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Accept incoming connections until listener is asked to stop.
+    fn accept_connections(&self) {
+        let mut connections: Vec<(TcpStream, JoinHandle<()>)> = vec![];
+        let mut nb_connections: u32 = 0;
+
+        while !self.stop.load(Ordering::SeqCst) {
+            // Forget about peers that are no longer served, closing their connection
+            connections.retain(|(_, thread)| !thread.is_finished());
+
+            let (conn, addr) = match self.listener.accept() {
+                Ok(connection) => connection,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+                Err(_) => {
+                    error!("Error: could not accept incoming connection");
+                    continue;
+                }
+            };
+
+            // Close connection rather than serving more peers than allowed
+            let max_peers = self.torrent.config().max_peers;
+            if connections.len() >= max_peers {
+                info!(
+                    "Drop incoming connection from {}, already serving {} peers",
+                    addr, max_peers
+                );
+                continue;
+            }
+
+            let peer = Peer {
+                id: nb_connections,
+                ip: addr.ip(),
+                port: addr.port(),
+                seed: None,
+            };
+            nb_connections += 1;
+
+            info!("Accept incoming connection from peer {}", peer);
+
+            // Send small messages without delay, keep connection on failure
+            if conn.set_nodelay(self.torrent.config().nodelay).is_err() {
+                warn!("Could not set TCP_NODELAY on connection to peer {}", peer);
+            }
+
+            // Keep a handle on connection to shut it down on exit
+            let conn_copy = match conn.try_clone() {
+                Ok(conn_copy) => conn_copy,
+                Err(_) => continue,
+            };
+
+            // Serve peer in a new thread
+            let mut client = Client::from_stream(
+                peer,
+                self.peer_id.clone(),
+                self.info_hash.clone(),
+                self.nb_pieces,
+                conn,
+            );
+            client.set_stats(self.torrent.stats());
+            let torrent = self.torrent.clone();
+            let have = self.have.clone();
+            let stop = self.stop.clone();
+            let thread = thread::spawn(move || {
+                if let Err(e) = serve_peer(client, torrent, have, stop) {
+                    info!("Stop serving peer: {}", e);
+                }
+            });
+
+            connections.push((conn_copy, thread));
+        }
+
+        // Shut down connections and wait for serving threads
+        for (conn, thread) in connections {
+            let _ = conn.shutdown(Shutdown::Both);
+            if thread.join().is_err() {
+                error!("Error: could not stop serving peer");
+            }
+        }
+    }
+}
+
+/// Serve pieces to a remote peer.
+///
+/// # Arguments
+///
+/// * `client` - A client connected to a remote peer.
+/// * `torrent` - The torrent to serve.
+/// * `have` - The bitfield of pieces saved to disk.
+/// * `stop` - Listener has been asked to stop.
+///
+fn serve_peer(
+    mut client: Client,
+    torrent: Arc<Torrent>,
+    have: Arc<Mutex<Bitfield>>,
+    stop: Arc<AtomicBool>,
+) -> Result<()> {
+    // Set connection timeout
+    client.set_connection_timeout(120)?;
+
+    // Handshake with peer, rejecting other torrents
+    client.accept_handshake()?;
+
+    // Send bitfield of pieces saved to disk
+    let bitfield: Bitfield = match have.lock() {
+        Ok(have) => have.clone(),
+        Err(_) => return Err(anyhow!("could not read bitfield")),
+    };
+    client.send_bitfield(&bitfield)?;
+    let mut announced: Bitfield = bitfield;
+
+    // Peer is choked until it is first interested
+    let mut choking = true;
+
+    while !stop.load(Ordering::SeqCst) {
+        // Notify peer of pieces saved to disk since last announced
+        let bitfield: Bitfield = match have.lock() {
+            Ok(have) => have.clone(),
+            Err(_) => return Err(anyhow!("could not read bitfield")),
+        };
+        client.send_new_haves(&bitfield, &mut announced)?;
+
+        // Listen peer
+        let message: MessageKind = client.read_message()?;
+        let name = message.name();
+
+        // Parse message
+        match message {
+            MessageKind::KeepAlive => {}
+            MessageKind::Interested => {
+                client.read_interested();
+                if choking {
+                    client.send_unchoke()?;
+                    choking = false;
+                }
+            }
+            MessageKind::NotInterested => client.read_not_interested(),
+            MessageKind::Request {
+                index,
+                begin,
+                length,
+            } => {
+                client.read_request(index, begin, length);
+
+                // Check if piece is saved to disk
+                let has_piece = match have.lock() {
+                    Ok(have) => have.has_piece(index),
+                    Err(_) => return Err(anyhow!("could not read bitfield")),
+                };
+                if !has_piece || length > REQUEST_LENGTH_MAX {
+                    info!("Ignore request for unavailable block of piece {:?}", index);
+                    continue;
+                }
+
+                // Send block to peer
+                let block: Vec<u8> = torrent.read_block(index, begin, length)?;
+                client.send_piece(index, begin, block)?;
+                torrent.stats().add_uploaded(length as u64);
+            }
+            _ => info!("Ignore {} from peer", name),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::*;
+    use crate::handshake::*;
+
+    use std::io::{Read, Write};
+
+    // Listener of a torrent of a single piece, on a port that was free
+    fn start_listener(torrent: Torrent) -> (u16, ListenerHandle) {
+        let port = TcpListener::bind(("0.0.0.0", 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let listener = Listener::new(
+            port,
+            Arc::new(torrent),
+            vec![0; 20],
+            vec![0xAB; 20],
+            1,
+            Arc::new(Mutex::new(Bitfield::new(1))),
+        )
+        .unwrap();
+        (port, listener.start())
+    }
+
+    #[test]
+    fn close_connection_of_other_torrent() {
+        let (port, _handle) = start_listener(Torrent::new());
+        let mut remote = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        remote
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        // Connection is closed without any handshake or bitfield sent back
+        let handshake = Handshake::new(vec![1; 20], vec![0xCD; 20]);
+        remote.write_all(&handshake.serialize().unwrap()).unwrap();
+        let mut received = vec![];
+        let _ = remote.read_to_end(&mut received);
+        assert!(received.is_empty());
+    }
+
+    #[test]
+    fn stop_listener_when_handle_is_dropped() {
+        let (port, handle) = start_listener(Torrent::new());
+        let mut remote = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        remote
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        // Peer of the torrent receives handshake and bitfield
+        let handshake = Handshake::new(vec![1; 20], vec![0xAB; 20]);
+        remote.write_all(&handshake.serialize().unwrap()).unwrap();
+        let mut received = [0; 68 + 6];
+        remote.read_exact(&mut received).unwrap();
+
+        // Served connection is shut down, and port is released once listener thread is joined
+        drop(handle);
+        let mut received = vec![];
+        assert_eq!(remote.read_to_end(&mut received).unwrap(), 0);
+        assert!(TcpListener::bind(("0.0.0.0", port)).is_ok());
+    }
+
+    #[test]
+    fn close_connections_beyond_max_peers() {
+        let config = TorrentConfig::builder().max_peers(1).build().unwrap();
+        let (port, _handle) = start_listener(Torrent::with_config(config));
+        let handshake = Handshake::new(vec![1; 20], vec![0xAB; 20]);
+
+        // First peer is served
+        let mut first = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        first
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        first.write_all(&handshake.serialize().unwrap()).unwrap();
+        let mut received = [0; 68 + 6];
+        first.read_exact(&mut received).unwrap();
+
+        // Second peer is disconnected while first one is served
+        let mut second = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        second
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let _ = second.write_all(&handshake.serialize().unwrap());
+        let mut received = vec![];
+        let _ = second.read_to_end(&mut received);
+        assert!(received.is_empty());
+    }
+
+    #[test]
+    fn unchoke_interested_peer_once() {
+        let (port, _handle) = start_listener(Torrent::new());
+        let mut remote = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        remote
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let handshake = Handshake::new(vec![1; 20], vec![0xAB; 20]);
+        remote.write_all(&handshake.serialize().unwrap()).unwrap();
+        let mut received = [0; 68 + 6];
+        remote.read_exact(&mut received).unwrap();
+
+        // Peer interested twice is unchoked a single time
+        let interested = MessageKind::Interested.serialize().unwrap();
+        remote.write_all(&interested).unwrap();
+        remote.write_all(&interested).unwrap();
+        let mut received = [0; 5];
+        remote.read_exact(&mut received).unwrap();
+        assert_eq!(received, [0, 0, 0, 1, MESSAGE_UNCHOKE]);
+        remote
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        assert!(remote.read(&mut [0; 1]).is_err());
+    }
+}
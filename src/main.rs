@@ -18,69 +18,665 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-#[macro_use]
-extern crate log;
-
 mod args;
-mod client;
-mod handshake;
-mod message;
-mod peer;
-mod piece;
-mod torrent;
-mod worker;
-
-use std::fs::File;
-use std::io::Write;
+
+use std::io::{self, IsTerminal};
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
+use indicatif::MultiProgress;
+use log::{info, warn, LevelFilter};
 
 use args::parse_args;
-use torrent::*;
+use xerus::config::TorrentConfig;
+use xerus::message::*;
+use xerus::stats::Stats;
+use xerus::torrent::*;
+
+// Delay before the first retry, doubled after each retry up to a limit
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+const RETRY_DELAY_MAX: Duration = Duration::from_secs(60);
 
 fn run(args: clap::ArgMatches) -> Result<()> {
-    let torrent = args.value_of("torrent").unwrap();
-    let file = args.value_of("file").unwrap();
+    let torrents: Vec<&str> = args.values_of("torrent").unwrap().collect();
+    let files: Vec<&str> = match args.values_of("file") {
+        Some(files) => files.collect(),
+        None => vec![],
+    };
 
-    // Check if torrent file exists
-    if !Path::new(&torrent).exists() {
-        return Err(anyhow!("could not find torrent"));
-    } else {
-        let torrent_filepath = PathBuf::from(torrent);
-        let output_filepath = PathBuf::from(file);
+    // Print info hashes of torrents to diagnose hash mismatches, without contacting trackers
+    if args.is_present("recompute-hash") {
+        for torrent in torrents.iter() {
+            print_info_hashes(torrent)?;
+        }
+        return Ok(());
+    }
+
+    // Check each torrent has a path where to save it, unless downloaded data is not saved
+    let saved =
+        !args.is_present("discard") && !args.is_present("list-peers") && !args.is_present("stdout");
+    if saved && files.len() != torrents.len() {
+        return Err(anyhow!(
+            "expected one file per torrent, got {:?} files for {:?} torrents",
+            files.len(),
+            torrents.len()
+        ));
+    }
+
+    // Check if torrent files exist
+    for torrent in torrents.iter() {
+        if !Path::new(torrent).exists() {
+            return Err(anyhow!("could not find torrent {:?}", torrent));
+        }
+    }
+
+    // Download a single torrent
+    if torrents.len() == 1 {
+        let torrent = build_torrent(&args)?;
+        let file = files.first().copied().unwrap_or_default();
+        return download_torrent(&args, torrent, torrents[0], file);
+    }
+
+    // Check incoming connections are not accepted for several torrents on the same port
+    if args.is_present("listen") {
+        return Err(anyhow!(
+            "could not listen on a single port for several torrents"
+        ));
+    }
+
+    // Check data of several torrents is not interleaved on stdout
+    if args.is_present("stdout") {
+        return Err(anyhow!("could not write several torrents to stdout"));
+    }
+
+    // Build torrents, sharing connection attempts and connected peers limits and progress display
+    let connect_limit = ConnectLimit::from_config(&build_config(&args)?);
+    let multi_progress = MultiProgress::new();
+    let mut jobs: Vec<(Torrent, &str, &str)> = vec![];
+    for (index, torrent_path) in torrents.iter().enumerate() {
+        let mut torrent = build_torrent(&args)?;
+        torrent.set_connect_limit(connect_limit.clone());
+        torrent.set_multi_progress(multi_progress.clone());
+        let file = files.get(index).copied().unwrap_or_default();
+        jobs.push((torrent, torrent_path, file));
+    }
+
+    // Download torrents concurrently, each in a new thread
+    let results: Vec<(&str, Result<()>)> = thread::scope(|scope| {
+        let handles: Vec<_> = jobs
+            .into_iter()
+            .map(|(torrent, torrent_path, file)| {
+                let args = &args;
+                let handle =
+                    scope.spawn(move || download_torrent(args, torrent, torrent_path, file));
+                (torrent_path, handle)
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|(torrent_path, handle)| match handle.join() {
+                Ok(result) => (torrent_path, result),
+                Err(_) => (torrent_path, Err(anyhow!("download thread panicked"))),
+            })
+            .collect()
+    });
+
+    // Report torrents that could not be downloaded
+    let mut nb_failed = 0;
+    for (torrent_path, result) in results {
+        if let Err(e) = result {
+            eprintln!("Error: could not download {:?}: {}", torrent_path, e);
+            nb_failed += 1;
+        }
+    }
+    if nb_failed > 0 {
+        return Err(anyhow!(
+            "could not download {:?} of {:?} torrents",
+            nb_failed,
+            torrents.len()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Build a torrent set up from arguments, not opened yet.
+///
+/// # Arguments
+///
+/// * `args` - The parsed arguments.
+///
+fn build_torrent(args: &clap::ArgMatches) -> Result<Torrent> {
+    let mut torrent = Torrent::with_config(build_config(args)?);
+    if let Some(expect_hash) = args.value_of("expect-hash") {
+        match hex::decode(expect_hash) {
+            Ok(info_hash) if info_hash.len() == 20 => torrent.set_expected_info_hash(info_hash),
+            _ => {
+                return Err(anyhow!(
+                    "invalid info hash to expect, not 40 hexadecimal digits"
+                ))
+            }
+        }
+    }
+    if let Some(priority_file) = args.value_of("priority-file") {
+        torrent.set_priority_file(PathBuf::from(priority_file));
+    }
+    if let Some(trackers) = args.value_of("trackers") {
+        torrent.set_trackers(read_trackers(trackers)?);
+    }
+    if let Some(range) = args.value_of("range") {
+        let (begin, end) = parse_range(range)?;
+        torrent.set_range(begin, end);
+    }
+
+    Ok(torrent)
+}
+
+/// Build the options of a download from arguments, rejecting invalid or conflicting ones.
+///
+/// # Arguments
+///
+/// * `args` - The parsed arguments.
+///
+fn build_config(args: &clap::ArgMatches) -> Result<TorrentConfig> {
+    let mut builder = TorrentConfig::builder()
+        .quiet(args.is_present("quiet"))
+        .progress_bar(!args.is_present("no-progress") && io::stderr().is_terminal())
+        .nodelay(!args.is_present("no-nodelay"))
+        .prefer_local(!args.is_present("no-prefer-local"))
+        .discard(args.is_present("discard"))
+        .preallocate(args.is_present("preallocate"))
+        .in_memory(args.is_present("in-memory"))
+        .stdout(args.is_present("stdout"))
+        .check_space(!args.is_present("no-space-check"));
+    if let Some(max_piece_failures) = args.value_of("max-piece-failures") {
+        match max_piece_failures.parse::<usize>() {
+            Ok(max_piece_failures) => builder = builder.max_piece_failures(max_piece_failures),
+            Err(_) => return Err(anyhow!("invalid number of peers allowed to fail a piece")),
+        }
+    }
+    if let Some(bind) = args.value_of("bind") {
+        match bind.parse::<IpAddr>() {
+            Ok(bind) => builder = builder.bind_address(bind),
+            Err(_) => return Err(anyhow!("invalid address to bind to")),
+        }
+    }
+    if let Some(port) = args.value_of("listen") {
+        match port.parse::<u16>() {
+            Ok(port) => builder = builder.listen_port(port),
+            Err(_) => return Err(anyhow!("invalid port to listen on")),
+        }
+    }
+    if let Some(max_inflight_pieces) = args.value_of("max-inflight-pieces") {
+        match max_inflight_pieces.parse::<usize>() {
+            Ok(max_inflight_pieces) => builder = builder.max_inflight_pieces(max_inflight_pieces),
+            Err(_) => return Err(anyhow!("invalid maximum number of in-flight pieces")),
+        }
+    }
+    if let Some(max_memory) = args.value_of("max-memory") {
+        match max_memory.parse::<u64>() {
+            Ok(max_memory) => builder = builder.max_memory(max_memory),
+            Err(_) => return Err(anyhow!("invalid maximum memory for pieces data")),
+        }
+    }
+    if let Some(max_connecting) = args.value_of("max-connecting") {
+        match max_connecting.parse::<usize>() {
+            Ok(max_connecting) => builder = builder.max_connecting(max_connecting),
+            Err(_) => return Err(anyhow!("invalid maximum number of connection attempts")),
+        }
+    }
+    if let Some(max_peers) = args.value_of("max-peers") {
+        match max_peers.parse::<usize>() {
+            Ok(max_peers) => builder = builder.max_peers(max_peers),
+            Err(_) => return Err(anyhow!("invalid maximum number of peers")),
+        }
+    }
+    if let Some(max_pieces_per_peer) = args.value_of("max-pieces-per-peer") {
+        match max_pieces_per_peer.parse::<usize>() {
+            Ok(max_pieces_per_peer) => builder = builder.max_pieces_per_peer(max_pieces_per_peer),
+            Err(_) => return Err(anyhow!("invalid maximum number of pieces per peer")),
+        }
+    }
+    if let Some(choke_timeout) = args.value_of("choke-timeout") {
+        match choke_timeout.parse::<u64>() {
+            Ok(choke_timeout) => {
+                builder = builder.choke_timeout(Duration::from_secs(choke_timeout))
+            }
+            Err(_) => return Err(anyhow!("invalid number of seconds to wait while choked")),
+        }
+    }
+    if let Some(hash_threads) = args.value_of("hash-threads") {
+        match hash_threads.parse::<usize>() {
+            Ok(hash_threads) => builder = builder.hash_threads(hash_threads),
+            Err(_) => return Err(anyhow!("invalid number of hashing threads")),
+        }
+    }
+    if let Some(verify_batch) = args.value_of("verify-batch") {
+        match verify_batch.parse::<usize>() {
+            Ok(verify_batch) => builder = builder.verify_batch(verify_batch),
+            Err(_) => return Err(anyhow!("invalid number of pieces to verify at once")),
+        }
+    }
+    if let Some(max_size) = args.value_of("max-size") {
+        match max_size.parse::<u64>() {
+            Ok(max_size) => builder = builder.max_size(max_size),
+            Err(_) => return Err(anyhow!("invalid maximum size of torrent")),
+        }
+    }
+    if let Some(seed) = args.value_of("seed") {
+        match seed.parse::<u64>() {
+            Ok(seed) => builder = builder.seed(seed),
+            Err(_) => return Err(anyhow!("invalid seed to shuffle peers")),
+        }
+    }
+
+    builder.build()
+}
+
+/// Open and download a torrent, retrying after a failure, then run the completion command.
+///
+/// # Arguments
+///
+/// * `args` - The parsed arguments.
+/// * `torrent` - The torrent set up from arguments.
+/// * `torrent_path` - The path to the torrent.
+/// * `file` - The path where to save the torrent.
+///
+fn download_torrent(
+    args: &clap::ArgMatches,
+    mut torrent: Torrent,
+    torrent_path: &str,
+    file: &str,
+) -> Result<()> {
+    let torrent_filepath = PathBuf::from(torrent_path);
+    let output_filepath = PathBuf::from(file);
+
+    let max_retries = match args.value_of("retries") {
+        Some(retries) => match retries.parse::<u32>() {
+            Ok(retries) => retries,
+            Err(_) => return Err(anyhow!("invalid number of retries")),
+        },
+        None => 0,
+    };
+    let mut nb_retries = 0;
+
+    // Open torrent, then announce again while trackers can not be reached
+    torrent.open(torrent_filepath)?;
+    while let Err(e) = torrent.announce() {
+        wait_retry(e, &mut nb_retries, max_retries)?;
+    }
+
+    // Print announced peers without connecting to them, to tell tracker problems from peer problems
+    if args.is_present("list-peers") {
+        print_peers(&torrent, torrent_path);
+        return Ok(());
+    }
+
+    // Check health of the swarm before connecting to peers, keeping stdout for downloaded data
+    if args.is_present("scrape") {
+        check_seeders(
+            &torrent,
+            args.is_present("require-seeders"),
+            args.is_present("quiet") || args.is_present("stdout"),
+        )?;
+    }
 
-        // Create new file
-        let mut output_file = match File::create(output_filepath) {
-            Ok(file) => file,
-            Err(_) => return Err(anyhow!("could not create file")),
-        };
+    // Check a sample of peers is reachable, before spawning workers for every peer
+    if args.is_present("check-peers") {
+        check_peers(
+            &torrent,
+            args.is_present("quiet") || args.is_present("stdout"),
+        );
+    }
+
+    // Create output files, unless downloaded data is not saved
+    if !args.is_present("discard") && !args.is_present("stdout") {
+        // Keep existing files that are not a previous download of this torrent
+        if !args.is_present("force") {
+            if let Err(e) = torrent.check_existing_files(&output_filepath) {
+                return Err(anyhow!("{}, use --force to overwrite it", e));
+            }
+        }
+        torrent.create_files(output_filepath)?;
+    }
+
+    // Download torrent, announcing and downloading again after a failure
+    while let Err(e) = torrent.download() {
+        // Stop without retrying once the program reading stdout is gone
+        if e.is::<StdoutClosed>() {
+            info!("Stop download: {}", e);
+            return Err(e);
+        }
+        wait_retry(e, &mut nb_retries, max_retries)?;
 
-        // Open and download torrent
-        let mut torrent = Torrent::new();
-        torrent.open(torrent_filepath)?;
-        let data: Vec<u8> = torrent.download()?;
+        // Pieces saved to disk are resumed from output files
+        while let Err(e) = torrent.announce() {
+            wait_retry(e, &mut nb_retries, max_retries)?;
+        }
+    }
 
-        // Save data to file
-        if output_file.write(&data).is_err() {
-            return Err(anyhow!("could not write data to file"));
+    if !args.is_present("quiet") {
+        let messages = format_messages(&torrent.stats());
+        if args.is_present("discard") {
+            println!("Discarded downloaded data.");
+            println!("{}", messages);
+        } else if args.is_present("stdout") {
+            eprintln!("Wrote downloaded data to stdout.");
+            eprintln!("{}", messages);
+        } else {
+            println!("Saved in {:?}.", file);
+            println!("{}", messages);
         }
+    }
 
-        println!("Saved in {:?}.", file);
+    // Run completion command, keeping downloaded data whatever happens
+    if let Some(command) = args.value_of("on-complete") {
+        run_on_complete(command, file, args.is_present("quiet"))?;
     }
 
     Ok(())
 }
 
-fn main() {
-    // Initialize logger
-    pretty_env_logger::init_timed();
+/// Format the number of messages exchanged with peers, for the main message types.
+///
+/// # Arguments
+///
+/// * `stats` - The stats of the download.
+///
+fn format_messages(stats: &Stats) -> String {
+    let counts: Vec<String> = [
+        MESSAGE_CHOKE,
+        MESSAGE_UNCHOKE,
+        MESSAGE_HAVE,
+        MESSAGE_REQUEST,
+        MESSAGE_PIECE,
+    ]
+    .iter()
+    .map(|&id| {
+        format!(
+            "{} {:?}/{:?}",
+            message_name(id),
+            stats.messages_sent(id),
+            stats.messages_received(id)
+        )
+    })
+    .collect();
+
+    format!("Messages sent/received: {}.", counts.join(", "))
+}
+
+/// Read announce URLs of trackers from a file, one per line.
+///
+/// Empty lines and lines starting with `#` are ignored.
+///
+/// # Arguments
+///
+/// * `path` - The path to the list of trackers.
+///
+fn read_trackers(path: &str) -> Result<Vec<String>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(_) => return Err(anyhow!("could not read trackers from {:?}", path)),
+    };
+
+    Ok(content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_owned())
+        .collect())
+}
+
+/// Parse a byte range, given as the offsets of its first and last bytes.
+///
+/// # Arguments
+///
+/// * `range` - The range, as `START-END`.
+///
+fn parse_range(range: &str) -> Result<(u64, u64)> {
+    let parsed = range
+        .split_once('-')
+        .and_then(|(begin, end)| Some((begin.parse::<u64>().ok()?, end.parse::<u64>().ok()?)));
+    match parsed {
+        Some(parsed) => Ok(parsed),
+        None => Err(anyhow!(
+            "invalid range {:?}, expected offsets of first and last bytes as START-END",
+            range
+        )),
+    }
+}
+
+/// Wait before retrying after a failure, or fail with the number of retries once none is left.
+///
+/// # Arguments
+///
+/// * `error` - The failure.
+/// * `nb_retries` - The number of retries so far, incremented before waiting.
+/// * `max_retries` - The number of retries allowed.
+///
+fn wait_retry(error: anyhow::Error, nb_retries: &mut u32, max_retries: u32) -> Result<()> {
+    if *nb_retries >= max_retries {
+        if max_retries == 0 {
+            return Err(error);
+        }
+        return Err(anyhow!(
+            "{}, gave up after {:?} retries",
+            error,
+            max_retries
+        ));
+    }
+    *nb_retries += 1;
+
+    // Wait longer after each retry
+    let delay = RETRY_DELAY
+        .saturating_mul(2u32.saturating_pow(*nb_retries - 1))
+        .min(RETRY_DELAY_MAX);
+    warn!(
+        "Download failed: {}, retry {:?} of {:?} in {:?}",
+        error, nb_retries, max_retries, delay
+    );
+    thread::sleep(delay);
+
+    Ok(())
+}
+
+/// Print the number of peers announced by trackers, then each peer as `ip:port`.
+///
+/// # Arguments
+///
+/// * `torrent` - The opened torrent.
+/// * `torrent_path` - The path to the torrent.
+///
+fn print_peers(torrent: &Torrent, torrent_path: &str) {
+    println!(
+        "Trackers announced {:?} peers for {:?}:",
+        torrent.peers().len(),
+        torrent_path
+    );
+    for peer in torrent.peers() {
+        if peer.is_seed() {
+            println!("{} (seeder)", peer);
+        } else {
+            println!("{}", peer);
+        }
+    }
+}
+
+/// Print the info hash of a torrent computed from its decoded keys, used for download, and from its raw bytes.
+///
+/// # Arguments
+///
+/// * `torrent_path` - The path to the torrent.
+///
+fn print_info_hashes(torrent_path: &str) -> Result<()> {
+    let buf = match std::fs::read(torrent_path) {
+        Ok(buf) => buf,
+        Err(_) => return Err(anyhow!("could not read torrent {:?}", torrent_path)),
+    };
+    let hashes = compute_info_hashes(&buf)?;
+
+    println!("Info hashes of {:?}:", torrent_path);
+    println!(
+        "  from decoded info keys, used for download: {}",
+        hex::encode(&hashes.serialized)
+    );
+    match &hashes.raw {
+        Some(raw) => {
+            println!(
+                "  from raw info bytes of torrent:            {}",
+                hex::encode(raw)
+            );
+            if *raw == hashes.serialized {
+                println!("Both hashes match.");
+            } else {
+                println!("Hashes differ, torrent info is not encoded canonically or has keys that are not decoded.");
+            }
+        }
+        None => println!("  from raw info bytes of torrent:            could not find info bytes"),
+    }
+
+    Ok(())
+}
 
+/// Report seeders and leechers scraped from trackers, warning if there is no seeder.
+///
+/// # Arguments
+///
+/// * `torrent` - The opened torrent.
+/// * `strict` - Fail instead of warning when there is no seeder.
+/// * `quiet` - Only print errors.
+///
+fn check_seeders(torrent: &Torrent, strict: bool, quiet: bool) -> Result<()> {
+    let scrape = match torrent.scrape() {
+        Ok(scrape) => scrape,
+        Err(e) if strict => return Err(anyhow!("could not scrape trackers: {}", e)),
+        Err(e) => {
+            warn!("Could not scrape trackers: {}", e);
+            return Ok(());
+        }
+    };
+
+    if !quiet {
+        println!(
+            "Trackers report {:?} seeders and {:?} leechers.",
+            scrape.complete, scrape.incomplete
+        );
+    }
+
+    if scrape.complete == 0 {
+        if strict {
+            return Err(anyhow!(
+                "trackers report no seeder, download can not complete"
+            ));
+        }
+        warn!("Trackers report no seeder, download will likely not complete");
+    }
+
+    Ok(())
+}
+
+/// Report how many of a sample of peers are reachable, warning if none is.
+///
+/// # Arguments
+///
+/// * `torrent` - The opened torrent.
+/// * `quiet` - Only print errors.
+///
+fn check_peers(torrent: &Torrent, quiet: bool) {
+    let check = torrent.check_peers();
+
+    if !quiet {
+        println!(
+            "Reached {:?} of {:?} sampled peers.",
+            check.reachable, check.sampled
+        );
+    }
+
+    if check.reachable == 0 {
+        warn!("Could not reach any sampled peer, download will likely be slow to start");
+    }
+}
+
+/// Run a command once download is complete.
+///
+/// # Arguments
+///
+/// * `command` - The command to run.
+/// * `file` - The path where the torrent is saved, given as argument to the command.
+/// * `quiet` - Only print errors.
+///
+fn run_on_complete(command: &str, file: &str, quiet: bool) -> Result<()> {
+    let status = match Command::new(command).arg(file).status() {
+        Ok(status) => status,
+        Err(_) => return Err(anyhow!("could not run completion command {:?}", command)),
+    };
+
+    if !status.success() {
+        return Err(anyhow!(
+            "completion command {:?} failed ({}), data is kept in {:?}",
+            command,
+            status,
+            file
+        ));
+    }
+
+    if !quiet {
+        println!("Completion command {:?} succeeded ({}).", command, status);
+    }
+
+    Ok(())
+}
+
+/// Initialize logger.
+///
+/// The log level is set from verbosity arguments, unless `RUST_LOG` is explicitly set.
+///
+/// # Arguments
+///
+/// * `args` - The parsed arguments.
+///
+fn init_logger(args: &clap::ArgMatches) {
+    // Get log level from verbosity arguments
+    let level = if args.is_present("quiet") {
+        LevelFilter::Error
+    } else {
+        match args.occurrences_of("verbose") {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    };
+
+    let mut builder = pretty_env_logger::formatted_timed_builder();
+    builder.filter_level(level);
+
+    // Let RUST_LOG override log level
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+
+    builder.init();
+}
+
+fn main() {
     // Parse arguments
     let args = parse_args();
 
+    // Initialize logger
+    init_logger(&args);
+
     // Run program, eventually exit failure
     if let Err(error) = run(args) {
+        // Exit quietly when the program reading stdout stopped early, as `head` does
+        if error.is::<StdoutClosed>() {
+            std::process::exit(0);
+        }
         eprintln!("Error: {}", error);
         std::process::exit(1);
     }
@@ -18,89 +18,325 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use anyhow::Result;
-use byteorder::{BigEndian, WriteBytesExt};
+use anyhow::{anyhow, Result};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
-type MessageId = u8;
-type MessagePayload = Vec<u8>;
+use std::io::Cursor;
+
+pub type MessageId = u8;
 
 pub const MESSAGE_CHOKE: MessageId = 0;
 pub const MESSAGE_UNCHOKE: MessageId = 1;
 pub const MESSAGE_INTERESTED: MessageId = 2;
+pub const MESSAGE_NOT_INTERESTED: MessageId = 3;
 pub const MESSAGE_HAVE: MessageId = 4;
 pub const MESSAGE_BITFIELD: MessageId = 5;
 pub const MESSAGE_REQUEST: MessageId = 6;
 pub const MESSAGE_PIECE: MessageId = 7;
+pub const MESSAGE_HAVE_ALL: MessageId = 14;
+pub const MESSAGE_HAVE_NONE: MessageId = 15;
+pub const MESSAGE_REJECT_REQUEST: MessageId = 16;
+pub const MESSAGE_ALLOWED_FAST: MessageId = 17;
 
-#[derive(Default, Debug)]
-pub struct Message {
-    // Message type
-    pub id: MessageId,
-    // Message payload
-    pub payload: MessagePayload,
+/// Message exchanged with a peer, with its payload parsed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    // Message of length 0 keeping connection open, without id nor payload
+    KeepAlive,
+    // Peer does not serve requests anymore
+    Choke,
+    // Peer serves requests
+    Unchoke,
+    // Peer wants to download pieces
+    Interested,
+    // Peer does not want to download pieces anymore
+    NotInterested,
+    // Peer has a piece, given by its index
+    Have(u32),
+    // Pieces that peer has, one bit per piece
+    Bitfield(Vec<u8>),
+    // Request for a block of a piece
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    // Block of a piece
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    // Peer has all pieces
+    HaveAll,
+    // Peer has no piece
+    HaveNone,
+    // Peer will not serve a requested block
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    // Piece that can be requested even while choked, given by its index
+    AllowedFast(u32),
+    // Message not supported by this client, given by its id
+    Unknown(MessageId),
 }
 
-impl Message {
-    /// Build a new message.
-    ///
-    /// # Arguments
-    ///
-    /// * `id` - The type of the message.
-    ///
-    pub fn new(id: MessageId) -> Self {
-        Message {
-            id,
-            payload: vec![],
-        }
+impl MessageKind {
+    /// Get the id of the message, or nothing for a keep-alive.
+    pub fn id(&self) -> Option<MessageId> {
+        let id = match self {
+            MessageKind::KeepAlive => return None,
+            MessageKind::Choke => MESSAGE_CHOKE,
+            MessageKind::Unchoke => MESSAGE_UNCHOKE,
+            MessageKind::Interested => MESSAGE_INTERESTED,
+            MessageKind::NotInterested => MESSAGE_NOT_INTERESTED,
+            MessageKind::Have(_) => MESSAGE_HAVE,
+            MessageKind::Bitfield(_) => MESSAGE_BITFIELD,
+            MessageKind::Request { .. } => MESSAGE_REQUEST,
+            MessageKind::Piece { .. } => MESSAGE_PIECE,
+            MessageKind::HaveAll => MESSAGE_HAVE_ALL,
+            MessageKind::HaveNone => MESSAGE_HAVE_NONE,
+            MessageKind::RejectRequest { .. } => MESSAGE_REJECT_REQUEST,
+            MessageKind::AllowedFast(_) => MESSAGE_ALLOWED_FAST,
+            MessageKind::Unknown(id) => *id,
+        };
+        Some(id)
     }
 
-    /// Build a new message with a payload.
-    ///
-    /// # Arguments
-    ///
-    /// * `id` - The type of the message.
-    /// * `payload` - The content of the message.
-    ///
-    pub fn new_with_payload(id: MessageId, payload: MessagePayload) -> Self {
-        Message { id, payload }
+    /// Get the name of the message, as written in logs.
+    pub fn name(&self) -> &'static str {
+        match self.id() {
+            Some(id) => message_name(id),
+            None => "KEEP_ALIVE",
+        }
     }
 
-    /// Serialize message.
+    /// Serialize message, prefixed by its length.
     pub fn serialize(&self) -> Result<Vec<u8>> {
-        // Get message length
-        let message_len = 1 + self.payload.len();
+        // A keep-alive is only its length
+        let id = match self.id() {
+            Some(id) => id,
+            None => return Ok(vec![0; 4]),
+        };
+
+        // Build message payload
+        let mut payload: Vec<u8> = vec![];
+        match self {
+            MessageKind::Have(index) | MessageKind::AllowedFast(index) => {
+                payload.write_u32::<BigEndian>(*index)?;
+            }
+            MessageKind::Bitfield(bitfield) => payload.extend_from_slice(bitfield),
+            MessageKind::Request {
+                index,
+                begin,
+                length,
+            }
+            | MessageKind::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                payload.write_u32::<BigEndian>(*index)?;
+                payload.write_u32::<BigEndian>(*begin)?;
+                payload.write_u32::<BigEndian>(*length)?;
+            }
+            MessageKind::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                payload.write_u32::<BigEndian>(*index)?;
+                payload.write_u32::<BigEndian>(*begin)?;
+                payload.extend_from_slice(block);
+            }
+            MessageKind::Unknown(_) => {
+                return Err(anyhow!("could not serialize unknown message"));
+            }
+            _ => {}
+        }
 
         // Create a new buffer
-        let mut serialized: Vec<u8> = vec![];
+        let mut serialized: Vec<u8> = Vec::with_capacity(5 + payload.len());
 
         // Add message length
-        serialized.write_u32::<BigEndian>(message_len as u32)?;
+        serialized.write_u32::<BigEndian>(1 + payload.len() as u32)?;
 
         // Add message id
-        serialized.push(self.id);
+        serialized.push(id);
 
         // Add message payload
-        let mut payload = self.payload.clone();
         serialized.append(&mut payload);
 
         Ok(serialized)
     }
 }
 
-/// Deserialize message.
+/// Get the name of a message from its id, as written in logs.
+///
+/// # Arguments
+///
+/// * `id` - The type of the message.
+///
+pub fn message_name(id: MessageId) -> &'static str {
+    match id {
+        MESSAGE_CHOKE => "MESSAGE_CHOKE",
+        MESSAGE_UNCHOKE => "MESSAGE_UNCHOKE",
+        MESSAGE_INTERESTED => "MESSAGE_INTERESTED",
+        MESSAGE_NOT_INTERESTED => "MESSAGE_NOT_INTERESTED",
+        MESSAGE_HAVE => "MESSAGE_HAVE",
+        MESSAGE_BITFIELD => "MESSAGE_BITFIELD",
+        MESSAGE_REQUEST => "MESSAGE_REQUEST",
+        MESSAGE_PIECE => "MESSAGE_PIECE",
+        MESSAGE_HAVE_ALL => "MESSAGE_HAVE_ALL",
+        MESSAGE_HAVE_NONE => "MESSAGE_HAVE_NONE",
+        MESSAGE_REJECT_REQUEST => "MESSAGE_REJECT_REQUEST",
+        MESSAGE_ALLOWED_FAST => "MESSAGE_ALLOWED_FAST",
+        _ => "unknown message",
+    }
+}
+
+/// Deserialize message, checking that its payload has the size expected for its type.
+///
+/// # Arguments
+///
+/// * `message_buf` - The message to deserialize, without its length prefix.
+///
+pub fn deserialize_message(message_buf: &[u8]) -> Result<MessageKind> {
+    deserialize_message_into(message_buf, vec![])
+}
+
+/// Deserialize message, copying the block of a piece into a buffer reused from a previous block.
 ///
 /// # Arguments
 ///
-/// * `message_buf` - The message to deserialize.
-/// * `message_len` - The message length.
+/// * `message_buf` - The message to deserialize, without its length prefix.
+/// * `block_buf` - The buffer holding the block of a MESSAGE_PIECE, cleared before use.
 ///
-pub fn deserialize_message(message_buf: &[u8], message_len: usize) -> Result<Message> {
-    // Get message id
-    let id: MessageId = message_buf[0];
-    // Get message payload
-    let payload: MessagePayload = message_buf[1..message_len].to_vec();
-    // Build message
-    let message: Message = Message::new_with_payload(id, payload);
+pub fn deserialize_message_into(message_buf: &[u8], mut block_buf: Vec<u8>) -> Result<MessageKind> {
+    // Get message id and payload
+    let (id, payload) = match message_buf.split_first() {
+        Some((id, payload)) => (*id, payload),
+        None => return Err(anyhow!("received empty message from peer")),
+    };
+
+    // Check payload size of the message type
+    let valid = match id {
+        MESSAGE_CHOKE
+        | MESSAGE_UNCHOKE
+        | MESSAGE_INTERESTED
+        | MESSAGE_NOT_INTERESTED
+        | MESSAGE_HAVE_ALL
+        | MESSAGE_HAVE_NONE => payload.is_empty(),
+        MESSAGE_HAVE | MESSAGE_ALLOWED_FAST => payload.len() == 4,
+        MESSAGE_REQUEST | MESSAGE_REJECT_REQUEST => payload.len() == 12,
+        // Piece index and byte offset, followed by a non-empty block
+        MESSAGE_PIECE => payload.len() > 8,
+        _ => true,
+    };
+    if !valid {
+        return Err(anyhow!("received invalid {} from peer", message_name(id)));
+    }
+
+    // Parse message payload
+    let mut payload_cursor = Cursor::new(payload);
+    let message = match id {
+        MESSAGE_CHOKE => MessageKind::Choke,
+        MESSAGE_UNCHOKE => MessageKind::Unchoke,
+        MESSAGE_INTERESTED => MessageKind::Interested,
+        MESSAGE_NOT_INTERESTED => MessageKind::NotInterested,
+        MESSAGE_HAVE => MessageKind::Have(payload_cursor.read_u32::<BigEndian>()?),
+        MESSAGE_BITFIELD => MessageKind::Bitfield(payload.to_vec()),
+        MESSAGE_REQUEST => MessageKind::Request {
+            index: payload_cursor.read_u32::<BigEndian>()?,
+            begin: payload_cursor.read_u32::<BigEndian>()?,
+            length: payload_cursor.read_u32::<BigEndian>()?,
+        },
+        MESSAGE_PIECE => MessageKind::Piece {
+            index: payload_cursor.read_u32::<BigEndian>()?,
+            begin: payload_cursor.read_u32::<BigEndian>()?,
+            block: {
+                block_buf.clear();
+                block_buf.extend_from_slice(&payload[8..]);
+                block_buf
+            },
+        },
+        MESSAGE_HAVE_ALL => MessageKind::HaveAll,
+        MESSAGE_HAVE_NONE => MessageKind::HaveNone,
+        MESSAGE_REJECT_REQUEST => MessageKind::RejectRequest {
+            index: payload_cursor.read_u32::<BigEndian>()?,
+            begin: payload_cursor.read_u32::<BigEndian>()?,
+            length: payload_cursor.read_u32::<BigEndian>()?,
+        },
+        MESSAGE_ALLOWED_FAST => MessageKind::AllowedFast(payload_cursor.read_u32::<BigEndian>()?),
+        id => MessageKind::Unknown(id),
+    };
 
     Ok(message)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_and_deserialize_messages() {
+        let messages = vec![
+            MessageKind::Choke,
+            MessageKind::Unchoke,
+            MessageKind::Interested,
+            MessageKind::NotInterested,
+            MessageKind::Have(3),
+            MessageKind::Bitfield(vec![0b1010_0000]),
+            MessageKind::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            MessageKind::Piece {
+                index: 1,
+                begin: 16384,
+                block: vec![0xAB; 32],
+            },
+            MessageKind::HaveAll,
+            MessageKind::HaveNone,
+            MessageKind::RejectRequest {
+                index: 2,
+                begin: 0,
+                length: 1024,
+            },
+            MessageKind::AllowedFast(7),
+        ];
+        for message in messages {
+            let serialized = message.serialize().unwrap();
+            assert_eq!(serialized.len(), 4 + serialized[3] as usize);
+            assert_eq!(deserialize_message(&serialized[4..]).unwrap(), message);
+        }
+
+        // Keep id of messages not supported, such as PORT
+        assert_eq!(
+            deserialize_message(&[9, 0x1A, 0xE1]).unwrap(),
+            MessageKind::Unknown(9)
+        );
+    }
+
+    #[test]
+    fn reject_invalid_payload_sizes() {
+        assert!(deserialize_message(&[]).is_err());
+        assert!(deserialize_message(&[MESSAGE_CHOKE, 0]).is_err());
+        assert!(deserialize_message(&[MESSAGE_HAVE, 0, 0, 3]).is_err());
+        assert!(deserialize_message(&[MESSAGE_ALLOWED_FAST, 0, 0, 0, 0, 7]).is_err());
+        assert!(deserialize_message(&[MESSAGE_REQUEST, 0, 0, 0, 1, 0, 0, 0, 0]).is_err());
+        assert!(deserialize_message(&[MESSAGE_REJECT_REQUEST; 14]).is_err());
+        assert!(deserialize_message(&[MESSAGE_PIECE, 0, 0, 0, 1, 0, 0, 0]).is_err());
+
+        // Reject a PIECE message holding an empty block, naming the message type
+        let error = deserialize_message(&[MESSAGE_PIECE, 0, 0, 0, 1, 0, 0, 0, 0]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "received invalid MESSAGE_PIECE from peer"
+        );
+        assert!(deserialize_message(&[MESSAGE_PIECE, 0, 0, 0, 1, 0, 0, 0, 0, 0xAB]).is_ok());
+    }
+}
@@ -23,10 +23,12 @@ use crate::torrent::*;
 use anyhow::{anyhow, Result};
 use byteorder::{BigEndian, ReadBytesExt};
 
+use std::fmt;
 use std::io::Cursor;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 const PEER_SIZE: usize = 6;
+const PEER6_SIZE: usize = 18;
 
 type PeerId = u32;
 
@@ -34,8 +36,10 @@ type PeerId = u32;
 #[derive(Clone)]
 pub struct Peer {
     pub id: PeerId,
-    pub ip: Ipv4Addr,
+    pub ip: IpAddr,
     pub port: u16,
+    // Whether tracker reported peer has the whole torrent, unknown for most trackers
+    pub seed: Option<bool>,
 }
 
 impl Peer {
@@ -43,53 +47,187 @@ impl Peer {
     pub fn new() -> Peer {
         Peer {
             id: 0,
-            ip: Ipv4Addr::new(1, 1, 1, 1),
+            ip: IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
             port: 0,
+            seed: None,
+        }
+    }
+
+    /// Get the socket address of the peer.
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+
+    /// Check if tracker reported peer has the whole torrent.
+    pub fn is_seed(&self) -> bool {
+        self.seed == Some(true)
+    }
+
+    /// Check if peer is on the local network, or on this machine.
+    ///
+    /// Local addresses are RFC 1918 private IPv4 ranges, link-local and unique local addresses,
+    /// and loopback addresses.
+    ///
+    pub fn is_local(&self) -> bool {
+        match self.ip {
+            IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+                None => ip.is_unique_local() || ip.is_unicast_link_local() || ip.is_loopback(),
+            },
         }
     }
 }
 
+impl fmt::Display for Peer {
+    /// Format peer as `ip:port`, with brackets around an IPv6 address.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.addr())
+    }
+}
+
+impl fmt::Debug for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Peer")
+            .field("id", &self.id)
+            .field("addr", &self.addr())
+            .field("seed", &self.seed)
+            .finish()
+    }
+}
+
+impl Default for Peer {
+    fn default() -> Self {
+        Peer::new()
+    }
+}
+
 impl Torrent {
     /// Build peers.
     ///
     /// # Arguments
     ///
     /// * `tracker_peers` - A string consisting of multiples of 6 bytes.
-    /// First 4 bytes are the IP address and last 2 bytes are the port number.
-    /// All in network (big endian) notation.
+    ///   First 4 bytes are the IP address and last 2 bytes are the port number.
+    ///   All in network (big endian) notation.
+    /// * `tracker_peers6` - A string consisting of multiples of 18 bytes.
+    ///   First 16 bytes are the IPv6 address and last 2 bytes are the port number.
+    ///   All in network (big endian) notation.
     ///
-    pub fn build_peers(&self, tracker_peers: Vec<u8>) -> Result<Vec<Peer>> {
+    pub fn build_peers(
+        &self,
+        tracker_peers: Vec<u8>,
+        tracker_peers6: Vec<u8>,
+    ) -> Result<Vec<Peer>> {
         // Check tracker peers are valid
-        if tracker_peers.len() % PEER_SIZE != 0 {
+        if !tracker_peers.len().is_multiple_of(PEER_SIZE)
+            || !tracker_peers6.len().is_multiple_of(PEER6_SIZE)
+        {
             return Err(anyhow!("received invalid peers from tracker"));
         }
 
         // Get number of peers
-        let nb_peers = tracker_peers.len() / PEER_SIZE;
+        let nb_peers = tracker_peers.len() / PEER_SIZE + tracker_peers6.len() / PEER6_SIZE;
 
         // Build peers
-        let mut peers: Vec<Peer> = vec![Peer::new(); nb_peers];
-
-        for (i, peer) in peers.iter_mut().enumerate().take(nb_peers) {
-            // Create peer ID
-            peer.id = i as u32;
-
-            let offset = i * PEER_SIZE;
+        let mut peers: Vec<Peer> = vec![];
+        let mut nb_invalid_ips = 0;
+        let mut nb_invalid_ports = 0;
 
-            // Read peer IP address
-            peer.ip = Ipv4Addr::new(
-                tracker_peers[offset],
-                tracker_peers[offset + 1],
-                tracker_peers[offset + 2],
-                tracker_peers[offset + 3],
-            );
+        let entries = tracker_peers
+            .chunks_exact(PEER_SIZE)
+            .chain(tracker_peers6.chunks_exact(PEER6_SIZE));
+        for entry in entries {
+            // Read peer IP address, from its family size
+            let ip_size = entry.len() - 2;
+            let ip = if ip_size == 4 {
+                IpAddr::V4(Ipv4Addr::new(entry[0], entry[1], entry[2], entry[3]))
+            } else {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&entry[0..16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
 
             // Read peer port
-            let port_bytes = &tracker_peers[offset + 4..offset + 6];
+            let port_bytes = &entry[ip_size..ip_size + 2];
             let mut port_cursor = Cursor::new(port_bytes);
-            peer.port = port_cursor.read_u16::<BigEndian>()?;
+            let port = port_cursor.read_u16::<BigEndian>()?;
+
+            // Discard peers that can not be connected to
+            let is_broadcast = matches!(ip, IpAddr::V4(ip) if ip.is_broadcast());
+            if ip.is_unspecified() || is_broadcast || ip.is_multicast() {
+                debug!("Discard peer with invalid IP address {:?}", ip);
+                nb_invalid_ips += 1;
+                continue;
+            }
+            if port == 0 {
+                debug!("Discard peer {:?} with invalid port 0", ip);
+                nb_invalid_ports += 1;
+                continue;
+            }
+
+            // Create peer ID
+            let mut peer = Peer::new();
+            peer.id = peers.len() as u32;
+            peer.ip = ip;
+            peer.port = port;
+            peers.push(peer);
         }
 
+        debug!(
+            "Discarded {:?} of {:?} peers ({:?} with invalid IP address, {:?} with invalid port)",
+            nb_invalid_ips + nb_invalid_ports,
+            nb_peers,
+            nb_invalid_ips,
+            nb_invalid_ports
+        );
+        debug!(
+            "Found {:?} peers on the local network",
+            peers.iter().filter(|peer| peer.is_local()).count()
+        );
+
         Ok(peers)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_local_peers() {
+        let is_local = |ip: &str| {
+            let mut peer = Peer::new();
+            peer.ip = ip.parse().unwrap();
+            peer.is_local()
+        };
+
+        // RFC 1918 ranges, link-local and loopback addresses
+        for ip in [
+            "10.0.0.1",
+            "172.16.0.1",
+            "172.31.255.254",
+            "192.168.1.10",
+            "169.254.3.4",
+            "127.0.0.1",
+            "fd12:3456::1",
+            "fe80::1",
+            "::1",
+            "::ffff:192.168.1.10",
+        ] {
+            assert!(is_local(ip), "{} should be local", ip);
+        }
+
+        // Public addresses, including those next to private ranges
+        for ip in [
+            "8.8.8.8",
+            "172.15.255.255",
+            "172.32.0.1",
+            "192.169.0.1",
+            "2001:db8::1",
+            "::ffff:8.8.8.8",
+        ] {
+            assert!(!is_local(ip), "{} should not be local", ip);
+        }
+    }
+}
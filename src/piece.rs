@@ -18,6 +18,14 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+use crate::progress::*;
+use crate::worker::Slot;
+
+use anyhow::{anyhow, Result};
+use crossbeam_channel::Sender;
+
+use std::time::Instant;
+
 /// PieceWork structure.
 #[derive(Default, Debug, Clone)]
 pub struct PieceWork {
@@ -27,7 +35,7 @@ pub struct PieceWork {
     pub hash: Vec<u8>,
     // Piece length
     pub length: u32,
-    // Piece data
+    // Piece data, allocated only while the piece is in flight
     pub data: Vec<u8>,
     // Requests number sent
     pub requests: u32,
@@ -35,10 +43,20 @@ pub struct PieceWork {
     pub requested: u32,
     // Size of downloaded data in bytes
     pub downloaded: u32,
+    // Blocks rejected by peer to request again, as (begin, length)
+    pub rejected: Vec<(u32, u32)>,
+    // Number of requests rejected by peer
+    pub rejects: u32,
+    // Peers that sent corrupted data for this piece
+    pub failed_peers: Vec<u32>,
+    // Peers that kept rejecting requests for this piece
+    pub refused_peers: Vec<u32>,
+    // Time the piece was taken by a peer, while in flight
+    pub started: Option<Instant>,
 }
 
 /// PieceResult structure.
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug)]
 pub struct PieceResult {
     // Piece index
     pub index: u32,
@@ -46,6 +64,8 @@ pub struct PieceResult {
     pub length: u32,
     // Piece data
     pub data: Vec<u8>,
+    // In-flight piece slot, given back once piece data is written or discarded
+    pub(crate) _slot: Option<Slot>,
 }
 
 impl PieceWork {
@@ -62,10 +82,36 @@ impl PieceWork {
             index,
             hash,
             length,
-            data: vec![0; length as usize],
+            data: vec![],
             requests: 0,
             requested: 0,
             downloaded: 0,
+            rejected: vec![],
+            rejects: 0,
+            failed_peers: vec![],
+            refused_peers: vec![],
+            started: None,
+        }
+    }
+
+    /// Free piece data, and resend piece to work channel as pending.
+    ///
+    /// # Arguments
+    ///
+    /// * `work_chan` - The channel to resend the piece to.
+    /// * `progress` - The state and availability of pieces.
+    ///
+    pub(crate) fn resend(
+        mut self,
+        work_chan: &Sender<PieceWork>,
+        progress: &Progress,
+    ) -> Result<()> {
+        self.data = vec![];
+        self.started = None;
+        progress.set_state(self.index, PieceState::Pending);
+        match work_chan.send(self) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(anyhow!("could not send piece to channel")),
         }
     }
 }
@@ -78,12 +124,43 @@ impl PieceResult {
     /// * `index` - The piece index.
     /// * `length` - The piece length.
     /// * `data` - The piece data.
+    /// * `slot` - The in-flight piece slot held by the piece data.
     ///
-    pub fn new(index: u32, length: u32, data: Vec<u8>) -> PieceResult {
+    pub(crate) fn new(index: u32, length: u32, data: Vec<u8>, slot: Option<Slot>) -> PieceResult {
         PieceResult {
             index,
             length,
             data,
+            _slot: slot,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crossbeam_channel::unbounded;
+
+    #[test]
+    fn resend_piece_without_data() {
+        let (work_chan, work_recv) = unbounded();
+        let progress = Progress::new();
+        progress.reset(1);
+        let mut piece_work = PieceWork::new(0, vec![], 4);
+        piece_work.data = vec![1; 4];
+        piece_work.started = Some(Instant::now());
+        progress.set_state(0, PieceState::InFlight);
+
+        // Piece is sent back pending, with its data freed
+        piece_work.resend(&work_chan, &progress).unwrap();
+        let piece_work = work_recv.try_recv().unwrap();
+        assert!(piece_work.data.is_empty());
+        assert!(piece_work.started.is_none());
+        assert_eq!(progress.snapshot().unwrap().states[0], PieceState::Pending);
+
+        // A closed work channel is reported
+        drop(work_recv);
+        assert!(piece_work.resend(&work_chan, &progress).is_err());
+    }
+}
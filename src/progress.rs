@@ -0,0 +1,174 @@
+// Copyright (c) 2020 zenoxygen
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::bitfield::*;
+
+use anyhow::{anyhow, Result};
+
+use std::sync::{Arc, Mutex};
+
+/// PieceState enum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceState {
+    // Piece is waiting to be downloaded
+    Pending,
+    // Piece is being downloaded from a peer
+    InFlight,
+    // Piece is saved to disk
+    Complete,
+}
+
+/// Snapshot structure, giving state and availability of pieces, indexed by piece index.
+#[derive(Default, Debug, Clone)]
+pub struct Snapshot {
+    // State of each piece
+    pub states: Vec<PieceState>,
+    // Number of connected peers having each piece
+    pub availability: Vec<u32>,
+    // Index from which pieces are held back, so pieces written in order are not buffered too far
+    pub window_end: Option<u32>,
+}
+
+/// Progress structure, a handle shared with workers to follow pieces during download.
+#[derive(Default, Debug, Clone)]
+pub struct Progress {
+    // State and availability of pieces
+    snapshot: Arc<Mutex<Snapshot>>,
+}
+
+impl Progress {
+    /// Build a new progress handle.
+    pub fn new() -> Progress {
+        Progress::default()
+    }
+
+    /// Reset progress to pending pieces, available from no peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `nb_pieces` - The number of pieces in torrent.
+    ///
+    pub fn reset(&self, nb_pieces: u32) {
+        if let Ok(mut snapshot) = self.snapshot.lock() {
+            snapshot.states = vec![PieceState::Pending; nb_pieces as usize];
+            snapshot.availability = vec![0; nb_pieces as usize];
+            snapshot.window_end = None;
+        }
+    }
+
+    /// Set the index from which pieces are held back, or let any piece be downloaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `window_end` - The index of the first piece held back.
+    ///
+    pub fn set_window_end(&self, window_end: Option<u32>) {
+        if let Ok(mut snapshot) = self.snapshot.lock() {
+            snapshot.window_end = window_end;
+        }
+    }
+
+    /// Check if a piece can be downloaded now, rather than held back.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The piece index.
+    ///
+    pub fn in_window(&self, index: u32) -> bool {
+        match self.snapshot.lock() {
+            Ok(snapshot) => snapshot
+                .window_end
+                .is_none_or(|window_end| index < window_end),
+            Err(_) => true,
+        }
+    }
+
+    /// Get a copy of the state and availability of pieces.
+    ///
+    /// The lock is only held while copying, so workers are not blocked for long.
+    ///
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        match self.snapshot.lock() {
+            Ok(snapshot) => Ok(snapshot.clone()),
+            Err(_) => Err(anyhow!("could not read progress")),
+        }
+    }
+
+    /// Set the state of a piece.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The piece index.
+    /// * `state` - The new state of the piece.
+    ///
+    pub fn set_state(&self, index: u32, state: PieceState) {
+        if let Ok(mut snapshot) = self.snapshot.lock() {
+            if let Some(piece_state) = snapshot.states.get_mut(index as usize) {
+                *piece_state = state;
+            }
+        }
+    }
+
+    /// Count pieces of a newly connected peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `bitfield` - The bitfield of the peer.
+    ///
+    pub fn add_peer(&self, bitfield: &Bitfield) {
+        if let Ok(mut snapshot) = self.snapshot.lock() {
+            for (index, count) in snapshot.availability.iter_mut().enumerate() {
+                if bitfield.has_piece(index as u32) {
+                    *count += 1;
+                }
+            }
+        }
+    }
+
+    /// Forget pieces of a disconnected peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `bitfield` - The bitfield of the peer.
+    ///
+    pub fn remove_peer(&self, bitfield: &Bitfield) {
+        if let Ok(mut snapshot) = self.snapshot.lock() {
+            for (index, count) in snapshot.availability.iter_mut().enumerate() {
+                if bitfield.has_piece(index as u32) {
+                    *count = count.saturating_sub(1);
+                }
+            }
+        }
+    }
+
+    /// Count a piece that a connected peer just announced.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The piece index.
+    ///
+    pub fn add_piece(&self, index: u32) {
+        if let Ok(mut snapshot) = self.snapshot.lock() {
+            if let Some(count) = snapshot.availability.get_mut(index as usize) {
+                *count += 1;
+            }
+        }
+    }
+}
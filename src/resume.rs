@@ -0,0 +1,245 @@
+// Copyright (c) 2020 zenoxygen
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::bitfield::*;
+use crate::hash::*;
+use crate::storage::*;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_bencode::{de, ser};
+use serde_bytes::ByteBuf;
+
+use std::ffi::OsString;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// BencodeResume structure, pieces verified by a previous download of a torrent.
+#[derive(Debug, Deserialize, Serialize)]
+struct BencodeResume {
+    // 20-byte SHA-1 hash of the info key of the torrent
+    #[serde(rename = "info hash")]
+    info_hash: ByteBuf,
+    // Pieces saved to output files and verified
+    #[serde(rename = "bitfield")]
+    bitfield: ByteBuf,
+    // SHA-1 hash of the info hash followed by the bitfield, to detect a corrupted file
+    #[serde(rename = "checksum")]
+    checksum: ByteBuf,
+}
+
+/// Get the path of the resume state of a torrent, next to its output path.
+///
+/// # Arguments
+///
+/// * `output` - The path where the torrent is saved.
+///
+pub fn resume_path(output: &Path) -> PathBuf {
+    let mut path: OsString = output.as_os_str().to_owned();
+    path.push(".resume");
+    PathBuf::from(path)
+}
+
+/// Save the pieces verified so far, replacing the previous resume state at once.
+///
+/// # Arguments
+///
+/// * `path` - The path of the resume state.
+/// * `info_hash` - 20-byte SHA-1 hash of the info key of the torrent.
+/// * `bitfield` - The pieces saved to output files and verified.
+///
+pub fn save_resume_state(path: &Path, info_hash: &[u8], bitfield: &Bitfield) -> Result<()> {
+    let resume = BencodeResume {
+        info_hash: ByteBuf::from(info_hash.to_vec()),
+        bitfield: ByteBuf::from(bitfield.as_bytes().to_vec()),
+        checksum: ByteBuf::from(checksum(info_hash, bitfield.as_bytes())?),
+    };
+    let buf = match ser::to_bytes(&resume) {
+        Ok(buf) => buf,
+        Err(_) => return Err(anyhow!("could not encode resume state")),
+    };
+
+    // Write a temporary file renamed over the previous one, never leaving a partial file
+    let mut tmp_path: OsString = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    if fs::write(&tmp_path, buf).is_err() {
+        return Err(anyhow!("could not write resume state {:?}", tmp_path));
+    }
+    if fs::rename(&tmp_path, path).is_err() {
+        return Err(anyhow!("could not write resume state {:?}", path));
+    }
+
+    Ok(())
+}
+
+/// Load the pieces verified by a previous download, if they can be trusted.
+///
+/// The resume state is rejected if it is corrupted, belongs to another torrent, or is older than
+/// one of the output files, which may have been modified since.
+///
+/// # Arguments
+///
+/// * `path` - The path of the resume state.
+/// * `info_hash` - 20-byte SHA-1 hash of the info key of the torrent.
+/// * `nb_pieces` - The number of pieces in torrent.
+/// * `output` - The path where the torrent is saved.
+/// * `files` - The files of the torrent.
+///
+pub fn load_resume_state(
+    path: &Path,
+    info_hash: &[u8],
+    nb_pieces: u32,
+    output: &Path,
+    files: &[TorrentFile],
+) -> Result<Bitfield> {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(_) => return Err(anyhow!("could not read resume state {:?}", path)),
+    };
+    let resume = match de::from_bytes::<BencodeResume>(&buf) {
+        Ok(resume) => resume,
+        Err(_) => return Err(anyhow!("could not decode resume state {:?}", path)),
+    };
+
+    // Check resume state is intact and belongs to the torrent
+    if !hashes_match(
+        &resume.checksum,
+        &checksum(&resume.info_hash, &resume.bitfield)?,
+    ) {
+        return Err(anyhow!("resume state {:?} is corrupted", path));
+    }
+    if !hashes_match(&resume.info_hash, info_hash) {
+        return Err(anyhow!(
+            "resume state {:?} belongs to another torrent",
+            path
+        ));
+    }
+    if resume.bitfield.len() != nb_pieces.div_ceil(8) as usize {
+        return Err(anyhow!(
+            "resume state {:?} has a wrong number of pieces",
+            path
+        ));
+    }
+
+    // Check output files were not modified after resume state was saved
+    let saved = match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+        Ok(saved) => saved,
+        Err(_) => return Err(anyhow!("could not read resume state {:?}", path)),
+    };
+    for file in files {
+        let filepath = file.filepath(output);
+        match fs::metadata(&filepath).and_then(|metadata| metadata.modified()) {
+            Ok(modified) if modified <= saved => {}
+            _ => {
+                return Err(anyhow!(
+                    "file {:?} changed since resume state was saved",
+                    filepath
+                ))
+            }
+        }
+    }
+
+    Ok(Bitfield::from_bytes(resume.bitfield.into_vec()))
+}
+
+/// Remove the resume state, once it is not needed anymore.
+///
+/// # Arguments
+///
+/// * `path` - The path of the resume state.
+///
+pub fn remove_resume_state(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(anyhow!("could not remove resume state {:?}", path))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Compute the checksum of a resume state.
+///
+/// # Arguments
+///
+/// * `info_hash` - 20-byte SHA-1 hash of the info key of the torrent.
+/// * `bitfield` - The bitfield bytes.
+///
+fn checksum(info_hash: &[u8], bitfield: &[u8]) -> Result<Vec<u8>> {
+    sha1(&[info_hash, bitfield].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn trust_only_intact_resume_state() {
+        let output =
+            std::env::temp_dir().join(format!("xerus-resume-state-{}", std::process::id()));
+        let files = vec![TorrentFile::new(PathBuf::new(), 4, 0)];
+        let path = resume_path(&output);
+        let info_hash = [0xAB; 20];
+        let load = || load_resume_state(&path, &info_hash, 10, &output, &files);
+
+        // Trust pieces saved after output files were written
+        create_files(&output, &files).unwrap();
+        let mut bitfield = Bitfield::new(10);
+        bitfield.set_piece(0);
+        bitfield.set_piece(9);
+        save_resume_state(&path, &info_hash, &bitfield).unwrap();
+        assert_eq!(load().unwrap().as_bytes(), bitfield.as_bytes());
+        assert!(load_resume_state(&path, &[0xCD; 20], 10, &output, &files).is_err());
+        assert!(load_resume_state(&path, &info_hash, 20, &output, &files).is_err());
+
+        // Reject a resume state with a flipped bit
+        let mut buf = fs::read(&path).unwrap();
+        let i = buf
+            .windows(2)
+            .position(|w| w == [0b1000_0000, 0b0100_0000])
+            .unwrap();
+        buf[i] = 0b1100_0000;
+        fs::write(&path, &buf).unwrap();
+        assert_eq!(
+            load().unwrap_err().to_string(),
+            format!("resume state {:?} is corrupted", path)
+        );
+
+        // Reject a resume state older than output files
+        save_resume_state(&path, &info_hash, &bitfield).unwrap();
+        let later = SystemTime::now() + Duration::from_secs(10);
+        File::options()
+            .write(true)
+            .open(&output)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(load().is_err());
+
+        // Forget a resume state twice
+        remove_resume_state(&path).unwrap();
+        remove_resume_state(&path).unwrap();
+        assert!(load().is_err());
+        fs::remove_file(&output).unwrap();
+    }
+}
@@ -0,0 +1,330 @@
+// Copyright (c) 2020 zenoxygen
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::message::*;
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Minimum delay between two samples of the download rate
+const RATE_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+// Time window of the moving average of the download rate
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+// Number of message ids counted, up to the last message supported by this client
+const MESSAGE_IDS: usize = MESSAGE_ALLOWED_FAST as usize + 1;
+
+/// Stats structure, a handle shared with workers to count transferred bytes during download.
+///
+/// Counters are atomics, so workers never wait for each other to update them.
+///
+#[derive(Default, Debug, Clone)]
+pub struct Stats {
+    counters: Arc<Counters>,
+}
+
+/// Counters structure, shared by handles to the same stats.
+#[derive(Default, Debug)]
+struct Counters {
+    // Bytes of blocks received from peers and web seeds
+    downloaded: AtomicU64,
+    // Bytes of blocks sent to peers
+    uploaded: AtomicU64,
+    // Bytes of verified pieces, saved to disk or discarded
+    saved: AtomicU64,
+    // Number of peers connected to
+    connected_peers: AtomicUsize,
+    // Number of messages sent to peers, by message id
+    messages_sent: [AtomicU64; MESSAGE_IDS],
+    // Number of messages received from peers, by message id
+    messages_received: [AtomicU64; MESSAGE_IDS],
+    // Download rate in bytes per second, smoothed over a time window
+    rate: AtomicU64,
+    // Time and downloaded bytes of the last rate sample, only locked when sampling
+    last_sample: Mutex<Option<(Instant, u64)>>,
+}
+
+impl Stats {
+    /// Build new stats, with every counter at zero.
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    /// Reset every counter to zero.
+    pub fn reset(&self) {
+        self.counters.downloaded.store(0, Ordering::Relaxed);
+        self.counters.uploaded.store(0, Ordering::Relaxed);
+        self.counters.saved.store(0, Ordering::Relaxed);
+        self.counters.connected_peers.store(0, Ordering::Relaxed);
+        self.counters.rate.store(0, Ordering::Relaxed);
+        for count in self
+            .counters
+            .messages_sent
+            .iter()
+            .chain(self.counters.messages_received.iter())
+        {
+            count.store(0, Ordering::Relaxed);
+        }
+        if let Ok(mut last_sample) = self.counters.last_sample.lock() {
+            *last_sample = None;
+        }
+    }
+
+    /// Count bytes received from a peer or a web seed.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The number of bytes received.
+    ///
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.counters.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count bytes sent to a peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The number of bytes sent.
+    ///
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.counters.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count bytes of a verified piece.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The length of the piece.
+    ///
+    pub fn add_saved(&self, bytes: u64) {
+        self.counters.saved.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count messages sent to a peer, ignoring messages not supported by this client.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The message id.
+    /// * `count` - The number of messages sent.
+    ///
+    pub fn add_messages_sent(&self, id: MessageId, count: u64) {
+        if let Some(sent) = self.counters.messages_sent.get(id as usize) {
+            sent.fetch_add(count, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a message received from a peer, ignoring messages not supported by this client.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The message id.
+    ///
+    pub fn add_message_received(&self, id: MessageId) {
+        if let Some(received) = self.counters.messages_received.get(id as usize) {
+            received.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a newly connected peer.
+    pub fn add_peer(&self) {
+        self.counters
+            .connected_peers
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Forget a disconnected peer.
+    pub fn remove_peer(&self) {
+        let _ = self.counters.connected_peers.fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |count| Some(count.saturating_sub(1)),
+        );
+    }
+
+    /// Get the number of bytes received from peers and web seeds.
+    pub fn downloaded(&self) -> u64 {
+        self.counters.downloaded.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of bytes sent to peers.
+    pub fn uploaded(&self) -> u64 {
+        self.counters.uploaded.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of bytes of verified pieces.
+    pub fn saved(&self) -> u64 {
+        self.counters.saved.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of peers connected to.
+    pub fn connected_peers(&self) -> usize {
+        self.counters.connected_peers.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of messages of a type sent to peers.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The message id.
+    ///
+    pub fn messages_sent(&self, id: MessageId) -> u64 {
+        match self.counters.messages_sent.get(id as usize) {
+            Some(sent) => sent.load(Ordering::Relaxed),
+            None => 0,
+        }
+    }
+
+    /// Get the number of messages of a type received from peers.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The message id.
+    ///
+    pub fn messages_received(&self, id: MessageId) -> u64 {
+        match self.counters.messages_received.get(id as usize) {
+            Some(received) => received.load(Ordering::Relaxed),
+            None => 0,
+        }
+    }
+
+    /// Get the download rate in bytes per second, smoothed over a few seconds.
+    pub fn rate(&self) -> u64 {
+        self.counters.rate.load(Ordering::Relaxed)
+    }
+
+    /// Sample downloaded bytes to update the download rate.
+    ///
+    /// Samples closer than a minimum delay are ignored, so this can be called as often as needed.
+    ///
+    pub fn sample(&self) {
+        self.sample_at(Instant::now());
+    }
+
+    /// Sample downloaded bytes at a given time to update the download rate.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The time of the sample.
+    ///
+    fn sample_at(&self, now: Instant) {
+        let mut last_sample = match self.counters.last_sample.try_lock() {
+            Ok(last_sample) => last_sample,
+            Err(_) => return,
+        };
+        let downloaded = self.downloaded();
+
+        // Start from the first sample
+        let (last_time, last_downloaded) = match *last_sample {
+            Some(sample) => sample,
+            None => {
+                *last_sample = Some((now, downloaded));
+                return;
+            }
+        };
+        let elapsed = now.saturating_duration_since(last_time);
+        if elapsed < RATE_SAMPLE_INTERVAL {
+            return;
+        }
+
+        // Weigh instant rate by elapsed time, in an exponential moving average
+        let instant = downloaded.saturating_sub(last_downloaded) as f64 / elapsed.as_secs_f64();
+        let alpha = 1.0 - (-elapsed.as_secs_f64() / RATE_WINDOW.as_secs_f64()).exp();
+        let rate = self.rate() as f64;
+        let rate = rate + alpha * (instant - rate);
+        self.counters
+            .rate
+            .store(rate.round() as u64, Ordering::Relaxed);
+
+        *last_sample = Some((now, downloaded));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_bytes_and_peers() {
+        let stats = Stats::new();
+        let shared = stats.clone();
+        shared.add_downloaded(16384);
+        shared.add_downloaded(1024);
+        shared.add_uploaded(512);
+        shared.add_peer();
+        shared.add_peer();
+        shared.remove_peer();
+
+        assert_eq!(stats.downloaded(), 17408);
+        assert_eq!(stats.uploaded(), 512);
+        assert_eq!(stats.connected_peers(), 1);
+
+        // Never count less than zero peers
+        stats.remove_peer();
+        stats.remove_peer();
+        assert_eq!(stats.connected_peers(), 0);
+    }
+
+    #[test]
+    fn count_messages_by_type() {
+        let stats = Stats::new();
+        stats.add_messages_sent(MESSAGE_INTERESTED, 1);
+        stats.add_messages_sent(MESSAGE_REQUEST, 5);
+        stats.add_message_received(MESSAGE_PIECE);
+        stats.add_message_received(MESSAGE_PIECE);
+
+        assert_eq!(stats.messages_sent(MESSAGE_INTERESTED), 1);
+        assert_eq!(stats.messages_sent(MESSAGE_REQUEST), 5);
+        assert_eq!(stats.messages_received(MESSAGE_PIECE), 2);
+        assert_eq!(stats.messages_received(MESSAGE_CHOKE), 0);
+
+        // Messages not supported by this client are not counted
+        stats.add_message_received(MESSAGE_ALLOWED_FAST + 1);
+        assert_eq!(stats.messages_received(MESSAGE_ALLOWED_FAST + 1), 0);
+    }
+
+    #[test]
+    fn smooth_download_rate() {
+        let stats = Stats::new();
+        let start = Instant::now();
+        stats.sample_at(start);
+
+        // A single burst only moves the rate part of the way
+        stats.add_downloaded(1_000_000);
+        stats.sample_at(start + Duration::from_secs(1));
+        let burst = stats.rate();
+        assert!(burst > 0 && burst < 1_000_000);
+
+        // Samples too close to the previous one are ignored
+        stats.add_downloaded(1_000_000);
+        stats.sample_at(start + Duration::from_millis(1100));
+        assert_eq!(stats.rate(), burst);
+
+        // The rate decreases steadily once download stalls
+        stats.sample_at(start + Duration::from_secs(2));
+        let mut previous = stats.rate();
+        for secs in 3..10 {
+            stats.sample_at(start + Duration::from_secs(secs));
+            assert!(stats.rate() < previous);
+            previous = stats.rate();
+        }
+    }
+}
@@ -0,0 +1,478 @@
+// Copyright (c) 2020 zenoxygen
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use anyhow::{anyhow, Result};
+
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// TorrentFile structure.
+#[derive(Default, Debug, Clone)]
+pub struct TorrentFile {
+    // Path of the file relative to the output path, empty for a single-file torrent
+    pub path: PathBuf,
+    // Size of the file in bytes
+    pub length: u64,
+    // Offset of the file within the torrent data in bytes
+    pub offset: u64,
+}
+
+impl TorrentFile {
+    /// Build a new torrent file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file relative to the output path.
+    /// * `length` - The file length.
+    /// * `offset` - The offset of the file within the torrent data.
+    ///
+    pub fn new(path: PathBuf, length: u64, offset: u64) -> TorrentFile {
+        TorrentFile {
+            path,
+            length,
+            offset,
+        }
+    }
+
+    /// Get the path of the file on disk.
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - The path where to save the torrent.
+    ///
+    pub fn filepath(&self, output: &Path) -> PathBuf {
+        if self.path.as_os_str().is_empty() {
+            output.to_path_buf()
+        } else {
+            output.join(&self.path)
+        }
+    }
+}
+
+/// Create the files of a torrent, keeping data of existing ones to resume download.
+///
+/// # Arguments
+///
+/// * `output` - The path where to save the torrent.
+/// * `files` - The files of the torrent.
+///
+pub fn create_files(output: &Path, files: &[TorrentFile]) -> Result<()> {
+    for file in files {
+        let filepath = file.filepath(output);
+
+        // Create parent directories
+        if let Some(parent) = filepath.parent() {
+            if create_dir_all(parent).is_err() {
+                return Err(anyhow!("could not create directory {:?}", parent));
+            }
+        }
+
+        // Create file, or open existing one
+        let f = match OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&filepath)
+        {
+            Ok(f) => f,
+            Err(_) => return Err(anyhow!("could not create file {:?}", filepath)),
+        };
+
+        // Set file to its expected size, leaving a file of the right size untouched
+        let resized = match f.metadata() {
+            Ok(metadata) if metadata.len() == file.length => Ok(()),
+            _ => f.set_len(file.length),
+        };
+        if resized.is_err() {
+            return Err(anyhow!("could not create file {:?}", filepath));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check existing files have the size of the files of a torrent, as a previous download of it.
+///
+/// # Arguments
+///
+/// * `output` - The path where to save the torrent.
+/// * `files` - The files of the torrent.
+///
+pub fn check_existing_files(output: &Path, files: &[TorrentFile]) -> Result<()> {
+    for file in files {
+        let filepath = file.filepath(output);
+        if let Ok(metadata) = filepath.metadata() {
+            if metadata.len() != file.length {
+                return Err(anyhow!(
+                    "file {:?} already exists with {:?} bytes rather than {:?} bytes, it is not a download of this torrent to resume",
+                    filepath,
+                    metadata.len(),
+                    file.length
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Flush data written to the files of a torrent to disk.
+///
+/// # Arguments
+///
+/// * `output` - The path where the torrent is saved.
+/// * `files` - The files of the torrent.
+///
+pub fn sync_files(output: &Path, files: &[TorrentFile]) -> Result<()> {
+    for file in files {
+        let filepath = file.filepath(output);
+        let synced = OpenOptions::new()
+            .write(true)
+            .open(&filepath)
+            .and_then(|f| f.sync_data());
+        if synced.is_err() {
+            return Err(anyhow!("could not sync file {:?}", filepath));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check there is enough disk space left to save the files of a torrent.
+///
+/// # Arguments
+///
+/// * `output` - The path where to save the torrent.
+/// * `files` - The files of the torrent.
+///
+pub fn check_space(output: &Path, files: &[TorrentFile]) -> Result<()> {
+    let mut needed: u64 = 0;
+    for file in files {
+        let filepath = file.filepath(output);
+
+        // Count bytes not yet allocated on disk
+        let allocated = match allocated_size(&filepath) {
+            Ok(allocated) => allocated,
+            Err(_) => return Err(anyhow!("could not get size of file {:?}", filepath)),
+        };
+        needed += file.length.saturating_sub(allocated);
+    }
+
+    // Query space available on the filesystem of the first file
+    let filepath = match files.first() {
+        Some(file) => file.filepath(output),
+        None => return Ok(()),
+    };
+    let available = match available_space(&filepath) {
+        Ok(available) => available,
+        Err(_) => return Err(anyhow!("could not get available space for {:?}", filepath)),
+    };
+
+    if needed > available {
+        return Err(anyhow!(
+            "not enough space, {:?} bytes needed but {:?} bytes available",
+            needed,
+            available
+        ));
+    }
+
+    Ok(())
+}
+
+/// Get the number of bytes allocated on disk for a file.
+///
+/// # Arguments
+///
+/// * `filepath` - The path of the file.
+///
+#[cfg(unix)]
+fn allocated_size(filepath: &Path) -> std::io::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Ok(std::fs::metadata(filepath)?.blocks() * 512)
+}
+
+/// Get the number of bytes allocated on disk for a file.
+///
+/// # Arguments
+///
+/// * `filepath` - The path of the file.
+///
+#[cfg(not(unix))]
+fn allocated_size(_filepath: &Path) -> std::io::Result<u64> {
+    Ok(0)
+}
+
+/// Get the number of bytes available to unprivileged users on the filesystem of a path.
+///
+/// # Arguments
+///
+/// * `path` - A path on the filesystem.
+///
+#[cfg(unix)]
+fn available_space(path: &Path) -> std::io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)),
+    };
+
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Get the number of bytes available to unprivileged users on the filesystem of a path.
+///
+/// Space is not queried on this platform.
+///
+/// # Arguments
+///
+/// * `path` - A path on the filesystem.
+///
+#[cfg(not(unix))]
+fn available_space(_path: &Path) -> std::io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+/// Allocate disk space for the files of a torrent, keeping data of existing ones.
+///
+/// # Arguments
+///
+/// * `output` - The path where to save the torrent.
+/// * `files` - The files of the torrent.
+///
+pub fn preallocate_files(output: &Path, files: &[TorrentFile]) -> Result<()> {
+    for file in files {
+        let filepath = file.filepath(output);
+
+        let f = match OpenOptions::new().write(true).open(&filepath) {
+            Ok(f) => f,
+            Err(_) => return Err(anyhow!("could not open file {:?}", filepath)),
+        };
+
+        // Allocate blocks for the whole file
+        if let Err(e) = allocate(&f, file.length) {
+            return match e.raw_os_error() {
+                #[cfg(unix)]
+                Some(libc::ENOSPC) => {
+                    Err(anyhow!("not enough space to allocate file {:?}", filepath))
+                }
+                _ => Err(anyhow!("could not allocate file {:?}: {}", filepath, e)),
+            };
+        }
+    }
+
+    Ok(())
+}
+
+/// Allocate disk blocks for a file.
+///
+/// # Arguments
+///
+/// * `f` - The file to allocate.
+/// * `length` - The number of bytes to allocate.
+///
+#[cfg(unix)]
+fn allocate(f: &File, length: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if length == 0 {
+        return Ok(());
+    }
+
+    match unsafe { libc::posix_fallocate(f.as_raw_fd(), 0, length as libc::off_t) } {
+        0 => Ok(()),
+        // Filesystem does not support allocation, file only has its expected size
+        libc::EOPNOTSUPP | libc::EINVAL => f.set_len(length),
+        errno => Err(std::io::Error::from_raw_os_error(errno)),
+    }
+}
+
+/// Allocate disk blocks for a file.
+///
+/// # Arguments
+///
+/// * `f` - The file to allocate.
+/// * `length` - The number of bytes to allocate.
+///
+#[cfg(not(unix))]
+fn allocate(f: &File, length: u64) -> std::io::Result<()> {
+    f.set_len(length)
+}
+
+/// Write bytes to files at an offset within the torrent data.
+///
+/// # Arguments
+///
+/// * `output` - The path where to save the torrent.
+/// * `files` - The files of the torrent.
+/// * `offset` - The offset within the torrent data.
+/// * `data` - The bytes to write.
+///
+pub fn write_files(output: &Path, files: &[TorrentFile], offset: u64, data: &[u8]) -> Result<()> {
+    let end = offset + data.len() as u64;
+
+    for file in files {
+        let file_end = file.offset + file.length;
+
+        // Skip empty files, holding no piece data, and files outside of the range
+        if file.length == 0 || file_end <= offset || file.offset >= end {
+            continue;
+        }
+
+        // Get range to write within file
+        let begin_in_file = offset.max(file.offset) - file.offset;
+        let end_in_file = end.min(file_end) - file.offset;
+
+        // Get range of bytes to write
+        let begin_in_data = (file.offset + begin_in_file - offset) as usize;
+        let end_in_data = (file.offset + end_in_file - offset) as usize;
+
+        let filepath = file.filepath(output);
+        let mut f = match OpenOptions::new().write(true).open(&filepath) {
+            Ok(f) => f,
+            Err(_) => return Err(anyhow!("could not open file {:?}", filepath)),
+        };
+        if f.seek(SeekFrom::Start(begin_in_file)).is_err() {
+            return Err(anyhow!("could not seek into file {:?}", filepath));
+        }
+        if f.write_all(&data[begin_in_data..end_in_data]).is_err() {
+            return Err(anyhow!("could not write data to file {:?}", filepath));
+        }
+    }
+
+    Ok(())
+}
+
+/// Read bytes from files at an offset within the torrent data.
+///
+/// # Arguments
+///
+/// * `output` - The path where the torrent is saved.
+/// * `files` - The files of the torrent.
+/// * `offset` - The offset within the torrent data.
+/// * `length` - The number of bytes to read.
+///
+pub fn read_files(
+    output: &Path,
+    files: &[TorrentFile],
+    offset: u64,
+    length: u64,
+) -> Result<Vec<u8>> {
+    let mut data: Vec<u8> = vec![];
+    let end = offset + length;
+
+    for file in files {
+        let file_end = file.offset + file.length;
+
+        // Skip empty files, holding no piece data, and files outside of the range
+        if file.length == 0 || file_end <= offset || file.offset >= end {
+            continue;
+        }
+
+        // Get range to read within file
+        let begin_in_file = offset.max(file.offset) - file.offset;
+        let end_in_file = end.min(file_end) - file.offset;
+
+        let filepath = file.filepath(output);
+        let mut f = match File::open(&filepath) {
+            Ok(f) => f,
+            Err(_) => return Err(anyhow!("could not open file {:?}", filepath)),
+        };
+        if f.seek(SeekFrom::Start(begin_in_file)).is_err() {
+            return Err(anyhow!("could not seek into file {:?}", filepath));
+        }
+
+        let mut buf: Vec<u8> = vec![0; (end_in_file - begin_in_file) as usize];
+        if f.read_exact(&mut buf).is_err() {
+            return Err(anyhow!("could not read data from file {:?}", filepath));
+        }
+        data.append(&mut buf);
+    }
+
+    // Check that the whole range was read
+    if data.len() != length as usize {
+        return Err(anyhow!("could not read data from files"));
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_and_read_around_empty_files() {
+        let output = std::env::temp_dir().join(format!("xerus-empty-files-{}", std::process::id()));
+        let files = vec![
+            TorrentFile::new(PathBuf::from("empty-first"), 0, 0),
+            TorrentFile::new(PathBuf::from("a"), 3, 0),
+            TorrentFile::new(["dir", "empty-middle"].iter().collect(), 0, 3),
+            TorrentFile::new(PathBuf::from("b"), 2, 3),
+            TorrentFile::new(PathBuf::from("empty-last"), 0, 5),
+        ];
+
+        create_files(&output, &files).unwrap();
+        write_files(&output, &files, 0, b"abcde").unwrap();
+
+        // Empty files are created, and data spans non-empty files only
+        for file in files.iter() {
+            let data = std::fs::read(file.filepath(&output)).unwrap();
+            assert_eq!(data.len(), file.length as usize);
+        }
+        assert_eq!(std::fs::read(output.join("a")).unwrap(), b"abc");
+        assert_eq!(std::fs::read(output.join("b")).unwrap(), b"de");
+        assert_eq!(read_files(&output, &files, 2, 2).unwrap(), b"cd");
+
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn check_existing_files_sizes() {
+        let output =
+            std::env::temp_dir().join(format!("xerus-existing-files-{}", std::process::id()));
+        let files = vec![
+            TorrentFile::new(PathBuf::from("a"), 3, 0),
+            TorrentFile::new(PathBuf::from("b"), 2, 3),
+        ];
+
+        // Accept missing files and files of a previous download
+        check_existing_files(&output, &files).unwrap();
+        create_files(&output, &files[..1]).unwrap();
+        check_existing_files(&output, &files).unwrap();
+
+        // Reject a file that would be resized
+        std::fs::write(output.join("b"), b"other").unwrap();
+        let error = check_existing_files(&output, &files).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("with 5 bytes rather than 2 bytes"));
+
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+}
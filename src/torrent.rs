@@ -18,50 +18,399 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+use crate::bitfield::*;
+use crate::client::*;
+use crate::config::*;
+use crate::hash::*;
+use crate::listener::*;
 use crate::peer::*;
 use crate::piece::*;
+use crate::progress::*;
+use crate::resume::*;
+use crate::stats::*;
+use crate::storage::*;
+use crate::verify::*;
+use crate::webseed::*;
 use crate::worker::*;
 
 use anyhow::{anyhow, Result};
-use boring::sha::Sha1;
-use crossbeam_channel::{unbounded, Receiver, Sender};
-use indicatif::{ProgressBar, ProgressStyle};
-use rand::Rng;
+use crossbeam_channel::{bounded, select, unbounded, Receiver, RecvTimeoutError, Sender};
+use flate2::read::GzDecoder;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value;
 use serde_bencode::{de, ser};
 use serde_bytes::ByteBuf;
 use std::str;
 use url::Url;
 
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const PORT: u16 = 6881;
+const TRACKER_ATTEMPTS: u32 = 3;
+const TRACKER_BACKOFF: Duration = Duration::from_secs(1);
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(15);
+const TRACKER_REDIRECTS_MAX: usize = 5;
+const TRACKER_RESPONSE_LEN_MAX: usize = 16 * 1024 * 1024;
+const TRACKER_SNIPPET_LEN: usize = 200;
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const TIER_GRACE: Duration = Duration::from_secs(2);
 const SHA1_HASH_SIZE: usize = 20;
+const PEER_ID_SIZE: usize = 20;
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_HASH_THREADS: usize = 4;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1800);
+const ANNOUNCE_BACKOFF_MAX: Duration = Duration::from_secs(3600);
+const ANNOUNCE_MIN_INTERVAL: Duration = Duration::from_secs(60);
+const STARVATION_DELAY: Duration = Duration::from_secs(10);
+const PEERS_LOST_TIMEOUT: Duration = Duration::from_secs(90);
+const PEER_CHECK_SAMPLE: usize = 5;
+const PEER_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(2);
+const PROGRESS_LINE_INTERVAL: Duration = Duration::from_secs(10);
+const PIECE_ORDER_INTERVAL: Duration = Duration::from_secs(5);
 
 /// Torrent structure.
 #[derive(Default, Clone)]
 pub struct Torrent {
     // URL of the tracker
     announce: String,
+    // Tiers of tracker URLs of a BEP 12 announce list, replacing the tracker when set
+    announce_list: Vec<Vec<String>>,
+    // URLs of additional trackers, announced to along with the torrent tracker
+    trackers: Vec<String>,
+    // URLs of BEP 17 web seeds, serving whole pieces
+    httpseeds: Vec<String>,
+    // URLs of BEP 19 web seeds, serving files of the torrent
+    url_list: Vec<String>,
+    // Addresses of DHT bootstrap nodes, when listed by a trackerless torrent
+    nodes: Option<Vec<String>>,
     // 20-byte SHA-1 hash calculated over the content of the bencoded info dictionary
     info_hash: Vec<u8>,
     // SHA-1 hashes of each pieces
     pieces_hashes: Vec<Vec<u8>>,
     // Size of each piece in bytes
     piece_length: u32,
-    // Size of the file in bytes
-    length: u32,
+    // Size of the torrent data in bytes
+    length: u64,
     // Suggested filename where to save the file
     name: String,
+    // Files of the torrent
+    files: Vec<TorrentFile>,
+    // Path where to save the torrent
+    output: PathBuf,
     // Urlencoded 20-byte string used as unique client ID
     peer_id: Vec<u8>,
     // Peers
     peers: Vec<Peer>,
+    // Options of the download
+    config: TorrentConfig,
+    // Interval between announces to trackers
+    announce_interval: Duration,
+    // Minimum interval between announces to trackers, when set by trackers
+    announce_min_interval: Option<Duration>,
+    // Connection attempts limit shared with other torrents, replacing the maximum when set
+    connect_limit: Option<ConnectLimit>,
+    // File whose pieces are downloaded first and in order, as listed in the torrent
+    priority_file: Option<PathBuf>,
+    // Byte range of a single-file torrent to download, ends included, other pieces being skipped
+    range: Option<(u64, u64)>,
+    // Info hash the torrent must have, when received out-of-band
+    expected_info_hash: Option<Vec<u8>>,
+    // Download has been asked to stop
+    stop: Arc<AtomicBool>,
+    // Download has been asked to pause, holding requests to peers
+    paused: Arc<AtomicBool>,
+    // State and availability of pieces during download
+    progress: Progress,
+    // Transferred bytes and download rate
+    stats: Stats,
+    // Progress display shared with other torrents, when set
+    multi_progress: Option<MultiProgress>,
+}
+
+/// Stopper structure, asking a download to stop from another thread.
+#[derive(Clone)]
+pub struct Stopper {
+    // Download has been asked to stop
+    stop: Arc<AtomicBool>,
+}
+
+impl Stopper {
+    /// Ask download to stop, keeping pieces already saved to disk.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// ConnectLimit structure, limiting connection attempts and connected peers at once across several torrents.
+#[derive(Clone)]
+pub struct ConnectLimit {
+    // Free connection slots
+    chan: (Sender<()>, Receiver<()>),
+    // Free connected peer slots
+    peers: (Sender<()>, Receiver<()>),
+}
+
+impl ConnectLimit {
+    /// Build a new connection limit.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_connecting` - The maximum number of connection attempts at once.
+    /// * `max_peers` - The maximum number of peers connected at once.
+    ///
+    pub fn new(max_connecting: usize, max_peers: usize) -> Self {
+        let chan = bounded(max_connecting);
+        for _ in 0..max_connecting {
+            let _ = chan.0.send(());
+        }
+        let peers = bounded(max_peers);
+        for _ in 0..max_peers {
+            let _ = peers.0.send(());
+        }
+
+        ConnectLimit { chan, peers }
+    }
+
+    /// Build a new connection limit from the limits of a torrent configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The options of the downloads sharing the limit.
+    ///
+    pub fn from_config(config: &TorrentConfig) -> Self {
+        ConnectLimit::new(config.max_connecting, config.max_peers)
+    }
+}
+
+impl Default for ConnectLimit {
+    fn default() -> Self {
+        ConnectLimit::new(MAX_CONNECTING, MAX_PEERS)
+    }
+}
+
+/// Scrape structure, the state of the swarm reported by trackers.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Scrape {
+    // Number of peers with the whole torrent
+    pub complete: u32,
+    // Number of peers still downloading
+    pub incomplete: u32,
+    // Number of completed downloads
+    pub downloaded: u32,
+    // Name of the torrent, if reported by the tracker
+    pub name: Option<String>,
+}
+
+/// PeerCheck structure, the reachability of a sample of peers checked before download.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PeerCheck {
+    // Number of peers connected to
+    pub sampled: usize,
+    // Number of peers that completed handshake
+    pub reachable: usize,
+}
+
+/// MemoryPieces structure, downloaded pieces held in memory until output files are written.
+struct MemoryPieces {
+    // Torrent data, pieces placed at their offset
+    data: Vec<u8>,
+    // Pieces held in data
+    pieces: Bitfield,
+}
+
+impl MemoryPieces {
+    /// Hold a verified piece in memory, at its offset within torrent data.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The piece index.
+    /// * `offset` - The offset of the piece within torrent data.
+    /// * `data` - The piece data.
+    ///
+    fn insert(&mut self, index: u32, offset: u64, data: &[u8]) -> Result<()> {
+        // Prevent piece from overrunning torrent data
+        let begin = offset as usize;
+        let end = match begin.checked_add(data.len()) {
+            Some(end) if end <= self.data.len() => end,
+            _ => {
+                return Err(anyhow!(
+                    "could not hold piece {:?} in memory, piece ends past torrent data",
+                    index
+                ))
+            }
+        };
+
+        self.data[begin..end].copy_from_slice(data);
+        self.pieces.set_piece(index);
+
+        Ok(())
+    }
+}
+
+/// OrderedPieces structure, verified pieces buffered until they can be written in order.
+struct OrderedPieces {
+    // Index of the next piece to write, the number of pieces once every wanted piece is written
+    next: u32,
+    // Pieces received ahead of the next piece, holding their in-flight slot until written
+    pieces: HashMap<u32, PieceResult>,
+    // Pieces to write, others being skipped
+    wanted: Bitfield,
+    // Number of pieces in torrent
+    nb_pieces: u32,
+    // Size of each piece in bytes
+    piece_length: u64,
+    // Byte range to write, ends included, boundary pieces being trimmed to it
+    range: Option<(u64, u64)>,
+}
+
+impl OrderedPieces {
+    /// Build a new writer of pieces in order, starting from the first wanted piece.
+    ///
+    /// # Arguments
+    ///
+    /// * `wanted` - The bitfield of pieces to write.
+    /// * `nb_pieces` - The number of pieces in torrent.
+    /// * `piece_length` - The size of each piece in bytes.
+    /// * `range` - The byte range to write, ends included, or the whole pieces if not set.
+    ///
+    fn new(
+        wanted: Bitfield,
+        nb_pieces: u32,
+        piece_length: u64,
+        range: Option<(u64, u64)>,
+    ) -> OrderedPieces {
+        let mut ordered = OrderedPieces {
+            next: 0,
+            pieces: HashMap::new(),
+            wanted,
+            nb_pieces,
+            piece_length,
+            range,
+        };
+        ordered.next = ordered.next_wanted(0);
+        ordered
+    }
+
+    /// Get the index of the first wanted piece from an index, or the number of pieces if none.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index to look from.
+    ///
+    fn next_wanted(&self, index: u32) -> u32 {
+        (index..self.nb_pieces)
+            .find(|index| self.wanted.has_piece(*index))
+            .unwrap_or(self.nb_pieces)
+    }
+
+    /// Get the index from which pieces are held back, leaving a number of wanted pieces from the
+    /// next piece to write.
+    ///
+    /// # Arguments
+    ///
+    /// * `window` - The number of wanted pieces that can be downloaded ahead.
+    ///
+    fn window_end(&self, window: u32) -> u32 {
+        let mut end = self.next;
+        let mut nb_wanted = 0;
+        while end < self.nb_pieces && nb_wanted < window {
+            if self.wanted.has_piece(end) {
+                nb_wanted += 1;
+            }
+            end += 1;
+        }
+        end
+    }
+
+    /// Get the bytes of a piece to write, trimmed to the byte range.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The piece index.
+    /// * `data` - The piece data.
+    ///
+    fn trim<'a>(&self, index: u32, data: &'a [u8]) -> &'a [u8] {
+        let (begin, end) = match self.range {
+            Some(range) => range,
+            None => return data,
+        };
+        let offset = index as u64 * self.piece_length;
+        let first = begin.saturating_sub(offset).min(data.len() as u64) as usize;
+        let last = (end + 1).saturating_sub(offset).min(data.len() as u64) as usize;
+        &data[first..last.max(first)]
+    }
+
+    /// Buffer a verified piece, then write the pieces following those already written.
+    ///
+    /// # Arguments
+    ///
+    /// * `piece_result` - The verified piece.
+    /// * `out` - The output to write pieces to.
+    ///
+    fn push(&mut self, piece_result: PieceResult, out: &mut impl Write) -> Result<()> {
+        self.pieces.insert(piece_result.index, piece_result);
+        while let Some(piece_result) = self.pieces.remove(&self.next) {
+            let data = self.trim(piece_result.index, &piece_result.data);
+            match out.write_all(data).and_then(|_| out.flush()) {
+                Ok(()) => self.next = self.next_wanted(self.next + 1),
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                    return Err(StdoutClosed { index: self.next }.into())
+                }
+                Err(_) => return Err(anyhow!("could not write piece {:?} to stdout", self.next)),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// StdoutClosed structure, the error of a download whose data was written to a closed pipe.
+///
+/// The program reading stdout may stop early on purpose, as `head` does, so callers can tell
+/// this error from a failed download and stop quietly.
+///
+#[derive(Debug)]
+pub struct StdoutClosed {
+    // Index of the piece that could not be written
+    pub index: u32,
+}
+
+impl fmt::Display for StdoutClosed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "could not write piece {:?} to stdout, pipe was closed",
+            self.index
+        )
+    }
+}
+
+impl std::error::Error for StdoutClosed {}
+
+/// Announce structure, the answer of trackers to an announce.
+#[derive(Debug)]
+struct Announce {
+    // Peers announced by trackers
+    peers: Vec<Peer>,
+    // Interval before announcing again
+    interval: Duration,
+    // Minimum interval before announcing again, even when more peers are needed
+    min_interval: Option<Duration>,
 }
 
 /// BencodeInfo structure.
@@ -73,12 +422,42 @@ struct BencodeInfo {
     // Size of each piece in bytes
     #[serde(rename = "piece length")]
     piece_length: u32,
-    // Size of the file in bytes
-    #[serde(rename = "length")]
-    length: u32,
+    // Size of the file in bytes, for a single-file torrent
+    #[serde(default, rename = "length", skip_serializing_if = "Option::is_none")]
+    length: Option<u64>,
+    // Files informations, for a multi-file torrent
+    #[serde(default, rename = "files", skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<BencodeFile>>,
     // Suggested filename where to save the file
     #[serde(rename = "name")]
     name: String,
+    // Source tag added by some private trackers, part of the info hash
+    #[serde(default, rename = "source", skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    // Version of the metainfo format, 2 for a BitTorrent v2 or hybrid torrent
+    #[serde(
+        default,
+        rename = "meta version",
+        skip_serializing_if = "Option::is_none"
+    )]
+    meta_version: Option<u32>,
+    // Files tree with merkle roots of a v2 torrent, kept to compute the info hash
+    #[serde(default, rename = "file tree", skip_serializing_if = "Option::is_none")]
+    file_tree: Option<Value>,
+}
+
+/// BencodeFile structure.
+#[derive(Deserialize, Serialize)]
+struct BencodeFile {
+    // Size of the file in bytes
+    #[serde(rename = "length")]
+    length: u64,
+    // Path components of the file
+    #[serde(rename = "path")]
+    path: Vec<String>,
+    // Attributes of the file, such as padding files of a hybrid torrent
+    #[serde(default, rename = "attr", skip_serializing_if = "Option::is_none")]
+    attr: Option<String>,
 }
 
 /// BencodeTorrent structure.
@@ -87,6 +466,19 @@ struct BencodeTorrent {
     #[serde(default)]
     // URL of the tracker
     announce: String,
+    // Tiers of tracker URLs of BEP 12, tried in order
+    #[serde(
+        default,
+        rename = "announce-list",
+        skip_serializing_if = "Option::is_none"
+    )]
+    announce_list: Option<Vec<Vec<String>>>,
+    // URLs of BEP 17 web seeds
+    #[serde(default, rename = "httpseeds", skip_serializing_if = "Option::is_none")]
+    httpseeds: Option<Vec<String>>,
+    // URL or URLs of BEP 19 web seeds
+    #[serde(default, rename = "url-list", skip_serializing_if = "Option::is_none")]
+    url_list: Option<Value>,
     // Informations about file
     info: BencodeInfo,
 }
@@ -94,10 +486,555 @@ struct BencodeTorrent {
 /// BencodeTracker structure.
 #[derive(Debug, Deserialize, Serialize)]
 struct BencodeTracker {
+    // Reason why the tracker refused the request, sent instead of other keys
+    #[serde(
+        default,
+        rename = "failure reason",
+        skip_serializing_if = "Option::is_none"
+    )]
+    failure_reason: Option<String>,
     // Interval time to refresh the list of peers in seconds
+    #[serde(default)]
     interval: u32,
+    // Minimum interval time between announces in seconds
+    #[serde(
+        default,
+        rename = "min interval",
+        skip_serializing_if = "Option::is_none"
+    )]
+    min_interval: Option<u32>,
     // Peers IP addresses
+    #[serde(default)]
     peers: ByteBuf,
+    // Peers IPv6 addresses, sent by some trackers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    peers6: Option<ByteBuf>,
+}
+
+/// BencodeScrape structure, the answer of a tracker to a scrape request.
+#[derive(Debug, Deserialize, Serialize)]
+struct BencodeScrape {
+    // Reason why the tracker refused the request, sent instead of other keys
+    #[serde(
+        default,
+        rename = "failure reason",
+        skip_serializing_if = "Option::is_none"
+    )]
+    failure_reason: Option<String>,
+    // Swarm state of each requested torrent, keyed by info hash
+    #[serde(default)]
+    files: HashMap<ByteBuf, BencodeScrapeFile>,
+}
+
+/// BencodeScrapeFile structure, the swarm state of a torrent.
+#[derive(Debug, Deserialize, Serialize)]
+struct BencodeScrapeFile {
+    // Number of seeders
+    #[serde(default)]
+    complete: u32,
+    // Number of leechers
+    #[serde(default)]
+    incomplete: u32,
+    // Number of completed downloads
+    #[serde(default)]
+    downloaded: u32,
+    // Name of the torrent, sent by some trackers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<ByteBuf>,
+}
+
+/// BencodeTrackerPeer structure, for a peer in the dictionary model.
+#[derive(Debug, Deserialize, Serialize)]
+struct BencodeTrackerPeer {
+    // Peer IP address, or DNS name
+    ip: String,
+    // Peer port
+    port: u16,
+    // Whether peer has the whole torrent, sent by some trackers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    complete: Option<u8>,
+}
+
+/// BencodeTrackerDict structure, for trackers ignoring the compact request.
+#[derive(Debug, Deserialize, Serialize)]
+struct BencodeTrackerDict {
+    // Interval time to refresh the list of peers in seconds
+    #[serde(default)]
+    interval: u32,
+    // Minimum interval time between announces in seconds
+    #[serde(
+        default,
+        rename = "min interval",
+        skip_serializing_if = "Option::is_none"
+    )]
+    min_interval: Option<u32>,
+    // Peers as dictionaries
+    #[serde(default)]
+    peers: Vec<BencodeTrackerPeer>,
+}
+
+impl BencodeTrackerDict {
+    /// Get the addresses of peers reported as seeders or not, when tracker tells.
+    fn build_seed_hints(&self) -> Vec<(SocketAddr, bool)> {
+        self.peers
+            .iter()
+            .filter_map(|peer| {
+                let ip = peer.ip.parse::<IpAddr>().ok()?;
+                let complete = peer.complete?;
+                Some((SocketAddr::new(ip, peer.port), complete != 0))
+            })
+            .collect()
+    }
+
+    /// Convert peers from the dictionary model to the compact model.
+    fn to_compact(&self) -> BencodeTracker {
+        let mut peers: Vec<u8> = vec![];
+        let mut peers6: Vec<u8> = vec![];
+
+        for peer in &self.peers {
+            match peer.ip.parse::<IpAddr>() {
+                Ok(IpAddr::V4(ip)) => {
+                    peers.extend_from_slice(&ip.octets());
+                    peers.extend_from_slice(&peer.port.to_be_bytes());
+                }
+                Ok(IpAddr::V6(ip)) => {
+                    peers6.extend_from_slice(&ip.octets());
+                    peers6.extend_from_slice(&peer.port.to_be_bytes());
+                }
+                Err(_) => debug!("Discard peer with unsupported address {:?}", peer.ip),
+            }
+        }
+
+        BencodeTracker {
+            failure_reason: None,
+            interval: self.interval,
+            min_interval: self.min_interval,
+            peers: ByteBuf::from(peers),
+            peers6: Some(ByteBuf::from(peers6)),
+        }
+    }
+}
+
+/// Get the addresses of DHT bootstrap nodes listed by a trackerless torrent, skipping malformed ones.
+///
+/// Nodes are listed under the `nodes` key, as a list of host and port pairs. Torrents without
+/// this key get `None`, to tell them from trackerless torrents listing no usable node.
+///
+/// # Arguments
+///
+/// * `dict` - The bencoded dictionary of the torrent.
+///
+fn build_nodes(dict: &HashMap<Vec<u8>, Value>) -> Option<Vec<String>> {
+    let nodes = match dict.get(&b"nodes"[..]) {
+        Some(Value::List(nodes)) => nodes,
+        Some(_) => return Some(vec![]),
+        None => return None,
+    };
+
+    let nodes = nodes
+        .iter()
+        .filter_map(|node| match node {
+            Value::List(node) => match node.as_slice() {
+                [Value::Bytes(host), Value::Int(port)] => {
+                    let host = str::from_utf8(host).ok()?;
+                    let port = u16::try_from(*port).ok()?;
+                    Some(format!("{}:{}", host, port))
+                }
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect();
+
+    Some(nodes)
+}
+
+/// InfoHashes structure, the info hash computed in two ways to diagnose hash mismatches.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InfoHashes {
+    // Hash of the info dictionary serialized again from its decoded keys, used for download
+    pub serialized: Vec<u8>,
+    // Hash of the info dictionary bytes as found in the torrent
+    pub raw: Option<Vec<u8>>,
+}
+
+/// Compute the info hash of a torrent both from its decoded info dictionary and from its raw bytes.
+///
+/// Both hashes differ when the torrent is not encoded canonically, or has info keys this client
+/// does not decode, which makes trackers and peers reject the hash used for download.
+///
+/// # Arguments
+///
+/// * `buf` - The bencoded content of the torrent.
+///
+pub fn compute_info_hashes(buf: &[u8]) -> Result<InfoHashes> {
+    let bencode = match de::from_bytes::<BencodeTorrent>(buf) {
+        Ok(bencode) => bencode,
+        Err(_) => return Err(anyhow!("could not decode torrent")),
+    };
+
+    Ok(InfoHashes {
+        serialized: bencode.info.hash()?,
+        raw: match find_info_bytes(buf) {
+            Some(info) => Some(sha1(info)?),
+            None => None,
+        },
+    })
+}
+
+/// Find the raw bytes of the info dictionary in a bencoded torrent.
+///
+/// # Arguments
+///
+/// * `buf` - The bencoded content of the torrent.
+///
+fn find_info_bytes(buf: &[u8]) -> Option<&[u8]> {
+    if buf.first() != Some(&b'd') {
+        return None;
+    }
+
+    // Walk keys of the torrent dictionary, skipping values until the info key
+    let mut pos = 1;
+    while buf.get(pos) != Some(&b'e') {
+        let key_end = bencode_value_end(buf, pos)?;
+        let key = &buf[pos..key_end];
+        let value_end = bencode_value_end(buf, key_end)?;
+        if key == b"4:info" {
+            return Some(&buf[key_end..value_end]);
+        }
+        pos = value_end;
+    }
+
+    None
+}
+
+/// Get the position following a bencoded value, or nothing if it is malformed.
+///
+/// # Arguments
+///
+/// * `buf` - The bencoded data.
+/// * `pos` - The position where the value starts.
+///
+fn bencode_value_end(buf: &[u8], pos: usize) -> Option<usize> {
+    match buf.get(pos)? {
+        b'i' => Some(pos + buf[pos..].iter().position(|&b| b == b'e')? + 1),
+        b'l' | b'd' => {
+            let mut pos = pos + 1;
+            while *buf.get(pos)? != b'e' {
+                pos = bencode_value_end(buf, pos)?;
+            }
+            Some(pos + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = pos + buf[pos..].iter().position(|&b| b == b':')?;
+            let len: usize = str::from_utf8(&buf[pos..colon]).ok()?.parse().ok()?;
+            let end = colon.checked_add(1)?.checked_add(len)?;
+            if end > buf.len() {
+                return None;
+            }
+            Some(end)
+        }
+        _ => None,
+    }
+}
+
+/// Find the first integer of bencoded data too wide for 64 bits, with the key holding it.
+///
+/// # Arguments
+///
+/// * `buf` - The bencoded data.
+///
+fn wide_integer(buf: &[u8]) -> Option<(String, String)> {
+    let mut wide = None;
+    find_wide_integer(buf, 0, b"", &mut wide);
+    wide
+}
+
+/// Find an integer too wide for 64 bits in a bencoded value, returning where the value ends.
+///
+/// # Arguments
+///
+/// * `buf` - The bencoded data.
+/// * `pos` - The position where the value starts.
+/// * `key` - The dictionary key holding the value.
+/// * `wide` - The key and value of the first integer found too wide.
+///
+fn find_wide_integer(
+    buf: &[u8],
+    pos: usize,
+    key: &[u8],
+    wide: &mut Option<(String, String)>,
+) -> Option<usize> {
+    match buf.get(pos)? {
+        b'i' => {
+            let end = bencode_value_end(buf, pos)?;
+            let value = String::from_utf8_lossy(&buf[pos + 1..end - 1]).into_owned();
+            if wide.is_none() && value.parse::<i64>().is_err() {
+                *wide = Some((String::from_utf8_lossy(key).into_owned(), value));
+            }
+            Some(end)
+        }
+        b'l' => {
+            let mut pos = pos + 1;
+            while *buf.get(pos)? != b'e' {
+                pos = find_wide_integer(buf, pos, key, wide)?;
+            }
+            Some(pos + 1)
+        }
+        b'd' => {
+            let mut pos = pos + 1;
+            while *buf.get(pos)? != b'e' {
+                let key_end = bencode_value_end(buf, pos)?;
+                let colon = pos + buf[pos..key_end].iter().position(|&b| b == b':')?;
+                pos = find_wide_integer(buf, key_end, &buf[colon + 1..key_end], wide)?;
+            }
+            Some(pos + 1)
+        }
+        _ => bencode_value_end(buf, pos),
+    }
+}
+
+/// Check an integer of a dictionary is within the range of its field, if present.
+///
+/// # Arguments
+///
+/// * `dict` - The bencoded dictionary.
+/// * `key` - The key of the integer.
+/// * `max` - The maximum value of the field.
+///
+fn check_integer(dict: &HashMap<Vec<u8>, Value>, key: &str, max: u64) -> Result<()> {
+    match dict.get(key.as_bytes()) {
+        None => Ok(()),
+        Some(Value::Int(n)) if *n >= 0 && *n as u64 <= max => Ok(()),
+        Some(Value::Int(n)) => Err(anyhow!(
+            "could not decode torrent, key {:?} has value {} out of range 0 to {}",
+            key,
+            n,
+            max
+        )),
+        Some(_) => Err(anyhow!(
+            "could not decode torrent, key {:?} is not an integer",
+            key
+        )),
+    }
+}
+
+/// Check if data looks like an HTML page, such as an error page served instead of a torrent.
+///
+/// # Arguments
+///
+/// * `buf` - The data to check.
+///
+fn is_html(buf: &[u8]) -> bool {
+    let start = String::from_utf8_lossy(&buf[..buf.len().min(512)]).to_ascii_lowercase();
+    let start = start.trim_start();
+    start.starts_with("<!doctype html") || start.starts_with("<html") || start.starts_with("<?xml")
+}
+
+/// Build the error of a tracker response that could not be decoded.
+///
+/// A response that is not bencoded, such as a maintenance page or a JSON error, is quoted so
+/// the message of the tracker can be read.
+///
+/// # Arguments
+///
+/// * `what` - The kind of response.
+/// * `body` - The body of the response.
+///
+fn decode_error(what: &str, body: &[u8]) -> anyhow::Error {
+    match body.first() {
+        // Bencoded data starts with a dictionary, list, integer or string
+        Some(b'd' | b'l' | b'i' | b'0'..=b'9') => anyhow!("could not decode {}", what),
+        Some(_) => {
+            let text = String::from_utf8_lossy(body);
+            let text = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+            let mut snippet: String = text.chars().take(TRACKER_SNIPPET_LEN).collect();
+            if snippet.len() < text.len() {
+                snippet.push_str("...");
+            }
+            anyhow!(
+                "could not decode {}, not bencoded, tracker answered {:?}",
+                what,
+                snippet
+            )
+        }
+        None => anyhow!("could not decode {}, response is empty", what),
+    }
+}
+
+/// Send a GET request to a tracker, retrying on network errors and overloaded tracker.
+///
+/// Each attempt times out, so a stalled tracker never keeps its request running for long.
+///
+/// # Arguments
+///
+/// * `client` - The HTTP client sending requests to trackers.
+/// * `announce` - The announce URL of the tracker.
+/// * `tracker_url` - The tracker URL with the announce parameters.
+///
+fn send_tracker_request(
+    client: &reqwest::blocking::Client,
+    announce: &str,
+    tracker_url: &str,
+) -> Result<Vec<u8>> {
+    // Send GET request to the tracker, retrying on network errors and overloaded tracker
+    let mut backoff = TRACKER_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let error = match client.get(tracker_url).send() {
+            Ok(response) => {
+                if response.url().as_str() != tracker_url {
+                    debug!("Tracker {} redirected to {}", announce, response.url());
+                }
+
+                // Retry a tracker failing or rate limiting, other errors would not change on retry
+                let status = response.status();
+                if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                    format!("tracker answered with status {}", status)
+                } else if !status.is_success() {
+                    return Err(anyhow!("tracker answered with status {}", status));
+                } else {
+                    let content_encoding = get_content_encoding(&response);
+                    match response.bytes() {
+                        Ok(bytes) => return decode_tracker_response(content_encoding, &bytes),
+                        Err(_) => "could not read response from tracker".to_string(),
+                    }
+                }
+            }
+            // A redirect loop would not change on retry
+            Err(e) if e.is_redirect() => {
+                return Err(anyhow!(
+                    "tracker redirected in a loop or more than {:?} times",
+                    TRACKER_REDIRECTS_MAX
+                ))
+            }
+            Err(_) => "could not send request to tracker".to_string(),
+        };
+
+        if attempt >= TRACKER_ATTEMPTS {
+            return Err(anyhow!("{} after {:?} attempts", error, attempt));
+        }
+        warn!("Tracker request failed: {}, retry in {:?}", error, backoff);
+        thread::sleep(backoff);
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+/// Get the content encoding of a tracker response, in lowercase.
+///
+/// # Arguments
+///
+/// * `response` - The tracker response.
+///
+fn get_content_encoding(response: &reqwest::blocking::Response) -> Option<String> {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_ENCODING)
+        .map(|encoding| {
+            String::from_utf8_lossy(encoding.as_bytes())
+                .trim()
+                .to_ascii_lowercase()
+        })
+}
+
+/// Decode the body of a tracker response, decompressing it when gzip was used.
+///
+/// Gzip data is also detected without a content encoding, as bencoded data never starts like it.
+///
+/// # Arguments
+///
+/// * `content_encoding` - The content encoding of the response, if set.
+/// * `body` - The body of the response.
+///
+fn decode_tracker_response(content_encoding: Option<String>, body: &[u8]) -> Result<Vec<u8>> {
+    match content_encoding.as_deref() {
+        None | Some("") | Some("identity") => match body.starts_with(&GZIP_MAGIC) {
+            true => decompress_gzip(body, TRACKER_RESPONSE_LEN_MAX),
+            false => Ok(body.to_vec()),
+        },
+        Some("gzip") | Some("x-gzip") => match body.starts_with(&GZIP_MAGIC) {
+            true => decompress_gzip(body, TRACKER_RESPONSE_LEN_MAX),
+            false => Err(anyhow!(
+                "tracker response is encoded as gzip but is not gzip data"
+            )),
+        },
+        Some(encoding) => Err(anyhow!(
+            "tracker response has unsupported content encoding {:?}",
+            encoding
+        )),
+    }
+}
+
+/// Decompress gzip data, rather than inflating whatever is received.
+///
+/// # Arguments
+///
+/// * `data` - The gzip data.
+/// * `max_len` - The maximum size of decompressed data.
+///
+fn decompress_gzip(data: &[u8], max_len: usize) -> Result<Vec<u8>> {
+    let mut decompressed: Vec<u8> = vec![];
+    if GzDecoder::new(data)
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .is_err()
+    {
+        return Err(anyhow!("could not decompress gzip data"));
+    }
+    if decompressed.len() > max_len {
+        return Err(anyhow!(
+            "could not decompress gzip data, larger than {:?} bytes",
+            max_len
+        ));
+    }
+
+    Ok(decompressed)
+}
+
+impl BencodeTorrent {
+    /// Build BEP 17 web seeds, keeping only valid HTTP URLs.
+    fn build_httpseeds(&self) -> Vec<String> {
+        let mut httpseeds: Vec<String> = vec![];
+        for httpseed in self.httpseeds.iter().flatten() {
+            match Url::parse(httpseed) {
+                Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+                    httpseeds.push(httpseed.to_owned())
+                }
+                _ => debug!("Discard web seed with invalid URL {:?}", httpseed),
+            }
+        }
+
+        httpseeds
+    }
+
+    /// Build BEP 19 web seeds from a single URL or a list of URLs, keeping only valid HTTP URLs.
+    fn build_url_list(&self) -> Vec<String> {
+        let urls: Vec<&Vec<u8>> = match &self.url_list {
+            Some(Value::Bytes(url)) => vec![url],
+            Some(Value::List(urls)) => urls
+                .iter()
+                .filter_map(|url| match url {
+                    Value::Bytes(url) => Some(url),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        };
+
+        let mut url_list: Vec<String> = vec![];
+        for url in urls {
+            let url = String::from_utf8_lossy(url);
+            match Url::parse(&url) {
+                Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => {
+                    url_list.push(url.into_owned())
+                }
+                _ => debug!("Discard web seed with invalid URL {:?}", url),
+            }
+        }
+
+        url_list
+    }
 }
 
 impl BencodeInfo {
@@ -107,13 +1044,7 @@ impl BencodeInfo {
         let buf: Vec<u8> = ser::to_bytes::<BencodeInfo>(self)?;
 
         // Hash bencoded informations
-        let mut hasher = Sha1::new();
-        hasher.update(&buf);
-
-        // Read hash digest
-        let hash = hasher.finish().to_vec();
-
-        Ok(hash)
+        sha1(&buf)
     }
 
     /// Split bencoded pieces into vectors of SHA-1 hashes.
@@ -122,7 +1053,7 @@ impl BencodeInfo {
         let nb_pieces = pieces.len();
 
         // Check torrent pieces
-        if nb_pieces % SHA1_HASH_SIZE != 0 {
+        if !nb_pieces.is_multiple_of(SHA1_HASH_SIZE) {
             return Err(anyhow!("torrent is invalid"));
         }
         let nb_hashes = nb_pieces / SHA1_HASH_SIZE;
@@ -135,260 +1066,3190 @@ impl BencodeInfo {
 
         Ok(hashes)
     }
+
+    /// Build files from bencoded informations.
+    fn build_files(&self) -> Result<Vec<TorrentFile>> {
+        // Single-file torrent
+        if let Some(length) = self.length {
+            return Ok(vec![TorrentFile::new(PathBuf::new(), length, 0)]);
+        }
+
+        // Multi-file torrent
+        let bencode_files = match &self.files {
+            Some(files) if !files.is_empty() => files,
+            _ => return Err(anyhow!("torrent is invalid")),
+        };
+
+        let mut files: Vec<TorrentFile> = vec![];
+        let mut offset: u64 = 0;
+        for bencode_file in bencode_files {
+            // Build relative path, rejecting path traversal
+            let path: PathBuf = bencode_file.path.iter().collect();
+            if bencode_file.path.is_empty()
+                || !path.components().all(|c| matches!(c, Component::Normal(_)))
+            {
+                return Err(anyhow!("torrent is invalid"));
+            }
+
+            files.push(TorrentFile::new(path, bencode_file.length, offset));
+
+            offset = match offset.checked_add(bencode_file.length) {
+                Some(offset) => offset,
+                None => return Err(anyhow!("torrent is invalid")),
+            };
+        }
+
+        Ok(files)
+    }
 }
 
 impl Torrent {
     /// Build a new torrent.
     pub fn new() -> Self {
-        Default::default()
+        Torrent::with_config(TorrentConfig::default())
     }
 
-    /// Open torrent.
+    /// Build a new torrent downloaded with the given options.
     ///
     /// # Arguments
     ///
-    /// * `filepath` - Path to the torrent.
+    /// * `config` - The options of the download, validated when built.
     ///
-    pub fn open(&mut self, filepath: PathBuf) -> Result<()> {
-        // Open torrent
-        let mut file = match File::open(filepath) {
-            Ok(file) => file,
-            Err(_) => return Err(anyhow!("could not open torrent")),
-        };
-
-        // Read torrent content in a buffer
-        let mut buf = vec![];
-        if file.read_to_end(&mut buf).is_err() {
-            return Err(anyhow!("could not read torrent"));
+    pub fn with_config(config: TorrentConfig) -> Self {
+        Torrent {
+            config,
+            ..Default::default()
         }
-        // Deserialize bencoded data from torrent
-        let bencode = match de::from_bytes::<BencodeTorrent>(&buf) {
-            Ok(bencode) => bencode,
-            Err(_) => return Err(anyhow!("could not decode torrent")),
-        };
+    }
 
-        // Generate a random 20-byte peer id
-        let mut peer_id: Vec<u8> = vec![0; 20];
-        let mut rng = rand::thread_rng();
-        for x in peer_id.iter_mut() {
-            *x = rng.gen();
-        }
+    /// Get the options of the download.
+    pub fn config(&self) -> &TorrentConfig {
+        &self.config
+    }
 
-        // Add torrent informations
-        self.announce = bencode.announce.to_owned();
-        self.info_hash = bencode.info.hash()?;
-        self.pieces_hashes = bencode.info.split_pieces_hashes()?;
-        self.piece_length = bencode.info.piece_length;
-        self.length = bencode.info.length;
-        self.name = bencode.info.name.to_owned();
-        self.peer_id = peer_id.clone();
-        self.peers = self.request_peers(peer_id, PORT)?;
+    /// Set connection attempts limit shared with other torrents, replacing the maximum number of connection attempts.
+    ///
+    /// # Arguments
+    ///
+    /// * `connect_limit` - The shared connection attempts limit.
+    ///
+    pub fn set_connect_limit(&mut self, connect_limit: ConnectLimit) {
+        self.connect_limit = Some(connect_limit);
+    }
+
+    /// Set a fixed peer id, instead of a random one generated when opening torrent.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer_id` - 20-byte string used as a unique ID for the client.
+    ///
+    pub fn set_peer_id(&mut self, peer_id: Vec<u8>) -> Result<()> {
+        if peer_id.len() != PEER_ID_SIZE {
+            return Err(anyhow!(
+                "invalid peer id, expected {:?} bytes but found {:?}",
+                PEER_ID_SIZE,
+                peer_id.len()
+            ));
+        }
+        self.peer_id = peer_id;
 
         Ok(())
     }
 
-    /// Request peers from tracker.
+    /// Get a handle to stop download from another thread.
+    pub fn stopper(&self) -> Stopper {
+        Stopper {
+            stop: self.stop.clone(),
+        }
+    }
+
+    /// Pause download, holding requests to peers but keeping connections open.
+    ///
+    /// Pieces being downloaded are completed first. Clones of the torrent share this state,
+    /// so download can be paused from another thread.
+    ///
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Resume a paused download, requesting pieces that are still missing.
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// Check if download is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Get a handle to follow state and availability of pieces from another thread.
+    pub fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    /// Get a handle to follow transferred bytes and download rate from another thread.
+    pub fn stats(&self) -> Stats {
+        self.stats.clone()
+    }
+
+    /// Set info hash the torrent must have, checked when torrent is opened before contacting trackers.
     ///
     /// # Arguments
     ///
-    /// * `peer_id` - Urlencoded 20-byte string used as a unique ID for the client.
-    /// * `port` - Port number that the client is listening on.
+    /// * `info_hash` - The expected 20-byte SHA-1 hash of the info key in the metainfo file.
     ///
-    fn request_peers(&self, peer_id: Vec<u8>, port: u16) -> Result<Vec<Peer>> {
-        // Build tracker URL
-        let tracker_url = match self.build_tracker_url(peer_id, port) {
-            Ok(url) => url,
-            Err(_) => return Err(anyhow!("could not build tracker url")),
-        };
+    pub fn set_expected_info_hash(&mut self, info_hash: Vec<u8>) {
+        self.expected_info_hash = Some(info_hash);
+    }
 
-        // Build blocking HTTP client
-        let client = match reqwest::blocking::Client::builder()
-            .timeout(Duration::from_secs(15))
-            .build()
-        {
-            Ok(client) => client,
-            Err(_) => return Err(anyhow!("could not connect to tracker")),
-        };
+    /// Set file whose pieces are downloaded first and in order, so it is usable sooner.
+    ///
+    /// Other pieces are downloaded afterwards, in the usual order.
+    ///
+    /// # Arguments
+    ///
+    /// * `priority_file` - The path of the file as listed in the torrent, or the torrent name for a single-file torrent.
+    ///
+    pub fn set_priority_file(&mut self, priority_file: PathBuf) {
+        self.priority_file = Some(priority_file);
+    }
 
-        // Send GET request to the tracker
-        let response = match client.get(tracker_url).send() {
-            Ok(response) => match response.bytes() {
-                Ok(bytes) => bytes,
-                Err(_) => return Err(anyhow!("could not read response from tracker")),
-            },
-            Err(_) => return Err(anyhow!("could not send request to tracker")),
-        };
+    /// Set byte range of a single-file torrent to download, skipping pieces outside of it.
+    ///
+    /// Pieces overlapping the range boundaries are downloaded and verified whole.
+    ///
+    /// # Arguments
+    ///
+    /// * `begin` - The offset of the first byte of the range.
+    /// * `end` - The offset of the last byte of the range, included.
+    ///
+    pub fn set_range(&mut self, begin: u64, end: u64) {
+        self.range = Some((begin, end));
+    }
 
-        // Deserialize bencoded tracker response
-        let tracker_bencode = match de::from_bytes::<BencodeTracker>(&response) {
-            Ok(bencode) => bencode,
-            Err(_) => return Err(anyhow!("could not decode tracker response")),
-        };
+    /// Set additional trackers to announce to, along with the torrent tracker.
+    ///
+    /// # Arguments
+    ///
+    /// * `trackers` - The announce URLs of additional trackers.
+    ///
+    pub fn set_trackers(&mut self, trackers: Vec<String>) {
+        self.trackers = trackers;
+    }
+
+    /// Set progress display shared with other torrents, prefixing progress bars by torrent name.
+    ///
+    /// # Arguments
+    ///
+    /// * `multi_progress` - The shared progress display.
+    ///
+    pub fn set_multi_progress(&mut self, multi_progress: MultiProgress) {
+        self.multi_progress = Some(multi_progress);
+    }
 
-        // Build peers from tracker response
-        let peers: Vec<Peer> = match self.build_peers(tracker_bencode.peers.to_vec()) {
-            Ok(peers) => peers,
-            Err(_) => return Err(anyhow!("could not build peers")),
+    /// Open torrent, without contacting trackers until it is announced.
+    ///
+    /// # Arguments
+    ///
+    /// * `filepath` - Path to the torrent.
+    ///
+    pub fn open(&mut self, filepath: PathBuf) -> Result<()> {
+        // Open torrent
+        let mut file = match File::open(filepath) {
+            Ok(file) => file,
+            Err(_) => return Err(anyhow!("could not open torrent")),
         };
 
-        Ok(peers)
+        // Read torrent content in a buffer
+        let mut buf = vec![];
+        if file.read_to_end(&mut buf).is_err() {
+            return Err(anyhow!("could not read torrent"));
+        }
+
+        self.load_from_bytes(&buf)
     }
 
-    /// Build tracker URL.
+    /// Open every torrent of a directory, each set up like this torrent, in file name order.
+    ///
+    /// Torrents are not announced, callers announce them once they download them. Torrents that can
+    /// not be read, decoded or validated are skipped with a warning, rather than aborting the scan.
     ///
     /// # Arguments
     ///
-    /// * `peer_id` - Urlencoded 20-byte string used as a unique ID for the client.
-    /// * `port` - Port number that the client is listening on.
+    /// * `dir` - Path to the directory holding `.torrent` files.
     ///
-    fn build_tracker_url(&self, peer_id: Vec<u8>, port: u16) -> Result<String> {
-        // Parse tracker URL from torrent
-        let mut base_url = match Url::parse(&self.announce) {
-            Ok(url) => url,
-            Err(_) => return Err(anyhow!("could not parse tracker url")),
+    pub fn open_dir(&self, dir: &Path) -> Result<Vec<Torrent>> {
+        // List torrent files, sorted so torrents are always returned in the same order
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return Err(anyhow!("could not read directory {:?}", dir)),
         };
+        let mut filepaths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.is_file()
+                    && path
+                        .extension()
+                        .is_some_and(|extension| extension.eq_ignore_ascii_case("torrent"))
+            })
+            .collect();
+        filepaths.sort();
 
-        // Add parameters to the tracker URL
-        base_url
-            // Add info hash
-            .query_pairs_mut()
-            .encoding_override(Some(&|input| {
-                if input != "!" {
-                    Cow::Borrowed(input.as_bytes())
-                } else {
-                    Cow::Owned(self.info_hash.clone())
-                }
-            }))
-            .append_pair("info_hash", "!");
-        base_url
-            // Add peer id
-            .query_pairs_mut()
-            .encoding_override(Some(&|input| {
-                if input != "!" {
-                    Cow::Borrowed(input.as_bytes())
-                } else {
-                    Cow::Owned(peer_id.clone())
-                }
-            }))
-            .append_pair("peer_id", "!");
-        base_url
-            .query_pairs_mut()
-            // Add port
-            .append_pair("port", &port.to_string())
-            // Add uploaded
-            .append_pair("uploaded", "0")
-            // Add downloaded
-            .append_pair("downloaded", "0")
-            // Add compact
-            .append_pair("compact", "1")
-            // Add left
-            .append_pair("left", &self.length.to_string());
+        let mut torrents: Vec<Torrent> = vec![];
+        for filepath in filepaths {
+            // Copy settings, without sharing download state with other torrents
+            let mut torrent = Torrent {
+                stop: Arc::default(),
+                paused: Arc::default(),
+                progress: Progress::new(),
+                stats: Stats::new(),
+                ..self.clone()
+            };
 
-        Ok(base_url.to_string())
+            // Skip invalid torrent
+            if let Err(e) = torrent.open(filepath.clone()) {
+                warn!("Skip torrent {:?}: {}", filepath, e);
+                continue;
+            }
+            torrents.push(torrent);
+        }
+
+        Ok(torrents)
     }
 
-    /// Download torrent.
-    pub fn download(&self) -> Result<Vec<u8>> {
-        println!(
-            "Downloading {:?} ({:?} pieces)",
-            self.name,
-            self.pieces_hashes.len(),
-        );
+    /// Load torrent from its bencoded content, without contacting trackers.
+    ///
+    /// Torrent is only changed once every check passed, so a torrent that fails to load is
+    /// never left half loaded.
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - The bencoded content of the torrent.
+    ///
+    pub fn load_from_bytes(&mut self, buf: &[u8]) -> Result<()> {
+        // Check torrent is a bencoded dictionary, to tell what is wrong with it
+        if buf.iter().all(|b| b.is_ascii_whitespace()) {
+            return Err(anyhow!("could not decode torrent, file is empty"));
+        }
+        let dict = match de::from_bytes::<Value>(buf) {
+            Ok(Value::Dict(dict)) => dict,
+            Ok(_) => {
+                return Err(anyhow!(
+                    "could not decode torrent, not a bencoded dictionary"
+                ))
+            }
+            Err(_) if is_html(buf) => {
+                return Err(anyhow!(
+                    "could not decode torrent, file is an HTML page rather than a valid torrent"
+                ))
+            }
+            Err(_) => match wide_integer(buf) {
+                Some((key, value)) => {
+                    return Err(anyhow!(
+                        "could not decode torrent, key {:?} has value {} too wide for 64 bits",
+                        key,
+                        value
+                    ))
+                }
+                None => return Err(anyhow!("could not decode torrent, file is not bencoded")),
+            },
+        };
 
-        // Create work pieces channel
-        let work_chan: (Sender<PieceWork>, Receiver<PieceWork>) = unbounded();
+        // Check required keys are present
+        let info = match dict.get(&b"info"[..]) {
+            Some(Value::Dict(info)) => info,
+            _ => return Err(anyhow!("could not decode torrent, missing key \"info\"")),
+        };
 
-        // Create result pieces channel
-        let result_chan: (Sender<PieceResult>, Receiver<PieceResult>) = unbounded();
+        // Check torrent has v1 pieces, hybrid v1/v2 torrents being verified with SHA-1
+        if let Some(Value::Int(2)) = info.get(&b"meta version"[..]) {
+            if !info.contains_key(&b"pieces"[..]) {
+                return Err(anyhow!(
+                    "could not decode torrent, BitTorrent v2 is not supported"
+                ));
+            }
+            info!("Torrent is a hybrid v1/v2 torrent, verify pieces with SHA-1");
+        }
+        for key in ["name", "piece length", "pieces"] {
+            if !info.contains_key(key.as_bytes()) {
+                return Err(anyhow!("could not decode torrent, missing key {:?}", key));
+            }
+        }
+        if !info.contains_key(&b"length"[..]) && !info.contains_key(&b"files"[..]) {
+            return Err(anyhow!(
+                "could not decode torrent, missing key \"length\" or \"files\""
+            ));
+        }
 
-        // Create and send pieces to work channel
-        for index in 0..self.pieces_hashes.len() {
-            // Create piece
-            let piece_index = index as u32;
-            let piece_hash = self.pieces_hashes[index].clone();
-            let piece_length = self.get_piece_length(piece_index)?;
-            let piece_work = PieceWork::new(piece_index, piece_hash, piece_length);
+        // Check integers fit their fields, to name the one that does not
+        check_integer(info, "piece length", u32::MAX.into())?;
+        check_integer(info, "meta version", u32::MAX.into())?;
+        check_integer(info, "length", u64::MAX)?;
+        if let Some(Value::List(files)) = info.get(&b"files"[..]) {
+            for (i, file) in files.iter().enumerate() {
+                let file = match file {
+                    Value::Dict(file) => file,
+                    _ => {
+                        return Err(anyhow!(
+                            "could not decode torrent, file {} is not a dictionary",
+                            i
+                        ))
+                    }
+                };
+                for key in ["length", "path"] {
+                    if !file.contains_key(key.as_bytes()) {
+                        return Err(anyhow!(
+                            "could not decode torrent, missing key {:?} in file {}",
+                            key,
+                            i
+                        ));
+                    }
+                }
+                check_integer(file, "length", u64::MAX)
+                    .map_err(|e| anyhow!("{} in file {}", e, i))?;
+            }
+        }
 
-            // Send piece to work channel
-            if work_chan.0.send(piece_work).is_err() {
-                return Err(anyhow!("Error: could not send piece to channel"));
+        // Deserialize bencoded data from torrent
+        let bencode = match de::from_bytes::<BencodeTorrent>(buf) {
+            Ok(bencode) => bencode,
+            Err(_) => return Err(anyhow!("could not decode torrent")),
+        };
+
+        // Check info hash is the expected one, before contacting trackers
+        let info_hash = bencode.info.hash()?;
+        if let Some(expected_info_hash) = &self.expected_info_hash {
+            if !hashes_match(expected_info_hash, &info_hash) {
+                return Err(anyhow!(
+                    "info hash mismatch, expected {} but torrent has {}",
+                    hex::encode(expected_info_hash),
+                    hex::encode(&info_hash)
+                ));
             }
         }
 
-        // Init workers
-        let peers = self.peers.to_owned();
-        for peer in peers {
-            let peer_copy = peer.clone();
-            let peer_id_copy = self.peer_id.clone();
-            let info_hash_copy = self.info_hash.clone();
-            let work_chan_copy = work_chan.clone();
-            let result_chan_copy = result_chan.clone();
+        // Check number of pieces matches torrent length
+        let pieces_hashes = bencode.info.split_pieces_hashes()?;
+        let piece_length = bencode.info.piece_length;
+        let files = bencode.info.build_files()?;
+        let length: u64 = files.iter().map(|file| file.length).sum();
+        if piece_length == 0 {
+            return Err(anyhow!("torrent is invalid, piece length is 0"));
+        }
+        let nb_pieces_expected = length.div_ceil(piece_length as u64) as usize;
+        if pieces_hashes.len() != nb_pieces_expected {
+            return Err(anyhow!(
+                "torrent is invalid, expected {:?} pieces but found {:?}",
+                nb_pieces_expected,
+                pieces_hashes.len()
+            ));
+        }
 
-            // Create new worker
-            let worker = Worker::new(
-                peer_copy,
-                peer_id_copy,
-                info_hash_copy,
-                work_chan_copy,
-                result_chan_copy,
-            )?;
+        // Check torrent size is within limit
+        if let Some(max_size) = self.config.max_size {
+            if length > max_size {
+                return Err(anyhow!(
+                    "torrent size of {:?} bytes exceeds limit of {:?} bytes",
+                    length,
+                    max_size
+                ));
+            }
+        }
 
-            // Start worker in a new thread
-            thread::spawn(move || {
-                worker.start_download();
-            });
+        // Generate a random 20-byte peer id, unless one was set
+        if self.peer_id.is_empty() {
+            let mut peer_id = vec![0; PEER_ID_SIZE];
+            let mut rng = rand::thread_rng();
+            for x in peer_id.iter_mut() {
+                *x = rng.gen();
+            }
+            self.peer_id = peer_id;
         }
 
-        // Create progress bar
-        let pb = ProgressBar::new(self.length as u64);
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template("{spinner:.green} {bytes}/{total_bytes} [{bar:40.cyan/blue}] {percent}%")
-                .unwrap()
-                .progress_chars("#>-"),
-        );
+        // Add torrent informations, once torrent is known to be valid
+        self.announce = bencode.announce.to_owned();
+        self.announce_list = bencode.announce_list.clone().unwrap_or_default();
+        self.httpseeds = bencode.build_httpseeds();
+        self.url_list = bencode.build_url_list();
+        self.nodes = build_nodes(&dict);
+        self.info_hash = info_hash;
+        self.pieces_hashes = pieces_hashes;
+        self.piece_length = piece_length;
+        self.files = files;
+        self.length = length;
+        self.name = bencode.info.name.to_owned();
 
-        // Build torrent
-        let mut data: Vec<u8> = vec![0; self.length as usize];
-        let mut nb_pieces_downloaded = 0;
-        while nb_pieces_downloaded < self.pieces_hashes.len() {
-            // Receive a piece from result channel
-            let piece_result: PieceResult = match result_chan.1.recv() {
-                Ok(piece_result) => piece_result,
-                Err(_) => return Err(anyhow!("Error: could not receive piece from channel")),
-            };
+        Ok(())
+    }
 
-            // Copy piece data
-            let begin: u32 = piece_result.index * self.piece_length;
-            for i in 0..piece_result.length as usize {
-                data[begin as usize + i] = piece_result.data[i];
+    /// Check a sample of peers is reachable, connecting and handshaking with each of them.
+    ///
+    /// Peers are the first ones download would connect to. Connections are closed once
+    /// handshake completes or fails, and use their own short timeout.
+    ///
+    pub fn check_peers(&self) -> PeerCheck {
+        let peers: Vec<Peer> = self
+            .build_connect_order()
+            .into_iter()
+            .take(PEER_CHECK_SAMPLE)
+            .collect();
+        let nb_pieces = self.pieces_hashes.len() as u32;
+
+        // Handshake with each peer in its own thread
+        let reachable = thread::scope(|scope| {
+            let handles: Vec<_> = peers
+                .iter()
+                .map(|peer| scope.spawn(move || self.check_peer(peer.to_owned(), nb_pieces)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap_or(false))
+                .filter(|&reachable| reachable)
+                .count()
+        });
+
+        PeerCheck {
+            sampled: peers.len(),
+            reachable,
+        }
+    }
+
+    /// Check a peer is reachable, closing connection once handshake completes or fails.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - The peer to check.
+    /// * `nb_pieces` - The number of pieces in torrent.
+    ///
+    fn check_peer(&self, peer: Peer, nb_pieces: u32) -> bool {
+        let mut client = match Client::connect_within(
+            peer.clone(),
+            self.peer_id.clone(),
+            self.info_hash.clone(),
+            nb_pieces,
+            self.config.nodelay,
+            self.config.bind,
+            PEER_CHECK_TIMEOUT,
+        ) {
+            Ok(client) => client,
+            Err(e) => {
+                debug!("Could not reach peer {}: {}", peer, e);
+                return false;
             }
+        };
+        if client
+            .set_connection_timeout(PEER_CHECK_TIMEOUT.as_secs())
+            .is_err()
+        {
+            return false;
+        }
 
-            // Update progress bar
-            pb.inc(piece_result.length as u64);
+        // Connection is closed when client is dropped
+        match client.handshake_with_peer() {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Could not handshake with peer {}: {}", peer, e);
+                false
+            }
+        }
+    }
 
-            // Update number of pieces downloaded
-            nb_pieces_downloaded += 1;
+    /// Get peers in the order download connects to them.
+    fn build_connect_order(&self) -> Vec<Peer> {
+        // Shuffle peers to sample the swarm evenly
+        let mut peers = self.peers.to_owned();
+        match self.config.seed {
+            Some(seed) => peers.shuffle(&mut StdRng::seed_from_u64(seed)),
+            None => peers.shuffle(&mut rand::thread_rng()),
         }
 
-        Ok(data)
+        // Connect to peers on the local network first, then to peers reported as seeders,
+        // keeping shuffled order otherwise so peers without hint are still tried
+        let prefer_local = self.config.prefer_local;
+        peers.sort_by_key(|peer| (prefer_local && !peer.is_local(), !peer.is_seed()));
+
+        peers
     }
 
-    /// Get piece length.
+    /// Announce to trackers, replacing peers with the ones they return.
+    pub fn announce(&mut self) -> Result<()> {
+        let announce = self.request_peers_from_trackers(
+            self.peer_id.clone(),
+            self.config.listen_port.unwrap_or(PORT),
+        )?;
+        self.peers = announce.peers;
+        self.announce_interval = announce.interval;
+        self.announce_min_interval = announce.min_interval;
+
+        Ok(())
+    }
+
+    /// Check if torrent informations were loaded from a metainfo file.
     ///
-    /// # Arguments
+    /// A torrent may be loaded even though announcing to trackers failed.
     ///
-    /// * `index` - The piece index.
+    pub fn is_loaded(&self) -> bool {
+        self.info_hash.len() == SHA1_HASH_SIZE
+    }
+
+    /// Get the peers announced by trackers when torrent was opened, without connecting to them.
+    pub fn peers(&self) -> &[Peer] {
+        &self.peers
+    }
+
+    /// Get the tiers of announce URLs, tried in order, without duplicates.
     ///
-    fn get_piece_length(&self, index: u32) -> Result<u32> {
-        let begin: u32 = index * self.piece_length;
-        let mut end: u32 = begin + self.piece_length;
+    /// The BEP 12 announce list replaces the torrent tracker when set, and additional trackers are
+    /// announced to along with the first tier.
+    ///
+    fn build_announce_tiers(&self) -> Vec<Vec<String>> {
+        let mut tiers: Vec<Vec<&String>> = if self.announce_list.iter().any(|tier| !tier.is_empty())
+        {
+            self.announce_list
+                .iter()
+                .filter(|tier| !tier.is_empty())
+                .map(|tier| tier.iter().collect())
+                .collect()
+        } else {
+            vec![vec![&self.announce]]
+        };
+        tiers[0].extend(self.trackers.iter());
 
-        // Prevent unbounded values
-        if end > self.length {
-            end = self.length;
+        let mut announce_urls: Vec<String> = vec![];
+        let mut announce_tiers: Vec<Vec<String>> = vec![];
+        for tier in tiers {
+            let mut announce_tier: Vec<String> = vec![];
+            for announce in tier {
+                // Compare URLs in their normalized form, keeping the first tier of a URL
+                let announce = match Url::parse(announce.trim()) {
+                    Ok(url) => url.to_string(),
+                    Err(_) if announce.trim().is_empty() => continue,
+                    Err(_) => announce.trim().to_owned(),
+                };
+                if !announce_urls.contains(&announce) {
+                    announce_urls.push(announce.clone());
+                    announce_tier.push(announce);
+                }
+            }
+            if !announce_tier.is_empty() {
+                announce_tiers.push(announce_tier);
+            }
+        }
+
+        announce_tiers
+    }
+
+    /// Get the announce URLs of all tiers, without duplicates.
+    fn build_announce_urls(&self) -> Vec<String> {
+        self.build_announce_tiers().concat()
+    }
+
+    /// Request peers from trackers, tier after tier until a tracker of a tier answers.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer_id` - Urlencoded 20-byte string used as a unique ID for the client.
+    /// * `port` - Port number that the client is listening on.
+    ///
+    fn request_peers_from_trackers(&self, peer_id: Vec<u8>, port: u16) -> Result<Announce> {
+        let announce_tiers = self.build_announce_tiers();
+        if announce_tiers.is_empty() && self.nodes.is_some() {
+            return Err(anyhow!(
+                "could not find any tracker, torrent is trackerless and requires DHT, which is not supported yet"
+            ));
         }
+        if announce_tiers.is_empty() {
+            return Err(anyhow!("could not find any tracker"));
+        }
+
+        // Try next tier only if no tracker of a tier answered, as BEP 12 falls back
+        let mut errors: Vec<(String, anyhow::Error)> = vec![];
+        for (i, announce_tier) in announce_tiers.iter().enumerate() {
+            let (announce, mut tier_errors) =
+                self.request_peers_from_tier(announce_tier, &peer_id, port);
+            errors.append(&mut tier_errors);
+            if let Some(announce) = announce {
+                for (announce, e) in errors {
+                    warn!("Could not get peers from tracker {}: {}", announce, e);
+                }
+                return Ok(announce);
+            }
+            if i + 1 < announce_tiers.len() {
+                info!("No tracker of tier {:?} answered, try next tier", i);
+            }
+        }
+
+        // Fail with the error of each tracker
+        if errors.len() == 1 {
+            if let Some((_, e)) = errors.pop() {
+                return Err(e);
+            }
+        }
+        Err(anyhow!(
+            "could not get peers from any of {:?} trackers: {}",
+            errors.len(),
+            errors
+                .iter()
+                .map(|(announce, e)| format!("{}: {}", announce, e))
+                .collect::<Vec<String>>()
+                .join("; ")
+        ))
+    }
+
+    /// Request peers from all trackers of a tier at once, merging peers of trackers that answered.
+    ///
+    /// Once a tracker answered, the others are given a short while before they time out. Return
+    /// the merged announce if any tracker answered, along with errors of the others.
+    ///
+    /// # Arguments
+    ///
+    /// * `announce_tier` - The announce URLs of the tier.
+    /// * `peer_id` - Urlencoded 20-byte string used as a unique ID for the client.
+    /// * `port` - Port number that the client is listening on.
+    ///
+    fn request_peers_from_tier(
+        &self,
+        announce_tier: &[String],
+        peer_id: &[u8],
+        port: u16,
+    ) -> (Option<Announce>, Vec<(String, anyhow::Error)>) {
+        // Send request to each tracker in its own thread, not joined so a stalled tracker does not
+        // delay the others, each attempt timing out so a stalled thread ends before next announce
+        let (responses_chan, responses_recv) = unbounded::<(usize, Result<Vec<u8>>)>();
+        let client = self.build_tracker_client();
+        for (i, announce) in announce_tier.iter().enumerate() {
+            let request = match (
+                &client,
+                self.build_announce_request(announce, peer_id.to_vec(), port),
+            ) {
+                (Ok(client), Ok(tracker_url)) => (client.clone(), tracker_url),
+                (Err(e), _) => {
+                    let _ = responses_chan.send((i, Err(anyhow!("{}", e))));
+                    continue;
+                }
+                (_, Err(e)) => {
+                    let _ = responses_chan.send((i, Err(e)));
+                    continue;
+                }
+            };
+            let announce = announce.to_owned();
+            let responses_chan = responses_chan.clone();
+            thread::spawn(move || {
+                let (client, tracker_url) = request;
+                let response = send_tracker_request(&client, &announce, &tracker_url);
+                let _ = responses_chan.send((i, response));
+            });
+        }
+        drop(responses_chan);
+
+        // Wait for every tracker, or only a short while once a tracker answered
+        let mut tracker_responses: Vec<Option<Result<Announce>>> =
+            announce_tier.iter().map(|_| None).collect();
+        let mut deadline: Option<Instant> = None;
+        while tracker_responses.iter().any(|response| response.is_none()) {
+            let received = match deadline {
+                Some(deadline) => responses_recv.recv_deadline(deadline).ok(),
+                None => responses_recv.recv().ok(),
+            };
+            let (i, response) = match received {
+                Some(received) => received,
+                None => break,
+            };
+            let response = response.and_then(|response| self.read_tracker_response(&response));
+            if response.is_ok() && deadline.is_none() {
+                deadline = Some(Instant::now() + TIER_GRACE);
+            }
+            tracker_responses[i] = Some(response);
+        }
+
+        // Trackers still pending time out, in order of the tier
+        let responses: Vec<(String, Result<Announce>)> = announce_tier
+            .iter()
+            .zip(tracker_responses)
+            .map(|(announce, response)| {
+                let response = response.unwrap_or_else(|| {
+                    Err(anyhow!(
+                        "tracker did not answer within {:?} of another tracker",
+                        TIER_GRACE
+                    ))
+                });
+                (announce.to_owned(), response)
+            })
+            .collect();
+
+        // Merge peers, keeping the first of peers announced by several trackers
+        let mut peers: Vec<Peer> = vec![];
+        let mut interval: Option<Duration> = None;
+        let mut min_interval: Option<Duration> = None;
+        let mut errors: Vec<(String, anyhow::Error)> = vec![];
+        for (announce, response) in responses {
+            match response {
+                Ok(tracker_announce) => {
+                    // Announce again as soon as any tracker asks for it
+                    interval = Some(match interval {
+                        Some(interval) => interval.min(tracker_announce.interval),
+                        None => tracker_announce.interval,
+                    });
+
+                    // Never announce again sooner than any tracker allows
+                    min_interval = min_interval.max(tracker_announce.min_interval);
+                    for mut peer in tracker_announce.peers {
+                        match peers.iter_mut().find(|p| p.addr() == peer.addr()) {
+                            // Keep seed hint of any tracker that sent one
+                            Some(known) => known.seed = known.seed.or(peer.seed),
+                            None => {
+                                peer.id = peers.len() as u32;
+                                peers.push(peer);
+                            }
+                        }
+                    }
+                }
+                Err(e) => errors.push((announce, e)),
+            }
+        }
+
+        let announce = interval.map(|interval| Announce {
+            peers,
+            interval,
+            min_interval,
+        });
+
+        (announce, errors)
+    }
+
+    /// Build the URL of an announce request to a tracker.
+    ///
+    /// # Arguments
+    ///
+    /// * `announce` - The announce URL of the tracker.
+    /// * `peer_id` - Urlencoded 20-byte string used as a unique ID for the client.
+    /// * `port` - Port number that the client is listening on.
+    ///
+    fn build_announce_request(
+        &self,
+        announce: &str,
+        peer_id: Vec<u8>,
+        port: u16,
+    ) -> Result<String> {
+        // Report UDP tracker, whose protocol is not supported yet
+        if announce.starts_with("udp://") {
+            return Err(anyhow!(
+                "could not announce to tracker {}, udp trackers are not supported",
+                announce
+            ));
+        }
+
+        // Build tracker URL
+        match self.build_tracker_url(announce, peer_id, port) {
+            Ok(url) => Ok(url),
+            Err(_) => Err(anyhow!("could not build tracker url")),
+        }
+    }
+
+    /// Read peers from the decoded body of a tracker response.
+    ///
+    /// # Arguments
+    ///
+    /// * `response` - The decoded body of the tracker response.
+    ///
+    fn read_tracker_response(&self, response: &[u8]) -> Result<Announce> {
+        // Deserialize bencoded tracker response, whichever peers model the tracker used
+        let mut seed_hints: Vec<(SocketAddr, bool)> = vec![];
+        let tracker_bencode = match de::from_bytes::<BencodeTracker>(response) {
+            Ok(bencode) => bencode,
+            Err(serde_bencode::Error::InvalidType(_)) => {
+                debug!("Tracker ignored compact request, decode peers as dictionaries");
+                match de::from_bytes::<BencodeTrackerDict>(response) {
+                    Ok(bencode) => {
+                        seed_hints = bencode.build_seed_hints();
+                        bencode.to_compact()
+                    }
+                    Err(_) => return Err(decode_error("tracker response", response)),
+                }
+            }
+            Err(_) => return Err(decode_error("tracker response", response)),
+        };
+
+        // Check if tracker refused request, which would not change on retry
+        if let Some(failure_reason) = tracker_bencode.failure_reason {
+            return Err(anyhow!("tracker refused request: {}", failure_reason));
+        }
+
+        // Build IPv4 and IPv6 peers from tracker response
+        let tracker_peers6: Vec<u8> = match tracker_bencode.peers6 {
+            Some(peers6) => peers6.to_vec(),
+            None => vec![],
+        };
+        let mut peers: Vec<Peer> =
+            match self.build_peers(tracker_bencode.peers.to_vec(), tracker_peers6) {
+                Ok(peers) => peers,
+                Err(_) => return Err(anyhow!("could not build peers")),
+            };
+
+        // Mark peers tracker reported as seeders or not
+        for peer in peers.iter_mut() {
+            if let Some((_, seed)) = seed_hints.iter().find(|(addr, _)| *addr == peer.addr()) {
+                peer.seed = Some(*seed);
+            }
+        }
+
+        // Use default interval when tracker did not set it
+        let interval = match tracker_bencode.interval {
+            0 => ANNOUNCE_INTERVAL,
+            interval => Duration::from_secs(interval as u64),
+        };
+
+        // Announce at least as late as the minimum interval
+        let min_interval = tracker_bencode
+            .min_interval
+            .map(|min_interval| Duration::from_secs(min_interval as u64));
+        let interval = interval.max(min_interval.unwrap_or_default());
+
+        Ok(Announce {
+            peers,
+            interval,
+            min_interval,
+        })
+    }
+
+    /// Build the HTTP client requesting trackers, following a bounded number of redirects.
+    ///
+    /// Responses are decompressed by `decode_tracker_response`, so gzip is accepted explicitly.
+    ///
+    fn build_tracker_client(&self) -> Result<reqwest::blocking::Client> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            reqwest::header::ACCEPT_ENCODING,
+            reqwest::header::HeaderValue::from_static("gzip"),
+        );
+        match reqwest::blocking::Client::builder()
+            .timeout(TRACKER_TIMEOUT)
+            .default_headers(headers)
+            .local_address(self.config.bind)
+            .redirect(reqwest::redirect::Policy::limited(TRACKER_REDIRECTS_MAX))
+            .build()
+        {
+            Ok(client) => Ok(client),
+            Err(_) => Err(anyhow!("could not connect to tracker")),
+        }
+    }
+
+    /// Scrape trackers for the number of seeders and leechers of the torrent.
+    ///
+    /// When several trackers answer, the one reporting the most seeders is kept.
+    ///
+    pub fn scrape(&self) -> Result<Scrape> {
+        // Build blocking HTTP client
+        let client = self.build_tracker_client()?;
+
+        let mut best: Option<Scrape> = None;
+        let mut last_error = anyhow!("could not find any tracker");
+        for announce in self.build_announce_urls() {
+            match self.scrape_tracker(&client, &announce) {
+                Ok(scrape) => {
+                    if best
+                        .as_ref()
+                        .is_none_or(|best| scrape.complete > best.complete)
+                    {
+                        best = Some(scrape);
+                    }
+                }
+                Err(e) => {
+                    debug!("Could not scrape tracker {}: {}", announce, e);
+                    last_error = e;
+                }
+            }
+        }
+
+        match best {
+            Some(scrape) => Ok(scrape),
+            None => Err(last_error),
+        }
+    }
+
+    /// Scrape a tracker for the swarm state of the torrent.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The HTTP client.
+    /// * `announce` - The announce URL of the tracker.
+    ///
+    fn scrape_tracker(&self, client: &reqwest::blocking::Client, announce: &str) -> Result<Scrape> {
+        let scrape_url = self.build_scrape_url(announce)?;
+
+        // Send GET request to the tracker
+        let response = match client.get(&scrape_url).send() {
+            Ok(response) => {
+                let content_encoding = get_content_encoding(&response);
+                match response.bytes() {
+                    Ok(bytes) => decode_tracker_response(content_encoding, &bytes)?,
+                    Err(_) => return Err(anyhow!("could not read response from tracker")),
+                }
+            }
+            Err(_) => return Err(anyhow!("could not send request to tracker")),
+        };
+
+        // Deserialize bencoded scrape response
+        let scrape_bencode = match de::from_bytes::<BencodeScrape>(&response) {
+            Ok(bencode) => bencode,
+            Err(_) => return Err(decode_error("scrape response", &response)),
+        };
+        if let Some(failure_reason) = scrape_bencode.failure_reason {
+            return Err(anyhow!("tracker refused scrape: {}", failure_reason));
+        }
+
+        // Get swarm state of the torrent, keyed by its raw 20-byte info hash
+        let file = match scrape_bencode
+            .files
+            .get(&ByteBuf::from(self.info_hash.clone()))
+        {
+            Some(file) => file,
+            None => return Err(anyhow!("tracker does not know torrent")),
+        };
+
+        // Check name reported by tracker, when sent
+        let name = file
+            .name
+            .as_ref()
+            .map(|name| String::from_utf8_lossy(name).into_owned());
+        if let Some(name) = &name {
+            if *name != self.name {
+                warn!(
+                    "Tracker {} reports torrent name {:?}, expected {:?}",
+                    announce, name, self.name
+                );
+            }
+        }
+
+        Ok(Scrape {
+            complete: file.complete,
+            incomplete: file.incomplete,
+            downloaded: file.downloaded,
+            name,
+        })
+    }
+
+    /// Build scrape URL, replacing `announce` by `scrape` in the last path segment.
+    ///
+    /// # Arguments
+    ///
+    /// * `announce` - The announce URL of the tracker.
+    ///
+    fn build_scrape_url(&self, announce: &str) -> Result<String> {
+        // Parse tracker URL
+        let mut url = match Url::parse(announce) {
+            Ok(url) => url,
+            Err(_) => return Err(anyhow!("could not parse tracker url")),
+        };
+
+        // Trackers support scrape by convention only when announce path ends with "announce"
+        let path = url.path().to_owned();
+        let (parent, last) = match path.rsplit_once('/') {
+            Some((parent, last)) if last.starts_with("announce") => (parent, last),
+            _ => return Err(anyhow!("tracker does not support scrape")),
+        };
+        url.set_path(&format!("{}/scrape{}", parent, &last["announce".len()..]));
+
+        // Add info hash
+        url.query_pairs_mut()
+            .encoding_override(Some(&|input| {
+                if input != "!" {
+                    Cow::Borrowed(input.as_bytes())
+                } else {
+                    Cow::Owned(self.info_hash.clone())
+                }
+            }))
+            .append_pair("info_hash", "!");
+
+        Ok(url.to_string())
+    }
+
+    /// Announce to trackers periodically in a new thread, until the done channel is disconnected.
+    ///
+    /// Peers announced are sent to the returned channel. On failure, the next announce is delayed
+    /// twice as long, up to a limit, and the delay is reset to the tracker interval on success.
+    /// Announces asked early never happen sooner than the minimum interval set by trackers.
+    ///
+    /// # Arguments
+    ///
+    /// * `done_chan` - The channel disconnected when announces must stop.
+    /// * `trigger_chan` - The channel asking for an early announce, when more peers are needed.
+    ///
+    fn start_announcer(
+        &self,
+        done_chan: Receiver<()>,
+        trigger_chan: Receiver<()>,
+    ) -> Receiver<Vec<Peer>> {
+        let (peers_chan, peers_recv): (Sender<Vec<Peer>>, Receiver<Vec<Peer>>) = unbounded();
+        let torrent = self.clone();
+
+        // Not joined, as a pending tracker request must not delay the end of download
+        thread::spawn(move || {
+            let port = torrent.config.listen_port.unwrap_or(PORT);
+            let mut delay = torrent.announce_interval;
+            let mut min_interval = torrent.announce_min_interval;
+            let mut last_announce = Instant::now();
+            loop {
+                // Wait until next announce, or until more peers are needed
+                select! {
+                    recv(done_chan) -> _ => return,
+                    recv(trigger_chan) -> trigger => {
+                        if trigger.is_err() {
+                            return;
+                        }
+
+                        // Wait for the minimum interval since last announce, to avoid being banned
+                        let wait = min_interval
+                            .unwrap_or(ANNOUNCE_MIN_INTERVAL)
+                            .saturating_sub(last_announce.elapsed());
+                        if !wait.is_zero() {
+                            info!("More peers needed, re-announce in {:?}", wait);
+                            if let Err(RecvTimeoutError::Disconnected) = done_chan.recv_timeout(wait) {
+                                return;
+                            }
+                        }
+                    },
+                    default(delay) => {},
+                }
+
+                let result = torrent.request_peers_from_trackers(torrent.peer_id.clone(), port);
+                last_announce = Instant::now();
+                while trigger_chan.try_recv().is_ok() {}
+                match result {
+                    Ok(announce) => {
+                        debug!("Re-announce got {:?} peers", announce.peers.len());
+                        delay = announce.interval;
+                        min_interval = announce.min_interval;
+                        if peers_chan.send(announce.peers).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        delay =
+                            (delay * 2).min(ANNOUNCE_BACKOFF_MAX.max(torrent.announce_interval));
+                        warn!("Re-announce failed: {}, retry in {:?}", e, delay);
+                    }
+                }
+            }
+        });
+
+        peers_recv
+    }
+
+    /// Build tracker URL.
+    ///
+    /// # Arguments
+    ///
+    /// * `announce` - The announce URL of the tracker.
+    /// * `peer_id` - Urlencoded 20-byte string used as a unique ID for the client.
+    /// * `port` - Port number that the client is listening on.
+    ///
+    fn build_tracker_url(&self, announce: &str, peer_id: Vec<u8>, port: u16) -> Result<String> {
+        // Parse tracker URL
+        let mut base_url = match Url::parse(announce) {
+            Ok(url) => url,
+            Err(_) => return Err(anyhow!("could not parse tracker url")),
+        };
+
+        // Add parameters to the tracker URL
+        base_url
+            // Add info hash
+            .query_pairs_mut()
+            .encoding_override(Some(&|input| {
+                if input != "!" {
+                    Cow::Borrowed(input.as_bytes())
+                } else {
+                    Cow::Owned(self.info_hash.clone())
+                }
+            }))
+            .append_pair("info_hash", "!");
+        base_url
+            // Add peer id
+            .query_pairs_mut()
+            .encoding_override(Some(&|input| {
+                if input != "!" {
+                    Cow::Borrowed(input.as_bytes())
+                } else {
+                    Cow::Owned(peer_id.clone())
+                }
+            }))
+            .append_pair("peer_id", "!");
+        base_url
+            .query_pairs_mut()
+            // Add port
+            .append_pair("port", &port.to_string())
+            // Add uploaded
+            .append_pair("uploaded", "0")
+            // Add downloaded
+            .append_pair("downloaded", "0")
+            // Add compact
+            .append_pair("compact", "1")
+            // Add left
+            .append_pair("left", &self.length.to_string());
+
+        Ok(base_url.to_string())
+    }
+
+    /// Create output files.
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - Path where to save the torrent, a directory for a multi-file torrent.
+    ///
+    pub fn create_files(&mut self, output: PathBuf) -> Result<()> {
+        create_files(&output, &self.files)?;
+        self.output = output;
+
+        Ok(())
+    }
+
+    /// Check files already at the output path can be resumed, rather than overwritten.
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - Path where to save the torrent, a directory for a multi-file torrent.
+    ///
+    pub fn check_existing_files(&self, output: &Path) -> Result<()> {
+        check_existing_files(output, &self.files)
+    }
+
+    /// Write a downloaded piece to output files.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The zero-based piece index.
+    /// * `data` - The piece data.
+    ///
+    fn write_piece(&self, index: u32, data: &[u8]) -> Result<()> {
+        write_files(&self.output, &self.files, self.piece_offset(index), data)
+    }
+
+    /// Write pieces held in memory to output files, by runs of consecutive pieces.
+    ///
+    /// # Arguments
+    ///
+    /// * `memory` - The pieces held in memory.
+    ///
+    fn write_memory_pieces(&self, memory: &MemoryPieces) -> Result<()> {
+        let nb_pieces = self.pieces_hashes.len() as u32;
+        let mut index = 0;
+        while index < nb_pieces {
+            if !memory.pieces.has_piece(index) {
+                index += 1;
+                continue;
+            }
+
+            // Find the end of the run of pieces held in memory
+            let first = index;
+            while index < nb_pieces && memory.pieces.has_piece(index) {
+                index += 1;
+            }
+            let begin = self.piece_offset(first);
+            let end = self.piece_offset(index - 1) + self.get_piece_length(index - 1)? as u64;
+            write_files(
+                &self.output,
+                &self.files,
+                begin,
+                &memory.data[begin as usize..end as usize],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Read a block of a piece from output files.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The zero-based piece index.
+    /// * `begin` - The zero-based byte offset within the piece.
+    /// * `length` - The requested length.
+    ///
+    pub fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>> {
+        // Check piece index
+        if index as usize >= self.pieces_hashes.len() {
+            return Err(anyhow!("invalid piece index {:?}", index));
+        }
+
+        // Check range within piece
+        let piece_length = self.get_piece_length(index)?;
+        match begin.checked_add(length) {
+            Some(end) if end <= piece_length => {}
+            _ => return Err(anyhow!("invalid block range within piece {:?}", index)),
+        }
+
+        // Read block at offset within torrent data
+        let offset = self.piece_offset(index) + begin as u64;
+        read_files(&self.output, &self.files, offset, length as u64)
+    }
+
+    /// Build web seeds of both kinds, BEP 17 scripts first, discarding those that can not serve files.
+    fn build_http_seeds(&self) -> Vec<(String, HttpSeedKind)> {
+        let mut seeds: Vec<(String, HttpSeedKind)> = self
+            .httpseeds
+            .iter()
+            .map(|url| (url.to_owned(), HttpSeedKind::Script))
+            .collect();
+        for url in self.url_list.iter() {
+            match build_file_urls(url, &self.name, &self.files) {
+                Ok(files) => seeds.push((
+                    url.to_owned(),
+                    HttpSeedKind::Files {
+                        piece_length: self.piece_length as u64,
+                        files,
+                    },
+                )),
+                Err(e) => debug!("Discard web seed {}: {}", url, e),
+            }
+        }
+
+        seeds
+    }
+
+    /// Get number of hashing threads, one per core up to a limit unless it was set.
+    fn hash_threads(&self) -> usize {
+        self.config.hash_threads.unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+                .min(MAX_HASH_THREADS)
+        })
+    }
+
+    /// Get maximum number of pieces downloaded simultaneously, whatever the number of peers.
+    ///
+    /// The number is lowered so data buffers of pieces in flight fit in the maximum memory, but
+    /// never below one piece so download can always progress.
+    ///
+    fn max_inflight_pieces(&self) -> usize {
+        let mut max_inflight_pieces = self.config.max_inflight_pieces;
+        if let Some(max_memory) = self.config.max_memory {
+            let max_buffers = max_memory / self.piece_length.max(1) as u64;
+            max_inflight_pieces = max_inflight_pieces.min(max_buffers as usize);
+        }
+        max_inflight_pieces.max(1)
+    }
+
+    /// Build bitfield of pieces already saved to output files, verifying their integrity.
+    ///
+    /// Pieces recorded in the resume state of a previous download are trusted, otherwise output
+    /// files are read sequentially by batches of pieces, hashed on several threads.
+    ///
+    fn build_resume_bitfield(&self) -> Result<Bitfield> {
+        let nb_pieces = self.pieces_hashes.len() as u32;
+
+        // Trust pieces recorded by a previous download, unless resume state can not be trusted
+        let path = resume_path(&self.output);
+        match load_resume_state(&path, &self.info_hash, nb_pieces, &self.output, &self.files) {
+            Ok(bitfield) => {
+                info!(
+                    "Resume pieces recorded in {:?}, skip verifying saved data",
+                    path
+                );
+                return Ok(bitfield);
+            }
+            Err(e) => info!("Verify saved data, {}", e),
+        }
+
+        let mut bitfield = Bitfield::new(nb_pieces);
+        let batch = u32::try_from(self.config.verify_batch.max(1)).unwrap_or(u32::MAX);
+        let hash_threads = self.hash_threads().max(1);
+
+        // Create verifying progress bar, hidden in quiet mode
+        let pb = self.build_progress_bar();
+        pb.set_style(
+            ProgressStyle::default_bar()
+                .template(
+                    "{spinner:.green} {prefix}Verifying {bytes}/{total_bytes} [{bar:40.cyan/blue}] {percent}%",
+                )
+                .unwrap()
+                .progress_chars("#>-"),
+        );
+
+        // Batches of pieces read from output files, and indexes of pieces matching their hash
+        let (batch_chan, batch_recv) = bounded::<(u32, Vec<u8>)>(hash_threads);
+        let (valid_chan, valid_recv) = unbounded::<(u32, bool)>();
+
+        let read = thread::scope(|scope| -> Result<()> {
+            // Hash pieces of each batch, until every batch is read
+            for _ in 0..hash_threads {
+                let batch_recv = batch_recv.clone();
+                let valid_chan = valid_chan.clone();
+                scope.spawn(move || {
+                    for (first, data) in batch_recv.iter() {
+                        for (i, piece) in data.chunks(self.piece_length as usize).enumerate() {
+                            let index = first + i as u32;
+                            let valid = match sha1(piece) {
+                                Ok(hash) => hash == self.pieces_hashes[index as usize],
+                                Err(_) => false,
+                            };
+                            if valid_chan.send((index, valid)).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+            drop(valid_chan);
+
+            // Read batches of pieces sequentially, stopping hashing threads once done
+            let reader = scope.spawn(move || -> Result<()> {
+                for first in (0..nb_pieces).step_by(batch as usize) {
+                    let last = first.saturating_add(batch).min(nb_pieces) - 1;
+                    let begin = self.piece_offset(first);
+                    let end = self.piece_offset(last) + self.get_piece_length(last)? as u64;
+                    let data = read_files(&self.output, &self.files, begin, end - begin)?;
+                    if batch_chan.send((first, data)).is_err() {
+                        return Err(anyhow!("could not send pieces to verify"));
+                    }
+                }
+                Ok(())
+            });
+
+            // Keep pieces matching their hash
+            for (index, valid) in valid_recv.iter() {
+                if valid {
+                    bitfield.set_piece(index);
+                }
+                pb.inc(self.get_piece_length(index)? as u64);
+            }
+
+            match reader.join() {
+                Ok(read) => read,
+                Err(_) => Err(anyhow!("could not read pieces to verify")),
+            }
+        });
+        pb.finish_and_clear();
+        read?;
+
+        Ok(bitfield)
+    }
+
+    /// Build a progress bar of the torrent length, hidden in quiet mode.
+    ///
+    /// The progress bar is added to the shared progress display and prefixed by torrent name, when set.
+    ///
+    fn build_progress_bar(&self) -> ProgressBar {
+        if self.config.quiet || !self.config.progress_bar {
+            return ProgressBar::hidden();
+        }
+
+        match &self.multi_progress {
+            Some(multi_progress) => {
+                let pb = multi_progress.add(ProgressBar::new(self.length));
+                pb.set_prefix(format!("{} ", self.name));
+                pb
+            }
+            None => ProgressBar::new(self.length),
+        }
+    }
+
+    /// Print a line about the download, unless in quiet mode, keeping stdout for downloaded data.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The line to print.
+    ///
+    fn print_line(&self, line: &str) {
+        if self.config.quiet {
+            return;
+        }
+
+        match &self.multi_progress {
+            Some(multi_progress) if !self.config.stdout => {
+                multi_progress.suspend(|| println!("{}", line))
+            }
+            _ if self.config.stdout => eprintln!("{}", line),
+            _ => println!("{}", line),
+        }
+    }
+
+    /// Print progress as a plain line, when it is not drawn as a progress bar.
+    ///
+    /// # Arguments
+    ///
+    /// * `length` - The number of bytes to download.
+    ///
+    fn print_progress_line(&self, length: u64) {
+        if self.config.progress_bar {
+            return;
+        }
+
+        let saved = self.stats.saved();
+        let percent = (saved * 100).checked_div(length).unwrap_or(100);
+        self.print_line(&format!(
+            "Downloaded {}% of {:?} ({}/{}), {}/s, {:?} peers",
+            percent,
+            self.name,
+            HumanBytes(saved),
+            HumanBytes(length),
+            HumanBytes(self.stats.rate()),
+            self.stats.connected_peers()
+        ));
+    }
+
+    /// Build bitfield of pieces to download, every piece unless a byte range was set.
+    fn build_wanted_pieces(&self) -> Result<Bitfield> {
+        let nb_pieces = self.pieces_hashes.len() as u32;
+        let mut wanted = Bitfield::new(nb_pieces);
+        let (first, last) = match self.range {
+            // Pieces overlapping the range, boundaries included
+            Some((begin, end)) => {
+                if self.files.len() > 1 {
+                    return Err(anyhow!(
+                        "invalid range, only supported for a single-file torrent"
+                    ));
+                }
+                if begin > end || end >= self.length {
+                    return Err(anyhow!(
+                        "invalid range {}-{}, torrent has {:?} bytes",
+                        begin,
+                        end,
+                        self.length
+                    ));
+                }
+                let piece_length = self.piece_length as u64;
+                let first = (begin / piece_length) as u32;
+                let last = (end / piece_length) as u32;
+                info!(
+                    "Download pieces {:?} to {:?} of range {}-{}",
+                    first, last, begin, end
+                );
+                (first, last)
+            }
+            None if nb_pieces == 0 => return Ok(wanted),
+            None => (0, nb_pieces - 1),
+        };
+        for index in first..=last {
+            wanted.set_piece(index);
+        }
+
+        Ok(wanted)
+    }
+
+    /// Build the order in which pieces are downloaded, pieces of the priority file first and in order.
+    ///
+    /// Other pieces follow rarest first, pieces no connected peer has coming last.
+    ///
+    /// # Arguments
+    ///
+    /// * `availability` - The number of connected peers having each piece.
+    ///
+    fn build_piece_order(&self, availability: &[u32]) -> Result<Vec<u32>> {
+        let nb_pieces = self.pieces_hashes.len() as u32;
+        let priority_file = match &self.priority_file {
+            Some(priority_file) => priority_file,
+            None => return Ok((0..nb_pieces).collect()),
+        };
+
+        // Find priority file, the file of a single-file torrent being named after the torrent
+        let file = match self.files.iter().find(|file| {
+            file.path == *priority_file
+                || (file.path.as_os_str().is_empty() && *priority_file == Path::new(&self.name))
+        }) {
+            Some(file) => file,
+            None => {
+                return Err(anyhow!(
+                    "could not find file {:?} in torrent",
+                    priority_file
+                ))
+            }
+        };
+
+        // Get pieces holding data of the priority file
+        let piece_length = self.piece_length as u64;
+        let first = (file.offset / piece_length) as u32;
+        let end = ((file.offset + file.length).div_ceil(piece_length) as u32).min(nb_pieces);
+        debug!(
+            "Download pieces {:?} to {:?} of file {:?} first",
+            first,
+            end.saturating_sub(1),
+            priority_file
+        );
+
+        // Download other pieces afterwards, rarest first and in index order among equals
+        let mut others: Vec<u32> = (0..nb_pieces)
+            .filter(|index| *index < first || *index >= end)
+            .collect();
+        others.sort_by_key(|index| match availability.get(*index as usize) {
+            Some(0) | None => u32::MAX,
+            Some(count) => *count,
+        });
+        let mut order: Vec<u32> = (first..end).collect();
+        order.extend(others);
+
+        Ok(order)
+    }
+
+    /// Queue pieces waiting in work channel again in download order, as pieces availability changes.
+    ///
+    /// # Arguments
+    ///
+    /// * `work_chan` - The channel of work pieces.
+    ///
+    fn reorder_work_pieces(
+        &self,
+        work_chan: &(Sender<PieceWork>, Receiver<PieceWork>),
+    ) -> Result<()> {
+        let availability = self.progress.snapshot()?.availability;
+        let order = self.build_piece_order(&availability)?;
+        let mut rank: Vec<usize> = vec![0; order.len()];
+        for (i, index) in order.iter().enumerate() {
+            rank[*index as usize] = i;
+        }
+
+        // Take only pieces queued now, as workers keep resending pieces meanwhile
+        let nb_queued = work_chan.1.len();
+        let mut pieces: Vec<PieceWork> = work_chan.1.try_iter().take(nb_queued).collect();
+        pieces.sort_by_key(|piece_work| rank.get(piece_work.index as usize).copied());
+        for piece_work in pieces {
+            if work_chan.0.send(piece_work).is_err() {
+                return Err(anyhow!("could not send piece to channel"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Download torrent, saving pieces to output files as they arrive.
+    pub fn download(&self) -> Result<()> {
+        // Check torrent was opened, rather than waiting for pieces that do not exist
+        if !self.is_loaded() {
+            return Err(anyhow!("torrent not loaded"));
+        }
+
+        self.print_line(&format!(
+            "Downloading {:?} ({:?} pieces)",
+            self.name,
+            self.pieces_hashes.len(),
+        ));
+
+        // Check disk space, failing early instead of midway
+        if self.config.check_space && self.saves_files() {
+            check_space(&self.output, &self.files)?;
+        }
+
+        // Allocate output files, failing early if disk space is missing
+        if self.config.preallocate && self.saves_files() {
+            preallocate_files(&self.output, &self.files)?;
+        }
+
+        // Create bitfield of pieces saved to disk, resuming verified pieces
+        let nb_pieces = self.pieces_hashes.len() as u32;
+        let resumed: Bitfield = if !self.saves_files() {
+            Bitfield::new(nb_pieces)
+        } else {
+            self.build_resume_bitfield()?
+        };
+        let wanted: Bitfield = self.build_wanted_pieces()?;
+        let nb_pieces_wanted = wanted.count_pieces(nb_pieces) as usize;
+        let nb_pieces_resumed = (0..nb_pieces)
+            .filter(|&index| wanted.has_piece(index) && resumed.has_piece(index))
+            .count();
+        if nb_pieces_resumed > 0 {
+            info!(
+                "Resume download with {:?} of {:?} pieces already saved",
+                nb_pieces_resumed, nb_pieces_wanted
+            );
+        }
+        let have: Arc<Mutex<Bitfield>> = Arc::new(Mutex::new(resumed.clone()));
+        if nb_pieces_resumed == nb_pieces_wanted {
+            self.forget_resume_state(&have)?;
+            return Ok(());
+        }
+
+        // Follow state of pieces and transferred bytes, starting from resumed pieces
+        self.progress.reset(nb_pieces);
+        self.stats.reset();
+        let mut wanted_length: u64 = 0;
+        for index in (0..nb_pieces).filter(|&index| wanted.has_piece(index)) {
+            let piece_length = self.get_piece_length(index)? as u64;
+            wanted_length += piece_length;
+            if resumed.has_piece(index) {
+                self.progress.set_state(index, PieceState::Complete);
+                self.stats.add_saved(piece_length);
+            }
+        }
+
+        // Accept incoming connections, until download returns
+        let _listener: Option<ListenerHandle> = match self.config.listen_port {
+            Some(port) => Some(
+                Listener::new(
+                    port,
+                    Arc::new(self.clone()),
+                    self.peer_id.clone(),
+                    self.info_hash.clone(),
+                    nb_pieces,
+                    have.clone(),
+                )?
+                .start(),
+            ),
+            None => None,
+        };
+
+        // Create work pieces channel
+        let work_chan: (Sender<PieceWork>, Receiver<PieceWork>) = unbounded();
+
+        // Create result pieces channel
+        let result_chan: (Sender<PieceResult>, Receiver<PieceResult>) = unbounded();
+
+        // Create and send missing pieces to work channel, in download order while no peer is connected
+        for piece_index in self.build_piece_order(&[])? {
+            // Skip piece already saved to disk, or outside of the range to download
+            if resumed.has_piece(piece_index) || !wanted.has_piece(piece_index) {
+                continue;
+            }
+
+            // Create piece
+            let piece_hash = self.pieces_hashes[piece_index as usize].clone();
+            let piece_length = self.get_piece_length(piece_index)?;
+            let piece_work = PieceWork::new(piece_index, piece_hash, piece_length);
+
+            // Send piece to work channel
+            if work_chan.0.send(piece_work).is_err() {
+                return Err(anyhow!("Error: could not send piece to channel"));
+            }
+        }
+
+        // Create failed pieces channel
+        let fail_chan: (Sender<u32>, Receiver<u32>) = unbounded();
+
+        // Create in-flight piece slots channel, bounding memory of pieces data whatever the number of peers
+        let max_inflight_pieces = self.max_inflight_pieces();
+        let inflight_chan: (Sender<()>, Receiver<()>) = bounded(max_inflight_pieces);
+        for _ in 0..max_inflight_pieces {
+            if inflight_chan.0.send(()).is_err() {
+                return Err(anyhow!("Error: could not send slot to channel"));
+            }
+        }
+
+        // Hold back pieces too far ahead of the next piece written to stdout, pieces buffered ahead
+        // keeping their in-flight slot, so the next piece can always take a slot. Pieces are written
+        let window = max_inflight_pieces as u32;
+        // from the first wanted piece, so pieces outside the range are neither awaited nor written
+        let mut ordered: Option<OrderedPieces> = if self.config.stdout {
+            let ordered = OrderedPieces::new(
+                wanted.clone(),
+                nb_pieces,
+                self.piece_length as u64,
+                self.range,
+            );
+            self.progress
+                .set_window_end(Some(ordered.window_end(window)));
+            Some(ordered)
+        } else {
+            None
+        };
+
+        // Create connection and connected peer slots channels, limiting simultaneous connection
+        // attempts and peers downloaded from at once, shared with other torrents when set
+        let connect_limit = match &self.connect_limit {
+            Some(connect_limit) => connect_limit.clone(),
+            None => ConnectLimit::from_config(&self.config),
+        };
+        let connect_chan = connect_limit.chan.clone();
+        let peer_chan = connect_limit.peers.clone();
+
+        let peers = self.build_connect_order();
+        debug!(
+            "Connect to peers in order {:?}",
+            peers
+                .iter()
+                .map(|peer| peer.to_string())
+                .collect::<Vec<String>>()
+        );
+
+        // Init hashing threads, stopped when download returns once workers are stopped
+        let verifier = VerifyPool::new(
+            self.hash_threads(),
+            nb_pieces,
+            work_chan.0.clone(),
+            result_chan.0.clone(),
+            fail_chan.0.clone(),
+            self.config.max_piece_failures,
+            self.progress.clone(),
+        );
+
+        // Init workers, stopped when download returns
+        let mut workers = WorkerPool::new();
+        let spawn_worker = |peer: Peer, workers: &mut WorkerPool| -> Result<()> {
+            let peer_copy = peer.clone();
+            let peer_id_copy = self.peer_id.clone();
+            let info_hash_copy = self.info_hash.clone();
+            let work_chan_copy = work_chan.clone();
+            let inflight_chan_copy = inflight_chan.clone();
+            let connect_chan_copy = connect_chan.clone();
+            let peer_chan_copy = peer_chan.clone();
+
+            // Create new worker
+            let worker = Worker::new(
+                peer_copy,
+                peer_id_copy,
+                info_hash_copy,
+                nb_pieces,
+                self.config.clone(),
+                work_chan_copy,
+                verifier.jobs(),
+                inflight_chan_copy,
+                connect_chan_copy,
+                peer_chan_copy,
+                have.clone(),
+                self.paused.clone(),
+                workers.done_chan(),
+                workers.conns(),
+                self.progress.clone(),
+                self.stats.clone(),
+            )?;
+
+            // Start worker in a new thread
+            workers.spawn(worker);
+
+            Ok(())
+        };
+        let mut known_peers: Vec<SocketAddr> = peers.iter().map(|peer| peer.addr()).collect();
+        for peer in peers {
+            spawn_worker(peer, &mut workers)?;
+        }
+
+        // Announce to trackers periodically, to find new peers during download
+        let (trigger, trigger_chan): (Sender<()>, Receiver<()>) = bounded(1);
+        let announced_chan = self.start_announcer(workers.done_chan(), trigger_chan);
+        let mut starved_since = Instant::now();
+        let mut lost_since: Option<Instant> = None;
+
+        // Init web seeds, downloading whole pieces alongside peers, with IDs counted down from
+        // the last one so they never match a peer ID
+        for (i, (url, kind)) in self.build_http_seeds().into_iter().enumerate() {
+            let seed = HttpSeed::new(
+                u32::MAX - i as u32,
+                url,
+                kind,
+                self.info_hash.clone(),
+                self.config.bind,
+                work_chan.clone(),
+                verifier.jobs(),
+                inflight_chan.clone(),
+                self.paused.clone(),
+                workers.done_chan(),
+                self.progress.clone(),
+                self.stats.clone(),
+            );
+
+            // Start web seed in a new thread
+            workers.spawn_http_seed(seed);
+        }
+
+        // Create progress bar, hidden in quiet mode
+        let pb = self.build_progress_bar();
+        pb.set_length(wanted_length);
+        pb.set_style(
+            ProgressStyle::default_bar()
+                .template(
+                    "{spinner:.green} {prefix}{bytes}/{total_bytes} [{bar:40.cyan/blue}] {percent}% {msg}",
+                )
+                .unwrap()
+                .progress_chars("#>-"),
+        );
+
+        // Hold pieces in memory until download returns, when asked to
+        let mut memory: Option<MemoryPieces> = if self.config.in_memory && self.saves_files() {
+            Some(MemoryPieces {
+                data: vec![0; self.length as usize],
+                pieces: Bitfield::new(nb_pieces),
+            })
+        } else {
+            None
+        };
+
+        // Save pieces to output files
+        let mut nb_pieces_downloaded = nb_pieces_resumed;
+        let mut last_recorded = Instant::now();
+        let mut last_printed = Instant::now();
+        let mut last_reordered = Instant::now();
+        while nb_pieces_downloaded < nb_pieces_wanted {
+            // Update progress bar from stats
+            self.stats.sample();
+            pb.set_position(self.stats.saved());
+            pb.set_message(format!(
+                "{}/s, {:?} peers",
+                HumanBytes(self.stats.rate()),
+                self.stats.connected_peers()
+            ));
+
+            // Print progress once in a while, when there is no progress bar
+            if last_printed.elapsed() >= PROGRESS_LINE_INTERVAL {
+                self.print_progress_line(wanted_length);
+                last_printed = Instant::now();
+            }
+
+            // Queue pieces outside the priority file rarest first, as peers announce their pieces
+            if self.priority_file.is_some() && last_reordered.elapsed() >= PIECE_ORDER_INTERVAL {
+                self.reorder_work_pieces(&work_chan)?;
+                last_reordered = Instant::now();
+            }
+
+            // Announce early once no peer is connected for a while
+            if self.stats.connected_peers() > 0 {
+                starved_since = Instant::now();
+            } else if starved_since.elapsed() >= STARVATION_DELAY {
+                info!("No peer connected, ask trackers for more peers");
+                let _ = trigger.try_send(());
+                starved_since = Instant::now();
+            }
+
+            // Give up once every worker and web seed stopped and trackers announced no new peer
+            // for a while, keeping pieces saved to disk so download can be retried
+            if !workers.all_stopped() {
+                lost_since = None;
+            } else {
+                match lost_since {
+                    None => {
+                        info!("No peer left to download from, ask trackers for more peers");
+                        let _ = trigger.try_send(());
+                        lost_since = Some(Instant::now());
+                    }
+                    Some(since) if since.elapsed() >= PEERS_LOST_TIMEOUT => {
+                        if let Some(memory) = &memory {
+                            self.write_memory_pieces(memory)?;
+                        }
+                        self.record_saved_pieces(&have);
+                        return Err(anyhow!(
+                            "no peer left to download from, trackers announced no new peer within {:?}",
+                            PEERS_LOST_TIMEOUT
+                        ));
+                    }
+                    Some(_) => {}
+                }
+            }
+
+            // Receive a piece from result channel, or abort on a failed piece
+            let piece_result: PieceResult = select! {
+                recv(result_chan.1) -> piece_result => match piece_result {
+                    Ok(piece_result) => piece_result,
+                    Err(_) => return Err(anyhow!("Error: could not receive piece from channel")),
+                },
+                recv(fail_chan.1) -> index => match index {
+                    Ok(index) => {
+                        // Keep pieces verified so far
+                        if let Some(memory) = &memory {
+                            self.write_memory_pieces(memory)?;
+                        }
+                        self.record_saved_pieces(&have);
+                        return Err(anyhow!(
+                            "could not verify piece {:?}, corrupted by {:?} peers",
+                            index,
+                            self.config.max_piece_failures
+                        ))
+                    }
+                    Err(_) => return Err(anyhow!("Error: could not receive piece from channel")),
+                },
+                recv(announced_chan) -> announced => {
+                    // Start workers for peers not known yet, keeping IDs unique
+                    let mut announced = announced.unwrap_or_default();
+                    if self.config.prefer_local {
+                        announced.sort_by_key(|peer| !peer.is_local());
+                    }
+                    for mut peer in announced {
+                        if known_peers.contains(&peer.addr()) {
+                            continue;
+                        }
+                        info!("Found new peer {} from tracker", peer);
+                        peer.id = known_peers.len() as u32;
+                        known_peers.push(peer.addr());
+                        spawn_worker(peer, &mut workers)?;
+                    }
+                    continue;
+                },
+                default(STOP_POLL_INTERVAL) => {
+                    // Stop download, keeping pieces saved to disk
+                    if self.stop.load(Ordering::SeqCst) {
+                        if let Some(memory) = &memory {
+                            self.write_memory_pieces(memory)?;
+                        }
+                        self.record_saved_pieces(&have);
+                        return Err(anyhow!(
+                            "download stopped, {:?} of {:?} pieces saved",
+                            nb_pieces_downloaded,
+                            self.pieces_hashes.len()
+                        ));
+                    }
+                    continue;
+                },
+            };
+
+            // Discard a duplicate of a piece already saved, so it is not counted twice
+            match have.lock() {
+                Ok(have) if have.has_piece(piece_result.index) => {
+                    debug!("Discard duplicate of piece {:?}", piece_result.index);
+                    continue;
+                }
+                Ok(_) => {}
+                Err(_) => return Err(anyhow!("could not read bitfield")),
+            }
+
+            // Write piece data or hold it in memory, unless it is discarded or written to stdout
+            match memory.as_mut() {
+                Some(memory) => memory.insert(
+                    piece_result.index,
+                    self.piece_offset(piece_result.index),
+                    &piece_result.data,
+                )?,
+                None if self.saves_files() => {
+                    self.write_piece(piece_result.index, &piece_result.data)?
+                }
+                None => {}
+            }
+
+            // Write pieces to stdout in order, buffering pieces ahead with their in-flight slot
+            let index = piece_result.index;
+            let length = piece_result.length;
+            match ordered.as_mut() {
+                Some(ordered) => {
+                    ordered.push(piece_result, &mut io::stdout().lock())?;
+                    self.progress
+                        .set_window_end(Some(ordered.window_end(window)));
+                }
+                // Give back in-flight slot, piece data being written or discarded
+                None => drop(piece_result),
+            }
+
+            // Make piece available to peers
+            match have.lock() {
+                Ok(mut have) => have.set_piece(index),
+                Err(_) => return Err(anyhow!("could not update bitfield")),
+            }
+            self.progress.set_state(index, PieceState::Complete);
+
+            // Record pieces saved to output files once in a while, to resume without verifying
+            if memory.is_none() && last_recorded.elapsed() >= RESUME_SAVE_INTERVAL {
+                self.record_saved_pieces(&have);
+                last_recorded = Instant::now();
+            }
+
+            // Count verified piece
+            self.stats.add_saved(length as u64);
+
+            // Update number of pieces downloaded
+            nb_pieces_downloaded += 1;
+        }
+
+        self.print_progress_line(wanted_length);
+
+        // Write pieces held in memory at once
+        if let Some(memory) = &memory {
+            self.write_memory_pieces(memory)?;
+        }
+        self.forget_resume_state(&have)?;
+
+        Ok(())
+    }
+
+    /// Forget resume state once every piece is saved, or record saved pieces when only a range is.
+    ///
+    /// # Arguments
+    ///
+    /// * `have` - The bitfield of pieces saved to output files.
+    ///
+    fn forget_resume_state(&self, have: &Arc<Mutex<Bitfield>>) -> Result<()> {
+        if !self.saves_files() {
+            return Ok(());
+        }
+        if self.range.is_some() {
+            self.record_saved_pieces(have);
+            return Ok(());
+        }
+
+        remove_resume_state(&resume_path(&self.output))
+    }
+
+    /// Record pieces saved to output files in the resume state, once their data is on disk.
+    ///
+    /// A resume state that could not be saved only makes the next download verify saved data.
+    ///
+    /// # Arguments
+    ///
+    /// * `have` - The bitfield of pieces saved to output files.
+    ///
+    fn record_saved_pieces(&self, have: &Arc<Mutex<Bitfield>>) {
+        if !self.saves_files() {
+            return;
+        }
+        let bitfield: Bitfield = match have.lock() {
+            Ok(have) => have.clone(),
+            Err(_) => return,
+        };
+        let recorded = sync_files(&self.output, &self.files).and_then(|_| {
+            save_resume_state(&resume_path(&self.output), &self.info_hash, &bitfield)
+        });
+        if let Err(e) = recorded {
+            warn!("Could not record saved pieces: {}", e);
+        }
+    }
+
+    /// Check if downloaded pieces are saved to output files.
+    fn saves_files(&self) -> bool {
+        !self.config.discard && !self.config.stdout
+    }
+
+    /// Get the offset of a piece within the torrent data.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The piece index.
+    ///
+    fn piece_offset(&self, index: u32) -> u64 {
+        index as u64 * self.piece_length as u64
+    }
+
+    /// Get piece length.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The piece index.
+    ///
+    fn get_piece_length(&self, index: u32) -> Result<u32> {
+        let begin: u64 = self.piece_offset(index);
+        let mut end: u64 = begin + self.piece_length as u64;
+
+        // Prevent unbounded values
+        if end > self.length {
+            end = self.length;
+        }
+        if begin > end {
+            return Err(anyhow!("invalid piece index {:?}", index));
+        }
+
+        Ok((end - begin) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn download_unloaded_torrent() {
+        let torrent = Torrent::new();
+        let error = torrent.download().unwrap_err();
+        assert_eq!(error.to_string(), "torrent not loaded");
+    }
+
+    #[test]
+    fn decode_compressed_tracker_responses() {
+        let body = b"d8:intervali1800e5:peers0:e";
+        let compressed = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0xff, 0x4b, 0xb1, 0xb0, 0xca,
+            0xcc, 0x2b, 0x49, 0x2d, 0x2a, 0x4b, 0xcc, 0xc9, 0x34, 0xb4, 0x30, 0x30, 0x48, 0x35,
+            0xb5, 0x2a, 0x48, 0x4d, 0x2d, 0x2a, 0x36, 0xb0, 0x4a, 0x05, 0x00, 0xab, 0xd9, 0x98,
+            0x92, 0x1b, 0x00, 0x00, 0x00,
+        ];
+
+        // Uncompressed and compressed responses decode to the same body
+        assert_eq!(decode_tracker_response(None, body).unwrap(), body);
+        assert_eq!(
+            decode_tracker_response(Some("gzip".to_owned()), &compressed).unwrap(),
+            body
+        );
+        assert_eq!(decode_tracker_response(None, &compressed).unwrap(), body);
+
+        // Truncated data, or data inflating beyond the limit, is rejected
+        assert!(decompress_gzip(&compressed[..40], 1 << 20).is_err());
+        let error = decompress_gzip(&compressed, 16).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "could not decompress gzip data, larger than 16 bytes"
+        );
+
+        // Mislabeled or unsupported encodings are reported
+        let error = decode_tracker_response(Some("gzip".to_owned()), body).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "tracker response is encoded as gzip but is not gzip data"
+        );
+        let error = decode_tracker_response(Some("br".to_owned()), &compressed).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "tracker response has unsupported content encoding \"br\""
+        );
+    }
+
+    #[test]
+    fn quote_tracker_responses_not_bencoded() {
+        let error = decode_error("tracker response", b"d8:intervali1800e");
+        assert_eq!(error.to_string(), "could not decode tracker response");
+
+        let body = b"{\"error\": \"rate limited\",\n  \"retry\": 60}";
+        let error = decode_error("tracker response", body);
+        assert_eq!(
+            error.to_string(),
+            r#"could not decode tracker response, not bencoded, tracker answered "{\"error\": \"rate limited\", \"retry\": 60}""#
+        );
+
+        // Long responses are cut
+        let body = format!("<html>{}</html>", "x".repeat(500));
+        let error = decode_error("scrape response", body.as_bytes()).to_string();
+        assert!(error.ends_with(&format!("<html>{}...\"", "x".repeat(194))));
+    }
+
+    #[test]
+    fn share_connect_limit_between_torrents() {
+        let connect_limit = ConnectLimit::new(2, 1);
+        let mut first = Torrent::new();
+        let mut second = Torrent::new();
+        first.set_connect_limit(connect_limit.clone());
+        second.set_connect_limit(connect_limit);
+
+        // Slots taken for a torrent are missing for the other
+        let first_chan = first.connect_limit.as_ref().unwrap().chan.clone();
+        let second_chan = second.connect_limit.as_ref().unwrap().chan.clone();
+        assert!(first_chan.1.try_recv().is_ok());
+        assert!(second_chan.1.try_recv().is_ok());
+        assert!(first_chan.1.try_recv().is_err());
+        assert!(second_chan.1.try_recv().is_err());
+
+        // Slots freed for a torrent are available to the other
+        first_chan.0.send(()).unwrap();
+        assert!(second_chan.1.try_recv().is_ok());
+
+        // Connected peers are counted across torrents too
+        let first_peers = first.connect_limit.as_ref().unwrap().peers.clone();
+        let second_peers = second.connect_limit.as_ref().unwrap().peers.clone();
+        assert!(first_peers.1.try_recv().is_ok());
+        assert!(second_peers.1.try_recv().is_err());
+        first_peers.0.send(()).unwrap();
+        assert!(second_peers.1.try_recv().is_ok());
+    }
+
+    #[test]
+    fn load_invalid_torrents() {
+        let error = |buf: &[u8]| Torrent::new().load_from_bytes(buf).unwrap_err().to_string();
+
+        assert_eq!(error(b""), "could not decode torrent, file is empty");
+        assert_eq!(
+            error(b"\n<!DOCTYPE html><html><body>404 Not Found</body></html>"),
+            "could not decode torrent, file is an HTML page rather than a valid torrent"
+        );
+        assert_eq!(
+            error(b"d8:announce"),
+            "could not decode torrent, file is not bencoded"
+        );
+        assert_eq!(
+            error(b"l4:infoe"),
+            "could not decode torrent, not a bencoded dictionary"
+        );
+        assert_eq!(
+            error(b"d8:announce9:http://t/e"),
+            "could not decode torrent, missing key \"info\""
+        );
+        assert_eq!(
+            error(b"d4:infod4:name8:data.bin6:pieces0:ee"),
+            "could not decode torrent, missing key \"piece length\""
+        );
+        assert_eq!(
+            error(b"d4:infod4:name8:data.bin6:lengthi4e6:pieces0:ee"),
+            "could not decode torrent, missing key \"piece length\""
+        );
+        assert_eq!(
+            error(b"d4:infod4:name8:data.bin12:piece lengthi16384e6:pieces0:ee"),
+            "could not decode torrent, missing key \"length\" or \"files\""
+        );
+        assert_eq!(
+            error(b"d4:infod5:filesld6:lengthi4eee4:name3:dir12:piece lengthi16384e6:pieces0:ee"),
+            "could not decode torrent, missing key \"path\" in file 0"
+        );
+        assert_eq!(
+            error(
+                b"d4:infod9:file treede12:meta versioni2e4:name8:data.bin12:piece lengthi16384eee"
+            ),
+            "could not decode torrent, BitTorrent v2 is not supported"
+        );
+    }
+
+    #[test]
+    fn name_invalid_integers() {
+        let error = |buf: &[u8]| Torrent::new().load_from_bytes(buf).unwrap_err().to_string();
+
+        assert_eq!(
+            error(b"d4:infod6:lengthi99999999999999999999e4:name1:a12:piece lengthi1e6:pieces0:ee"),
+            "could not decode torrent, key \"length\" has value 99999999999999999999 too wide for 64 bits"
+        );
+        assert_eq!(
+            error(b"d4:infod6:lengthi4e4:name1:a12:piece lengthi4294967296e6:pieces0:ee"),
+            "could not decode torrent, key \"piece length\" has value 4294967296 out of range 0 to 4294967295"
+        );
+        assert_eq!(
+            error(b"d4:infod5:filesld6:lengthi-1e4:pathl1:beee4:name1:a12:piece lengthi1e6:pieces0:ee"),
+            "could not decode torrent, key \"length\" has value -1 out of range 0 to 18446744073709551615 in file 0"
+        );
+        assert_eq!(
+            error(b"d4:infod6:length1:44:name1:a12:piece lengthi1e6:pieces0:ee"),
+            "could not decode torrent, key \"length\" is not an integer"
+        );
+    }
+
+    #[test]
+    fn reject_invalid_torrents_before_announce() {
+        use std::net::TcpListener;
+
+        // Tracker that must never be contacted
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let tracker = format!("http://{}/announce", listener.local_addr().unwrap());
+        let torrent = |length: usize, nb_hashes: usize| {
+            format!(
+                "d8:announce{}:{}4:infod6:lengthi{}e4:name1:a12:piece lengthi4e6:pieces{}:{}ee",
+                tracker.len(),
+                tracker,
+                length,
+                nb_hashes * 20,
+                "x".repeat(nb_hashes * 20)
+            )
+        };
+
+        // Oversized torrent, and torrent whose pieces do not match its length, are not loaded
+        let config = TorrentConfig::builder().max_size(4).build().unwrap();
+        let mut oversized = Torrent::with_config(config);
+        let error = oversized
+            .load_from_bytes(torrent(8, 2).as_bytes())
+            .unwrap_err();
+        assert!(error.to_string().contains("exceeds limit"));
+        let mut mismatched = Torrent::new();
+        let error = mismatched
+            .load_from_bytes(torrent(8, 1).as_bytes())
+            .unwrap_err();
+        assert!(error.to_string().contains("expected 2 pieces but found 1"));
+        for torrent in [&oversized, &mismatched] {
+            assert!(!torrent.is_loaded());
+            assert!(torrent.peer_id.is_empty());
+            assert!(torrent.pieces_hashes.is_empty());
+        }
+
+        // Loading never contacts tracker, announces being only retried once torrent is loaded
+        let mut valid = Torrent::new();
+        valid.load_from_bytes(torrent(4, 1).as_bytes()).unwrap();
+        assert!(valid.is_loaded());
+        assert_eq!(
+            listener.accept().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn detect_trackerless_torrents() {
+        let info = [
+            &b"d4:infod6:lengthi4e4:name8:data.bin12:piece lengthi16384e6:pieces20:"[..],
+            &[0; 20],
+            b"e",
+        ]
+        .concat();
+
+        // Torrent without announce nor nodes
+        let mut torrent = Torrent::new();
+        let buf = [&info[..], b"e"].concat();
+        torrent.load_from_bytes(&buf).unwrap();
+        let error = torrent.announce().unwrap_err().to_string();
+        assert_eq!(error, "could not find any tracker");
+        assert_eq!(torrent.nodes, None);
+
+        // Trackerless torrent, skipping malformed nodes
+        let mut torrent = Torrent::new();
+        let buf = [&info[..], b"5:nodesll9:127.0.0.1i6881eel4:hosteee"].concat();
+        torrent.load_from_bytes(&buf).unwrap();
+        let error = torrent.announce().unwrap_err().to_string();
+        assert!(error.contains("requires DHT"));
+        assert_eq!(torrent.nodes, Some(vec!["127.0.0.1:6881".to_owned()]));
+    }
+
+    #[test]
+    fn check_sample_of_peers() {
+        use std::net::TcpListener;
+
+        // Peer echoing handshake, and peer refusing connections
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let reachable = listener.local_addr().unwrap();
+        let unreachable = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let echo = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut handshake = [0; 68];
+            conn.read_exact(&mut handshake).unwrap();
+            conn.write_all(&handshake).unwrap();
+
+            // Connection is closed once check is done
+            let mut buf = [0; 1];
+            assert_eq!(conn.read(&mut buf).unwrap(), 0);
+        });
+
+        let mut torrent = Torrent::new();
+        torrent.info_hash = vec![1; SHA1_HASH_SIZE];
+        torrent.peer_id = vec![2; PEER_ID_SIZE];
+        torrent.peers = [reachable, unreachable]
+            .iter()
+            .map(|addr| Peer {
+                id: 0,
+                ip: addr.ip(),
+                port: addr.port(),
+                seed: None,
+            })
+            .collect();
+
+        let check = torrent.check_peers();
+        assert_eq!(
+            check,
+            PeerCheck {
+                sampled: 2,
+                reachable: 1
+            }
+        );
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn set_fixed_peer_id() {
+        let mut torrent = Torrent::new();
+        assert!(torrent.set_peer_id(vec![0x2D; 19]).is_err());
+        assert!(torrent.set_peer_id(vec![0x2D; 21]).is_err());
+        assert!(torrent.peer_id.is_empty());
+
+        torrent
+            .set_peer_id(b"-XR0001-123456789012".to_vec())
+            .unwrap();
+        assert_eq!(torrent.peer_id, b"-XR0001-123456789012".to_vec());
+    }
+
+    #[test]
+    fn build_tracker_url_keeps_passkey() {
+        let mut torrent = Torrent::new();
+        torrent.announce = "https://tracker.example.org/announce/0123abcd?uid=42".to_string();
+        torrent.info_hash = vec![0xAB; 20];
+        torrent.length = 1024;
+
+        let url = torrent
+            .build_tracker_url(&torrent.announce, b"-XR0001-123456789012".to_vec(), 6881)
+            .unwrap();
+        let url = Url::parse(&url).unwrap();
+
+        // Passkey path segment and existing query are kept as is
+        assert_eq!(url.path(), "/announce/0123abcd");
+        let pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        assert_eq!(pairs[0], ("uid".to_string(), "42".to_string()));
+        assert!(pairs.contains(&("port".to_string(), "6881".to_string())));
+        assert!(pairs.contains(&("left".to_string(), "1024".to_string())));
+        assert!(url
+            .query()
+            .unwrap()
+            .contains(&format!("info_hash={}", "%AB".repeat(20))));
+    }
+
+    #[test]
+    fn build_httpseeds_discards_invalid_urls() {
+        let buf: Vec<u8> = [
+            &b"d8:announce9:http://t/9:httpseedsl21:http://seed.org/s.php9:ftp://bad3:bade"[..],
+            &b"4:infod6:lengthi1024e4:name8:data.bin12:piece lengthi16384e6:pieces0:ee"[..],
+        ]
+        .concat();
+        let bencode = de::from_bytes::<BencodeTorrent>(&buf).unwrap();
+        assert_eq!(bencode.build_httpseeds(), vec!["http://seed.org/s.php"]);
+    }
+
+    #[test]
+    fn build_url_list_from_single_url_or_list() {
+        let bencode = |url_list: &[u8]| {
+            let buf: Vec<u8> = [
+                &b"d8:announce9:http://t/8:url-list"[..],
+                url_list,
+                &b"4:infod6:lengthi1024e4:name8:data.bin12:piece lengthi16384e6:pieces0:ee"[..],
+            ]
+            .concat();
+            de::from_bytes::<BencodeTorrent>(&buf).unwrap()
+        };
+
+        assert_eq!(
+            bencode(b"24:http://seed.org/data.bin").build_url_list(),
+            vec!["http://seed.org/data.bin"]
+        );
+        assert_eq!(
+            bencode(b"l16:http://seed.org/9:ftp://bad0:e").build_url_list(),
+            vec!["http://seed.org/"]
+        );
+    }
+
+    #[test]
+    fn build_announce_urls_without_duplicates() {
+        let mut torrent = Torrent::new();
+        torrent.announce = "http://tracker.example.org/announce".to_string();
+        torrent.set_trackers(vec![
+            "http://other.example.org:8080/announce".to_string(),
+            " http://tracker.example.org/announce ".to_string(),
+            "HTTP://OTHER.example.org:8080/announce".to_string(),
+        ]);
+
+        assert_eq!(
+            torrent.build_announce_urls(),
+            vec![
+                "http://tracker.example.org/announce",
+                "http://other.example.org:8080/announce"
+            ]
+        );
+    }
+
+    #[test]
+    fn build_announce_tiers_from_announce_list() {
+        let mut torrent = Torrent::new();
+        torrent.announce = "http://a.example.org/announce".to_string();
+        torrent.set_trackers(vec!["http://extra.example.org/announce".to_string()]);
+        assert_eq!(
+            torrent.build_announce_tiers(),
+            vec![vec![
+                "http://a.example.org/announce",
+                "http://extra.example.org/announce"
+            ]]
+        );
+
+        // Announce list replaces the tracker, keeping the first tier of a tracker
+        torrent.announce_list = vec![
+            vec![],
+            vec![
+                "http://b.example.org/announce".to_string(),
+                "http://c.example.org/announce".to_string(),
+            ],
+            vec!["http://b.example.org/announce".to_string()],
+            vec!["http://a.example.org/announce".to_string()],
+        ];
+        assert_eq!(
+            torrent.build_announce_tiers(),
+            vec![
+                vec![
+                    "http://b.example.org/announce",
+                    "http://c.example.org/announce",
+                    "http://extra.example.org/announce"
+                ],
+                vec!["http://a.example.org/announce"]
+            ]
+        );
+    }
+
+    #[test]
+    fn fall_back_to_next_tier() {
+        use std::net::TcpListener;
+
+        // Tracker answering a single request with a bencoded body
+        let serve = |body: &'static [u8]| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let tracker = format!("http://{}/announce", listener.local_addr().unwrap());
+            let server = thread::spawn(move || {
+                let (mut conn, _) = listener.accept().unwrap();
+                let mut buf = [0; 4096];
+                let _ = conn.read(&mut buf).unwrap();
+                let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+                conn.write_all(&[head.as_bytes(), body].concat()).unwrap();
+            });
+            (tracker, server)
+        };
+        let failing = || serve(b"d14:failure reason4:downe");
+
+        // Try next tier once every tracker of the first tier failed
+        let (failed_a, server_a) = failing();
+        let (failed_b, server_b) = failing();
+        let (tracker, server) = serve(b"d8:intervali900e5:peers6:\x7f\x00\x00\x01\x1a\xe1e");
+        let mut torrent = Torrent::new();
+        torrent.info_hash = vec![1; SHA1_HASH_SIZE];
+        torrent.announce_list = vec![vec![failed_a, failed_b], vec![tracker]];
+        let announce = torrent
+            .request_peers_from_trackers(vec![2; PEER_ID_SIZE], PORT)
+            .unwrap();
+        assert_eq!(announce.peers.len(), 1);
+        assert_eq!(announce.peers[0].port, 6881);
+        for server in [server_a, server_b, server] {
+            server.join().unwrap();
+        }
+
+        // Report the error of each tracker once every tier failed
+        let (failed_a, server_a) = failing();
+        let (failed_b, server_b) = failing();
+        torrent.announce_list = vec![vec![failed_a.clone()], vec![failed_b.clone()]];
+        let error = torrent
+            .request_peers_from_trackers(vec![2; PEER_ID_SIZE], PORT)
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("could not get peers from any of 2 trackers: "));
+        assert!(error.contains(&format!("{}: ", failed_a)));
+        assert!(error.contains(&format!("; {}: ", failed_b)));
+        for server in [server_a, server_b] {
+            server.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn retry_tracker_answering_server_error() {
+        use std::net::TcpListener;
+
+        // Tracker answering each request with a status and a bencoded body
+        let serve = |answers: Vec<(&'static str, &'static [u8])>| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let tracker = format!("http://{}/announce", listener.local_addr().unwrap());
+            let server = thread::spawn(move || {
+                for (status, body) in answers {
+                    let (mut conn, _) = listener.accept().unwrap();
+                    let mut buf = [0; 4096];
+                    let _ = conn.read(&mut buf).unwrap();
+                    let head = format!(
+                        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        status,
+                        body.len()
+                    );
+                    conn.write_all(&[head.as_bytes(), body].concat()).unwrap();
+                }
+            });
+            (tracker, server)
+        };
+        let mut torrent = Torrent::new();
+        torrent.info_hash = vec![1; SHA1_HASH_SIZE];
+
+        // Overloaded tracker is asked again
+        let (tracker, server) = serve(vec![
+            ("503 Service Unavailable", b""),
+            (
+                "200 OK",
+                b"d8:intervali900e5:peers6:\x7f\x00\x00\x01\x1a\xe1e",
+            ),
+        ]);
+        torrent.announce_list = vec![vec![tracker]];
+        let announce = torrent
+            .request_peers_from_trackers(vec![2; PEER_ID_SIZE], PORT)
+            .unwrap();
+        assert_eq!(announce.peers.len(), 1);
+        server.join().unwrap();
+
+        // Tracker refusing request is not asked again
+        let (tracker, server) = serve(vec![("404 Not Found", b"")]);
+        torrent.announce_list = vec![vec![tracker]];
+        let error = torrent
+            .request_peers_from_trackers(vec![2; PEER_ID_SIZE], PORT)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(error, "tracker answered with status 404 Not Found");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn do_not_wait_for_stalled_tracker_of_tier() {
+        use std::net::TcpListener;
+
+        // Tracker answering a single request, and tracker accepting connections without answering
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tracker = format!("http://{}/announce", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut buf = [0; 4096];
+            let _ = conn.read(&mut buf).unwrap();
+            let body = b"d8:intervali900e5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            conn.write_all(&[head.as_bytes(), body].concat()).unwrap();
+        });
+        let stalled_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stalled = format!("http://{}/announce", stalled_listener.local_addr().unwrap());
+
+        // Tier returns peers of the tracker that answered, once the stalled tracker timed out
+        let mut torrent = Torrent::new();
+        torrent.info_hash = vec![1; SHA1_HASH_SIZE];
+        let started = Instant::now();
+        let (announce, errors) =
+            torrent.request_peers_from_tier(&[stalled.clone(), tracker], &[2; PEER_ID_SIZE], PORT);
+        assert!(started.elapsed() < TIER_GRACE + Duration::from_secs(3));
+        assert_eq!(announce.unwrap().peers.len(), 1);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, stalled);
+        assert!(errors[0]
+            .1
+            .to_string()
+            .starts_with("tracker did not answer within"));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn scrape_torrent_with_optional_name() {
+        use std::net::TcpListener;
+
+        // Tracker answering a single scrape request with a bencoded body
+        let serve = |body: Vec<u8>| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let tracker = format!("http://{}/announce", listener.local_addr().unwrap());
+            let server = thread::spawn(move || {
+                let (mut conn, _) = listener.accept().unwrap();
+                let mut buf = [0; 4096];
+                let _ = conn.read(&mut buf).unwrap();
+                let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+                conn.write_all(&[head.as_bytes(), &body].concat()).unwrap();
+            });
+            (tracker, server)
+        };
+        let client = reqwest::blocking::Client::new();
+        let mut torrent = Torrent::new();
+        torrent.info_hash = vec![0xAB; 20];
+        torrent.name = "data.bin".to_string();
+
+        // Swarm state of the torrent is keyed by its raw info hash, next to other torrents
+        let body = [
+            &b"d5:filesd20:"[..],
+            &[0x01; 20],
+            b"d8:completei9ee20:",
+            &[0xAB; 20],
+            b"d8:completei5e10:downloadedi7e10:incompletei2e4:name8:data.binee",
+            b"e",
+        ]
+        .concat();
+        let (tracker, server) = serve(body);
+        let scrape = torrent.scrape_tracker(&client, &tracker).unwrap();
+        server.join().unwrap();
+        assert_eq!(
+            scrape,
+            Scrape {
+                complete: 5,
+                incomplete: 2,
+                downloaded: 7,
+                name: Some("data.bin".to_string()),
+            }
+        );
+
+        // Name is optional
+        let body = [&b"d5:filesd20:"[..], &[0xAB; 20], b"d8:completei5eeee"].concat();
+        let (tracker, server) = serve(body);
+        let scrape = torrent.scrape_tracker(&client, &tracker).unwrap();
+        server.join().unwrap();
+        assert_eq!(scrape.complete, 5);
+        assert_eq!(scrape.name, None);
+    }
+
+    #[test]
+    fn report_udp_trackers_as_unsupported() {
+        let torrent = Torrent::new();
+        let error = torrent
+            .build_announce_request(
+                "udp://tracker.invalid:1337/announce",
+                vec![2; PEER_ID_SIZE],
+                PORT,
+            )
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "could not announce to tracker udp://tracker.invalid:1337/announce, udp trackers are not supported"
+        );
+    }
+
+    #[test]
+    fn build_scrape_url_from_announce() {
+        let mut torrent = Torrent::new();
+        torrent.info_hash = vec![0xAB; 20];
+
+        let url = torrent
+            .build_scrape_url("http://tracker.example.org/x/announce.php?passkey=1")
+            .unwrap();
+        assert_eq!(
+            url,
+            format!(
+                "http://tracker.example.org/x/scrape.php?passkey=1&info_hash={}",
+                "%AB".repeat(20)
+            )
+        );
+        assert!(torrent
+            .build_scrape_url("http://tracker.example.org/a")
+            .is_err());
+    }
+
+    #[test]
+    fn hash_info_with_extra_keys() {
+        let pieces: &[u8] = &[0xAB; 20];
+
+        // Bencoded info dictionaries, keys sorted, with and without a source tag
+        let without_source: Vec<u8> = [
+            &b"d6:lengthi1024e4:name8:data.bin12:piece lengthi16384e6:pieces20:"[..],
+            pieces,
+            &b"e"[..],
+        ]
+        .concat();
+        let with_source: Vec<u8> = [
+            &b"d6:lengthi1024e4:name8:data.bin12:piece lengthi16384e6:pieces20:"[..],
+            pieces,
+            &b"6:source7:TRACKERe"[..],
+        ]
+        .concat();
+
+        // Bencoded info dictionary of a hybrid v1/v2 torrent
+        let hybrid: Vec<u8> = [
+            &b"d9:file treed8:data.bind0:d6:lengthi1024e11:pieces root32:"[..],
+            &[0xCD; 32],
+            &b"eee6:lengthi1024e12:meta versioni2e4:name8:data.bin12:piece lengthi16384e6:pieces20:"[..],
+            pieces,
+            &b"e"[..],
+        ]
+        .concat();
+
+        for raw in [without_source, with_source, hybrid] {
+            let info = de::from_bytes::<BencodeInfo>(&raw).unwrap();
+            assert_eq!(info.hash().unwrap(), sha1(&raw).unwrap());
+        }
+    }
+
+    #[test]
+    fn compute_info_hashes_from_raw_bytes() {
+        let info: Vec<u8> = [
+            &b"d6:lengthi4e4:name8:data.bin12:piece lengthi16384e6:pieces20:"[..],
+            &[0xAB; 20],
+            &b"e"[..],
+        ]
+        .concat();
+        let torrent = [&b"d8:announce9:http://t/4:info"[..], &info, b"e"].concat();
+        let hashes = compute_info_hashes(&torrent).unwrap();
+        assert_eq!(hashes.serialized, sha1(&info).unwrap());
+        assert_eq!(hashes.raw, Some(sha1(&info).unwrap()));
+
+        // An info key this client does not decode only changes the raw hash
+        let info_extra: Vec<u8> = [&info[..info.len() - 1], b"7:privatei1ee"].concat();
+        let torrent = [&b"d4:info"[..], &info_extra, b"e"].concat();
+        let hashes = compute_info_hashes(&torrent).unwrap();
+        assert_eq!(hashes.serialized, sha1(&info).unwrap());
+        assert_eq!(hashes.raw, Some(sha1(&info_extra).unwrap()));
+
+        // Malformed values are not walked past
+        assert_eq!(find_info_bytes(b"d8:announce99:http://t/e"), None);
+        assert_eq!(find_info_bytes(b"l4:infoe"), None);
+    }
+
+    #[test]
+    fn open_torrents_of_directory() {
+        let dir = std::env::temp_dir().join(format!("xerus-open-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Torrents of an unreachable tracker, never announced, among invalid and unrelated files
+        let torrent = |name: &str| {
+            format!(
+                "d8:announce27:http://127.0.0.1:9/announce4:infod6:lengthi4e4:name{}:{}12:piece lengthi4e6:pieces20:{}ee",
+                name.len(),
+                name,
+                "x".repeat(20)
+            )
+        };
+        std::fs::write(dir.join("b.torrent"), torrent("b")).unwrap();
+        std::fs::write(dir.join("a.TORRENT"), torrent("a")).unwrap();
+        std::fs::write(dir.join("c.torrent"), "<html>404 Not Found</html>").unwrap();
+        std::fs::write(dir.join("d.txt"), torrent("d")).unwrap();
+        std::fs::create_dir_all(dir.join("e.torrent")).unwrap();
+        // Torrent of 2 pieces whose length fits a single piece
+        std::fs::write(
+            dir.join("f.torrent"),
+            torrent("f").replace("6:pieces20:", &format!("6:pieces40:{}", "x".repeat(20))),
+        )
+        .unwrap();
+
+        let torrents = Torrent::new().open_dir(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let names: Vec<&str> = torrents
+            .iter()
+            .map(|torrent| torrent.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["a", "b"]);
+        assert!(torrents.iter().all(|torrent| torrent.peers.is_empty()));
+
+        // Torrents do not share download state
+        torrents[0].pause();
+        assert!(!torrents[1].is_paused());
+
+        // A missing directory fails the scan
+        assert!(Torrent::new().open_dir(&dir).is_err());
+    }
+
+    #[test]
+    fn build_resume_bitfield_by_batches() {
+        let output = std::env::temp_dir().join(format!("xerus-resume-{}", std::process::id()));
+        let data: Vec<u8> = (0..100u32).map(|i| i as u8).collect();
+
+        // Torrent of 7 pieces over two files, the last piece being shorter
+        let config = TorrentConfig::builder().quiet(true).build().unwrap();
+        let mut torrent = Torrent::with_config(config);
+        torrent.piece_length = 16;
+        torrent.length = data.len() as u64;
+        torrent.files = vec![
+            TorrentFile::new(PathBuf::from("a"), 40, 0),
+            TorrentFile::new(PathBuf::from("b"), 60, 40),
+        ];
+        torrent.pieces_hashes = data.chunks(16).map(|piece| sha1(piece).unwrap()).collect();
+        torrent.create_files(output.clone()).unwrap();
+
+        // Save every piece but pieces 2, crossing files, and 5
+        let mut saved = data.clone();
+        saved[40] = 0xFF;
+        saved[80] = 0xFF;
+        write_files(&output, &torrent.files, 0, &saved).unwrap();
+
+        for batch in [1, 2, 3, 64, usize::MAX] {
+            torrent.config = TorrentConfig::builder()
+                .quiet(true)
+                .verify_batch(batch)
+                .build()
+                .unwrap();
+            let bitfield = torrent.build_resume_bitfield().unwrap();
+            let pieces: Vec<u32> = (0..7).filter(|&index| bitfield.has_piece(index)).collect();
+            assert_eq!(pieces, vec![0, 1, 3, 4, 6]);
+        }
+
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn decode_tracker_min_interval() {
+        let tracker =
+            de::from_bytes::<BencodeTracker>(b"d8:intervali1800e12:min intervali900e5:peers0:e")
+                .unwrap();
+        assert_eq!(tracker.interval, 1800);
+        assert_eq!(tracker.min_interval, Some(900));
+
+        let tracker = de::from_bytes::<BencodeTracker>(b"d8:intervali1800e5:peers0:e").unwrap();
+        assert_eq!(tracker.min_interval, None);
+    }
+
+    #[test]
+    fn connect_to_seeders_first() {
+        let tracker = de::from_bytes::<BencodeTrackerDict>(
+            b"d8:intervali1800e5:peersld2:ip8:10.0.0.18:completei0e4:porti1eed2:ip7:8.8.8.84:porti2eed2:ip7:8.8.4.48:completei1e4:porti3eeee",
+        )
+        .unwrap();
+        let hints = tracker.build_seed_hints();
+        assert_eq!(
+            hints,
+            vec![
+                ("10.0.0.1:1".parse().unwrap(), false),
+                ("8.8.4.4:3".parse().unwrap(), true)
+            ]
+        );
+
+        // Local peers come first, then seeders, then peers without hint
+        let mut torrent = Torrent::new();
+        torrent.peers = ["10.0.0.1:1", "8.8.8.8:2", "8.8.4.4:3"]
+            .iter()
+            .map(|addr| {
+                let addr: SocketAddr = addr.parse().unwrap();
+                let mut peer = Peer::new();
+                peer.ip = addr.ip();
+                peer.port = addr.port();
+                peer.seed = hints
+                    .iter()
+                    .find(|(hint, _)| *hint == addr)
+                    .map(|(_, seed)| *seed);
+                peer
+            })
+            .collect();
+        let order: Vec<u16> = torrent
+            .build_connect_order()
+            .iter()
+            .map(|peer| peer.port)
+            .collect();
+        assert_eq!(order, vec![1, 3, 2]);
+    }
+
+    #[test]
+    fn write_memory_pieces_by_runs() {
+        let output = std::env::temp_dir().join(format!("xerus-memory-{}", std::process::id()));
+        let data: Vec<u8> = (0..100u32).map(|i| i as u8).collect();
+
+        // Torrent of 7 pieces over two files, the last piece being shorter
+        let mut torrent = Torrent::new();
+        torrent.piece_length = 16;
+        torrent.length = data.len() as u64;
+        torrent.files = vec![
+            TorrentFile::new(PathBuf::from("a"), 40, 0),
+            TorrentFile::new(PathBuf::from("b"), 60, 40),
+        ];
+        torrent.pieces_hashes = vec![vec![0; 20]; 7];
+        torrent.create_files(output.clone()).unwrap();
+
+        // Hold every piece but piece 4 in memory
+        let mut memory = MemoryPieces {
+            data: data.clone(),
+            pieces: Bitfield::new(7),
+        };
+        for index in [0, 1, 2, 3, 5, 6] {
+            memory.pieces.set_piece(index);
+        }
+        torrent.write_memory_pieces(&memory).unwrap();
+
+        // Pieces are written at the same offsets as pieces written as they arrive
+        let mut expected = data.clone();
+        expected[64..80].fill(0);
+        assert_eq!(
+            read_files(&output, &torrent.files, 0, 100).unwrap(),
+            expected
+        );
+
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn hold_last_short_piece_in_memory() {
+        // Torrent of 3 pieces, the last piece being shorter
+        let mut torrent = Torrent::new();
+        torrent.piece_length = 16;
+        torrent.length = 40;
+        torrent.pieces_hashes = vec![vec![0; 20]; 3];
+        let mut memory = MemoryPieces {
+            data: vec![0; torrent.length as usize],
+            pieces: Bitfield::new(3),
+        };
+
+        // Pieces arriving in any order land at their offset, filled with their index plus one
+        for index in [2, 0, 1] {
+            let length = torrent.get_piece_length(index).unwrap();
+            let piece_result =
+                PieceResult::new(index, length, vec![index as u8 + 1; length as usize], None);
+            memory
+                .insert(
+                    piece_result.index,
+                    torrent.piece_offset(piece_result.index),
+                    &piece_result.data,
+                )
+                .unwrap();
+        }
+        let expected: Vec<u8> = [vec![1; 16], vec![2; 16], vec![3; 8]].concat();
+        assert_eq!(memory.data, expected);
+        assert_eq!(memory.pieces.count_pieces(3), 3);
+
+        // A last piece one byte too long is rejected, rather than overrunning data
+        assert_eq!(torrent.get_piece_length(2).unwrap(), 8);
+        assert!(memory.insert(2, torrent.piece_offset(2), &[3; 9]).is_err());
+        assert_eq!(memory.data, expected);
+    }
+
+    // Writer failing as a pipe whose reader is gone
+    struct ClosedPipe;
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::from(io::ErrorKind::BrokenPipe))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_pieces_in_order() {
+        let mut wanted = Bitfield::new(8);
+        for index in 0..8 {
+            wanted.set_piece(index);
+        }
+        let mut ordered = OrderedPieces::new(wanted, 8, 2, None);
+        let mut out: Vec<u8> = vec![];
+
+        // Pieces received ahead are buffered until missing pieces arrive
+        let piece =
+            |index: u32, data: Vec<u8>| PieceResult::new(index, data.len() as u32, data, None);
+        ordered.push(piece(1, vec![1, 1]), &mut out).unwrap();
+        ordered.push(piece(2, vec![2]), &mut out).unwrap();
+        assert!(out.is_empty());
+        ordered.push(piece(0, vec![0]), &mut out).unwrap();
+        assert_eq!(out, vec![0, 1, 1, 2]);
+        assert_eq!(ordered.next, 3);
+        assert!(ordered.pieces.is_empty());
+
+        // Pieces are held back from the window end
+        let progress = Progress::new();
+        progress.reset(8);
+        assert!(progress.in_window(7));
+        progress.set_window_end(Some(ordered.window_end(2)));
+        assert!(progress.in_window(4));
+        assert!(!progress.in_window(5));
+
+        // A closed pipe is told from other write failures
+        let error = ordered
+            .push(piece(3, vec![3]), &mut ClosedPipe)
+            .unwrap_err();
+        assert_eq!(error.downcast_ref::<StdoutClosed>().unwrap().index, 3);
+        assert_eq!(ordered.next, 3);
+    }
+
+    #[test]
+    fn write_range_of_pieces_in_order() {
+        // Range from byte 5 to byte 12 of a torrent of 6 pieces of 4 bytes
+        let mut wanted = Bitfield::new(6);
+        for index in 1..4 {
+            wanted.set_piece(index);
+        }
+        let mut ordered = OrderedPieces::new(wanted, 6, 4, Some((5, 12)));
+        let mut out: Vec<u8> = vec![];
+
+        // Writing and the window start from the first wanted piece, past the window from piece 0
+        assert_eq!(ordered.next, 1);
+        assert_eq!(ordered.window_end(2), 3);
+        let piece = |index: u32| PieceResult::new(index, 4, vec![index as u8; 4], None);
+        ordered.push(piece(2), &mut out).unwrap();
+        assert!(out.is_empty());
+        ordered.push(piece(1), &mut out).unwrap();
+        assert_eq!(ordered.next, 3);
+        assert_eq!(ordered.window_end(2), 6);
+
+        // Boundary pieces are trimmed to the range, and no piece is awaited past the range
+        ordered.push(piece(3), &mut out).unwrap();
+        assert_eq!(out, vec![1, 1, 1, 2, 2, 2, 2, 3]);
+        assert_eq!(ordered.next, 6);
+    }
+
+    #[test]
+    fn skip_pieces_not_wanted_in_order() {
+        let mut wanted = Bitfield::new(6);
+        for index in [1, 3, 4] {
+            wanted.set_piece(index);
+        }
+        let mut ordered = OrderedPieces::new(wanted, 6, 1, None);
+        let mut out: Vec<u8> = vec![];
+
+        // Writing starts from the first wanted piece, and the window counts wanted pieces only
+        assert_eq!(ordered.next, 1);
+        assert_eq!(ordered.window_end(2), 4);
+        let piece = |index: u32| PieceResult::new(index, 1, vec![index as u8], None);
+        ordered.push(piece(1), &mut out).unwrap();
+        assert_eq!(ordered.next, 3);
+        assert_eq!(ordered.window_end(2), 5);
+        ordered.push(piece(4), &mut out).unwrap();
+        ordered.push(piece(3), &mut out).unwrap();
+        assert_eq!(out, vec![1, 3, 4]);
+        assert_eq!(ordered.next, 6);
+    }
+
+    #[test]
+    fn limit_inflight_pieces_to_max_memory() {
+        // Fixed number of in-flight pieces by default, whatever the number of peers
+        let mut torrent = Torrent::new();
+        torrent.piece_length = 16384;
+        torrent.peers = vec![Peer::new(); 100];
+        assert_eq!(torrent.max_inflight_pieces(), MAX_INFLIGHT_PIECES);
+
+        // Fit data buffers of in-flight pieces in maximum memory
+        torrent.config = TorrentConfig::builder()
+            .max_memory(3 * 16384 + 100)
+            .build()
+            .unwrap();
+        assert_eq!(torrent.max_inflight_pieces(), 3);
+
+        // Always download at least one piece, even if larger than maximum memory
+        torrent.config = TorrentConfig::builder().max_memory(1).build().unwrap();
+        assert_eq!(torrent.max_inflight_pieces(), 1);
+    }
+
+    #[test]
+    fn build_wanted_pieces_of_range() {
+        // Single-file torrent of 7 pieces
+        let mut torrent = Torrent::new();
+        torrent.piece_length = 16;
+        torrent.length = 100;
+        torrent.files = vec![TorrentFile::new(PathBuf::new(), 100, 0)];
+        torrent.pieces_hashes = vec![vec![0; 20]; 7];
+        assert_eq!(torrent.build_wanted_pieces().unwrap().count_pieces(7), 7);
+
+        // Pieces overlapping range boundaries are wanted whole
+        torrent.set_range(20, 48);
+        let wanted = torrent.build_wanted_pieces().unwrap();
+        assert_eq!(wanted.as_bytes(), &[0b0111_0000]);
+        torrent.set_range(99, 99);
+        let wanted = torrent.build_wanted_pieces().unwrap();
+        assert_eq!(wanted.as_bytes(), &[0b0000_0010]);
+
+        // Reject a range past the end, or of a multi-file torrent
+        torrent.set_range(20, 100);
+        assert!(torrent.build_wanted_pieces().is_err());
+        torrent.set_range(20, 10);
+        assert!(torrent.build_wanted_pieces().is_err());
+        torrent.set_range(0, 10);
+        torrent.files = vec![
+            TorrentFile::new(PathBuf::from("a"), 50, 0),
+            TorrentFile::new(PathBuf::from("b"), 50, 50),
+        ];
+        assert!(torrent.build_wanted_pieces().is_err());
+    }
+
+    #[test]
+    fn build_piece_order_with_priority_file() {
+        // Torrent of 7 pieces over three files
+        let mut torrent = Torrent::new();
+        torrent.name = "dir".to_string();
+        torrent.piece_length = 16;
+        torrent.length = 100;
+        torrent.files = vec![
+            TorrentFile::new(PathBuf::from("a"), 40, 0),
+            TorrentFile::new(PathBuf::from("b"), 40, 40),
+            TorrentFile::new(PathBuf::from("c"), 20, 80),
+        ];
+        torrent.pieces_hashes = vec![vec![0; 20]; 7];
+        assert_eq!(
+            torrent.build_piece_order(&[]).unwrap(),
+            vec![0, 1, 2, 3, 4, 5, 6]
+        );
+
+        // Pieces of the priority file come first and in order, including those shared with other files
+        torrent.set_priority_file(PathBuf::from("b"));
+        assert_eq!(
+            torrent.build_piece_order(&[]).unwrap(),
+            vec![2, 3, 4, 0, 1, 5, 6]
+        );
+        torrent.set_priority_file(PathBuf::from("c"));
+        assert_eq!(
+            torrent.build_piece_order(&[]).unwrap(),
+            vec![5, 6, 0, 1, 2, 3, 4]
+        );
+        torrent.set_priority_file(PathBuf::from("d"));
+        assert!(torrent.build_piece_order(&[]).is_err());
+
+        // Other pieces follow rarest first, pieces no peer has coming last
+        torrent.set_priority_file(PathBuf::from("c"));
+        assert_eq!(
+            torrent.build_piece_order(&[3, 0, 1, 2, 1, 5, 5]).unwrap(),
+            vec![5, 6, 2, 4, 3, 0, 1]
+        );
+
+        // Pieces waiting in work channel are queued again in that order
+        torrent.progress.reset(7);
+        for index in [2, 2, 4] {
+            torrent.progress.add_piece(index);
+        }
+        let work_chan = unbounded();
+        for index in [0, 2, 4, 6] {
+            work_chan.0.send(PieceWork::new(index, vec![], 16)).unwrap();
+        }
+        torrent.reorder_work_pieces(&work_chan).unwrap();
+        let queued: Vec<u32> = work_chan.1.try_iter().map(|piece| piece.index).collect();
+        assert_eq!(queued, vec![6, 4, 2, 0]);
+
+        // The file of a single-file torrent is named after the torrent
+        torrent.files = vec![TorrentFile::new(PathBuf::new(), 100, 0)];
+        torrent.set_priority_file(PathBuf::from("dir"));
+        assert_eq!(
+            torrent.build_piece_order(&[]).unwrap(),
+            vec![0, 1, 2, 3, 4, 5, 6]
+        );
+    }
+
+    #[test]
+    fn build_files_larger_than_4_gib() {
+        // Single-file torrent of 5 GiB, in pieces of 1 GiB
+        let raw: Vec<u8> = [
+            &b"d6:lengthi5368709120e4:name8:data.bin12:piece lengthi1073741824e6:pieces100:"[..],
+            &[0xAB; 100],
+            &b"e"[..],
+        ]
+        .concat();
+        let info = de::from_bytes::<BencodeInfo>(&raw).unwrap();
+        let files = info.build_files().unwrap();
+        assert_eq!(files[0].length, 5 << 30);
 
-        Ok(end - begin)
+        // Offsets of the last pieces do not overflow
+        let mut torrent = Torrent::new();
+        torrent.piece_length = info.piece_length;
+        torrent.length = files[0].length;
+        assert_eq!(torrent.piece_offset(4), 4 << 30);
+        assert_eq!(torrent.get_piece_length(4).unwrap(), 1 << 30);
+        assert!(torrent.get_piece_length(6).is_err());
     }
 }
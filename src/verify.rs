@@ -0,0 +1,338 @@
+// Copyright (c) 2020 zenoxygen
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::bitfield::*;
+use crate::hash::*;
+use crate::piece::*;
+use crate::progress::*;
+use crate::worker::Slot;
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use std::thread::{self, JoinHandle};
+
+// Number of downloaded pieces waiting for verification per hashing thread
+const PENDING_PER_THREAD: usize = 2;
+
+/// VerifyJob structure, a downloaded piece to verify.
+pub struct VerifyJob {
+    // Downloaded piece
+    piece_work: PieceWork,
+    // Peer that sent the piece
+    peer_id: u32,
+    // In-flight piece slot, held until piece is written or discarded
+    slot: Slot,
+}
+
+/// VerifyPool structure, verifying downloaded pieces on dedicated threads.
+///
+/// Verified pieces are sent to the result channel, and corrupted ones are sent back to the
+/// work channel, or to the failed pieces channel once too many peers sent them corrupted.
+///
+pub struct VerifyPool {
+    // Channel to send pieces to verify, disconnected to stop hashing threads
+    jobs: Option<Sender<VerifyJob>>,
+    // Hashing threads
+    threads: Vec<JoinHandle<()>>,
+}
+
+/// Verifier structure, state shared by hashing threads.
+#[derive(Clone)]
+struct Verifier {
+    work_chan: Sender<PieceWork>,
+    result_chan: Sender<PieceResult>,
+    fail_chan: Sender<u32>,
+    max_piece_failures: usize,
+    progress: Progress,
+    // Pieces verified and sent to the result channel, so duplicates are not hashed again
+    verified: Arc<Mutex<Bitfield>>,
+}
+
+impl VerifyJob {
+    /// Build a new verification job.
+    ///
+    /// # Arguments
+    ///
+    /// * `piece_work` - The downloaded piece.
+    /// * `peer_id` - The ID of the peer that sent the piece.
+    /// * `slot` - The in-flight piece slot held by the piece data.
+    ///
+    pub(crate) fn new(piece_work: PieceWork, peer_id: u32, slot: Slot) -> VerifyJob {
+        VerifyJob {
+            piece_work,
+            peer_id,
+            slot,
+        }
+    }
+}
+
+impl VerifyPool {
+    /// Build a new verification pool, and start its hashing threads.
+    ///
+    /// # Arguments
+    ///
+    /// * `nb_threads` - The number of hashing threads.
+    /// * `nb_pieces` - The number of pieces in torrent.
+    /// * `work_chan` - The channel to resend corrupted pieces to.
+    /// * `result_chan` - The channel to send verified pieces to.
+    /// * `fail_chan` - The channel to send indexes of pieces that could not be verified.
+    /// * `max_piece_failures` - The number of peers allowed to send a corrupted piece.
+    /// * `progress` - The state and availability of pieces.
+    ///
+    pub fn new(
+        nb_threads: usize,
+        nb_pieces: u32,
+        work_chan: Sender<PieceWork>,
+        result_chan: Sender<PieceResult>,
+        fail_chan: Sender<u32>,
+        max_piece_failures: usize,
+        progress: Progress,
+    ) -> VerifyPool {
+        let nb_threads = nb_threads.max(1);
+        let (jobs, jobs_chan): (Sender<VerifyJob>, Receiver<VerifyJob>) =
+            bounded(nb_threads * PENDING_PER_THREAD);
+        let verifier = Verifier {
+            work_chan,
+            result_chan,
+            fail_chan,
+            max_piece_failures,
+            progress,
+            verified: Arc::new(Mutex::new(Bitfield::new(nb_pieces))),
+        };
+
+        // Start hashing threads, until jobs channel is disconnected
+        let threads = (0..nb_threads)
+            .map(|_| {
+                let jobs_chan = jobs_chan.clone();
+                let verifier = verifier.clone();
+                thread::spawn(move || {
+                    for job in jobs_chan.iter() {
+                        if !verifier.verify(job) {
+                            return;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        VerifyPool {
+            jobs: Some(jobs),
+            threads,
+        }
+    }
+
+    /// Get the channel to send downloaded pieces to verify.
+    pub fn jobs(&self) -> Sender<VerifyJob> {
+        match &self.jobs {
+            Some(jobs) => jobs.clone(),
+            None => bounded(0).0,
+        }
+    }
+}
+
+impl Drop for VerifyPool {
+    fn drop(&mut self) {
+        // Tell hashing threads to stop, once pieces sent by workers are verified
+        self.jobs = None;
+
+        // Wait for hashing threads
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                error!("Error: could not stop hashing thread");
+            }
+        }
+    }
+}
+
+impl Verifier {
+    /// Verify a downloaded piece, and send it to the matching channel.
+    ///
+    /// Return false if the hashing thread must stop.
+    ///
+    /// # Arguments
+    ///
+    /// * `job` - The piece to verify.
+    ///
+    fn verify(&self, job: VerifyJob) -> bool {
+        let mut piece_work = job.piece_work;
+
+        // Discard a piece already verified, completed by another peer meanwhile
+        if self.is_verified(piece_work.index) {
+            debug!(
+                "Discard piece {:?} already verified, without hashing it again",
+                piece_work.index
+            );
+            return true;
+        }
+
+        // Hash piece data, stopping hashing thread if piece can not be hashed
+        let hash = match sha1(&piece_work.data) {
+            Ok(hash) => hash,
+            Err(e) => {
+                error!("Error: {}", e);
+                if let Err(e) = piece_work.resend(&self.work_chan, &self.progress) {
+                    error!("Error: {}", e);
+                }
+                return false;
+            }
+        };
+
+        // Compare hashes
+        if hash != piece_work.hash {
+            info!(
+                "Could not verify integrity of piece {:?} downloaded from peer",
+                piece_work.index
+            );
+
+            // Remember peer that sent corrupted piece
+            piece_work.failed_peers.push(job.peer_id);
+
+            // Mark piece as permanently failed
+            if piece_work.failed_peers.len() >= self.max_piece_failures {
+                if self.fail_chan.send(piece_work.index).is_err() {
+                    error!("Error: could not send piece to channel");
+                }
+                return true;
+            }
+
+            // Free piece data and resend piece to work channel
+            if let Err(e) = piece_work.resend(&self.work_chan, &self.progress) {
+                error!("Error: {}", e);
+            }
+            return true;
+        }
+
+        // Mark piece as verified, discarding it if another hashing thread verified it first
+        match self.verified.lock() {
+            Ok(mut verified) if !verified.has_piece(piece_work.index) => {
+                verified.set_piece(piece_work.index)
+            }
+            Ok(_) => return true,
+            Err(_) => {
+                error!("Error: could not update verified pieces");
+                return false;
+            }
+        }
+
+        info!(
+            "Successfully verified integrity of piece {:?}",
+            piece_work.index
+        );
+
+        // Send piece to result channel
+        let piece_result = PieceResult::new(
+            piece_work.index,
+            piece_work.length,
+            piece_work.data,
+            Some(job.slot),
+        );
+        if self.result_chan.send(piece_result).is_err() {
+            error!("Error: could not send piece to channel");
+            return false;
+        }
+
+        true
+    }
+
+    /// Check if a piece was already verified and sent to the result channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The piece index.
+    ///
+    fn is_verified(&self, index: u32) -> bool {
+        match self.verified.lock() {
+            Ok(verified) => verified.has_piece(index),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crossbeam_channel::unbounded;
+
+    // Verifier of a torrent of 2 pieces, with channels to check where pieces go
+    fn new_verifier() -> (Verifier, Receiver<PieceWork>, Receiver<PieceResult>) {
+        let (work_chan, work_recv) = unbounded();
+        let (result_chan, result_recv) = unbounded();
+        let verifier = Verifier {
+            work_chan,
+            result_chan,
+            fail_chan: unbounded().0,
+            max_piece_failures: 3,
+            progress: Progress::new(),
+            verified: Arc::new(Mutex::new(Bitfield::new(2))),
+        };
+        (verifier, work_recv, result_recv)
+    }
+
+    #[test]
+    fn discard_duplicate_of_verified_piece() {
+        let (verifier, _work_recv, result_recv) = new_verifier();
+        let slots: (Sender<()>, Receiver<()>) = bounded(2);
+        let data = vec![0xAB; 16];
+        let mut piece_work = PieceWork::new(1, sha1(&data).unwrap(), 16);
+        piece_work.data = data;
+
+        // First copy is verified and sent, a late duplicate is discarded
+        for peer_id in [1, 2] {
+            let slot = Slot {
+                chan: slots.0.clone(),
+            };
+            assert!(verifier.verify(VerifyJob::new(piece_work.clone(), peer_id, slot)));
+        }
+        assert_eq!(result_recv.try_iter().count(), 1);
+        assert!(verifier.is_verified(1));
+        assert!(!verifier.is_verified(0));
+    }
+
+    #[test]
+    fn hold_slot_until_piece_is_written() {
+        let (verifier, work_recv, result_recv) = new_verifier();
+        let slots: (Sender<()>, Receiver<()>) = bounded(1);
+        let data = vec![0xAB; 16];
+
+        // Slot of a verified piece travels with its data, until the result is dropped
+        let mut piece_work = PieceWork::new(0, sha1(&data).unwrap(), 16);
+        piece_work.data = data;
+        let slot = Slot {
+            chan: slots.0.clone(),
+        };
+        assert!(verifier.verify(VerifyJob::new(piece_work.clone(), 1, slot)));
+        let piece_result = result_recv.try_recv().unwrap();
+        assert!(slots.1.try_recv().is_err());
+        drop(piece_result);
+        assert!(slots.1.try_recv().is_ok());
+
+        // Slot of a corrupted piece is given back with its data freed
+        piece_work.index = 1;
+        piece_work.hash = vec![0; 20];
+        let slot = Slot {
+            chan: slots.0.clone(),
+        };
+        assert!(verifier.verify(VerifyJob::new(piece_work, 1, slot)));
+        assert!(work_recv.try_recv().unwrap().data.is_empty());
+        assert!(slots.1.try_recv().is_ok());
+    }
+}
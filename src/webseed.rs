@@ -0,0 +1,664 @@
+// Copyright (c) 2020 zenoxygen
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::piece::*;
+use crate::progress::*;
+use crate::stats::*;
+use crate::storage::*;
+use crate::verify::*;
+use crate::worker::*;
+
+use anyhow::{anyhow, Result};
+use crossbeam_channel::{select, Receiver, RecvTimeoutError, Sender};
+use reqwest::StatusCode;
+use url::Url;
+
+use std::borrow::Cow;
+use std::io::Read;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Timeout of a piece request to a web seed
+const HTTP_SEED_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Delay before retrying when a web seed is busy and does not tell how long to wait
+const HTTP_SEED_RETRY: Duration = Duration::from_secs(10);
+
+/// Kind of web seed, telling how pieces are requested.
+pub enum HttpSeedKind {
+    // BEP 17 script, serving a piece by its index
+    Script,
+    // BEP 19 server, serving byte ranges of the files of the torrent
+    Files {
+        // Length of pieces in bytes, but the last one
+        piece_length: u64,
+        // URL, offset within the torrent data and size of each file, in bytes
+        files: Vec<(String, u64, u64)>,
+    },
+}
+
+/// HttpSeed structure, downloading whole pieces from a BEP 17 or BEP 19 web seed.
+pub struct HttpSeed {
+    // ID telling pieces sent corrupted by this web seed, apart from peer IDs
+    id: u32,
+    url: String,
+    kind: HttpSeedKind,
+    info_hash: Vec<u8>,
+    bind: Option<IpAddr>,
+    work_chan: (Sender<PieceWork>, Receiver<PieceWork>),
+    verify_chan: Sender<VerifyJob>,
+    inflight_chan: (Sender<()>, Receiver<()>),
+    paused: Arc<AtomicBool>,
+    done_chan: Receiver<()>,
+    progress: Progress,
+    stats: Stats,
+}
+
+/// Response of a web seed to a piece request.
+enum HttpSeedResponse {
+    // Piece data
+    Piece(Vec<u8>),
+    // Web seed is busy, retry after a delay
+    Busy(Duration),
+}
+
+impl HttpSeed {
+    /// Build a new web seed.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the web seed, apart from peer IDs.
+    /// * `url` - The URL of the web seed.
+    /// * `kind` - The kind of web seed, telling how pieces are requested.
+    /// * `info_hash` - 20-byte SHA-1 hash of the info key in the metainfo file.
+    /// * `bind` - The local address to connect from, chosen by the system if not set.
+    /// * `work_chan` - The channel to send and receive work pieces.
+    /// * `verify_chan` - The channel to send downloaded pieces to verify.
+    /// * `inflight_chan` - The channel holding free in-flight piece slots.
+    /// * `paused` - Whether download is paused, holding requests.
+    /// * `done_chan` - The channel disconnected when the web seed must stop.
+    /// * `progress` - The state and availability of pieces.
+    /// * `stats` - The transferred bytes and download rate.
+    ///
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        id: u32,
+        url: String,
+        kind: HttpSeedKind,
+        info_hash: Vec<u8>,
+        bind: Option<IpAddr>,
+        work_chan: (Sender<PieceWork>, Receiver<PieceWork>),
+        verify_chan: Sender<VerifyJob>,
+        inflight_chan: (Sender<()>, Receiver<()>),
+        paused: Arc<AtomicBool>,
+        done_chan: Receiver<()>,
+        progress: Progress,
+        stats: Stats,
+    ) -> HttpSeed {
+        HttpSeed {
+            id,
+            url,
+            kind,
+            info_hash,
+            bind,
+            work_chan,
+            verify_chan,
+            inflight_chan,
+            paused,
+            done_chan,
+            progress,
+            stats,
+        }
+    }
+
+    /// Start web seed, downloading pieces until none is left or the web seed fails.
+    pub fn start_download(&self) {
+        // Build blocking HTTP client
+        let client = match reqwest::blocking::Client::builder()
+            .timeout(HTTP_SEED_TIMEOUT)
+            .local_address(self.bind)
+            .build()
+        {
+            Ok(client) => client,
+            Err(_) => return,
+        };
+
+        let mut nb_skipped = 0;
+        loop {
+            // Hold requests while download is paused, until web seed must stop
+            while self.paused.load(Ordering::SeqCst) {
+                if let Err(RecvTimeoutError::Disconnected) =
+                    self.done_chan.recv_timeout(PAUSE_POLL_INTERVAL)
+                {
+                    return;
+                }
+            }
+
+            // Wait for a free in-flight piece slot
+            if self.recv_until_done(&self.inflight_chan.1).is_none() {
+                return;
+            }
+            let slot = Slot {
+                chan: self.inflight_chan.0.clone(),
+            };
+
+            // Receive a piece from work channel
+            let mut piece_work: PieceWork = match self.recv_until_done(&self.work_chan.1) {
+                Some(piece_work) => piece_work,
+                None => return,
+            };
+
+            // Resend piece held back, waiting once every queued piece has been skipped
+            if !self.progress.in_window(piece_work.index) {
+                if let Err(e) = piece_work.resend(&self.work_chan.0, &self.progress) {
+                    error!("Error: {}", e);
+                }
+                nb_skipped += 1;
+                if nb_skipped > self.work_chan.0.len() {
+                    nb_skipped = 0;
+                    if let Err(RecvTimeoutError::Disconnected) =
+                        self.done_chan.recv_timeout(SKIP_BACKOFF)
+                    {
+                        return;
+                    }
+                }
+                continue;
+            }
+            nb_skipped = 0;
+
+            // Stop web seed once it served corrupted data
+            if piece_work.failed_peers.contains(&self.id) {
+                info!(
+                    "Web seed {} sent piece {:?} corrupted, stop web seed",
+                    self.url, piece_work.index
+                );
+                if let Err(e) = piece_work.resend(&self.work_chan.0, &self.progress) {
+                    error!("Error: {}", e);
+                }
+                return;
+            }
+            self.progress
+                .set_state(piece_work.index, PieceState::InFlight);
+
+            // Download whole piece from web seed
+            let data = match self.request_piece(&client, &piece_work) {
+                Ok(HttpSeedResponse::Piece(data)) => data,
+                Ok(HttpSeedResponse::Busy(delay)) => {
+                    info!("Web seed {} is busy, retry in {:?}", self.url, delay);
+                    if let Err(e) = piece_work.resend(&self.work_chan.0, &self.progress) {
+                        error!("Error: {}", e);
+                    }
+
+                    // Wait before requesting a piece again, until web seed must stop
+                    if let Err(RecvTimeoutError::Disconnected) = self.done_chan.recv_timeout(delay)
+                    {
+                        return;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    info!("Web seed {} failed: {}, stop web seed", self.url, e);
+                    if let Err(e) = piece_work.resend(&self.work_chan.0, &self.progress) {
+                        error!("Error: {}", e);
+                    }
+                    return;
+                }
+            };
+            self.stats.add_downloaded(data.len() as u64);
+            piece_work.data = data;
+
+            // Send piece to be verified with its slot, like pieces downloaded from peers
+            let job = VerifyJob::new(piece_work, self.id, slot);
+            if self.verify_chan.send(job).is_err() {
+                error!("Error: could not send piece to channel");
+                return;
+            }
+        }
+    }
+
+    /// Build the URL requesting a piece, as `<url>?info_hash=<hash>&piece=<index>`.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The piece index.
+    ///
+    fn build_piece_url(&self, index: u32) -> Result<String> {
+        // Parse web seed URL from torrent
+        let mut url = match Url::parse(&self.url) {
+            Ok(url) => url,
+            Err(_) => return Err(anyhow!("could not parse web seed url")),
+        };
+
+        // Add parameters to the web seed URL
+        url
+            // Add info hash
+            .query_pairs_mut()
+            .encoding_override(Some(&|input| {
+                if input != "!" {
+                    Cow::Borrowed(input.as_bytes())
+                } else {
+                    Cow::Owned(self.info_hash.clone())
+                }
+            }))
+            .append_pair("info_hash", "!");
+        url.query_pairs_mut()
+            // Add piece index
+            .append_pair("piece", &index.to_string());
+
+        Ok(url.to_string())
+    }
+
+    /// Request a whole piece from web seed, with the requests of its kind.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The HTTP client.
+    /// * `piece_work` - The piece to download.
+    ///
+    fn request_piece(
+        &self,
+        client: &reqwest::blocking::Client,
+        piece_work: &PieceWork,
+    ) -> Result<HttpSeedResponse> {
+        let (piece_length, files) = match &self.kind {
+            HttpSeedKind::Script => {
+                let url = self.build_piece_url(piece_work.index)?;
+                return request_data(client.get(&url), piece_work, piece_work.length as u64);
+            }
+            HttpSeedKind::Files {
+                piece_length,
+                files,
+            } => (*piece_length, files),
+        };
+
+        // Request the part of each file the piece spans
+        let ranges = build_piece_ranges(files, piece_length, piece_work)?;
+        let mut data: Vec<u8> = Vec::with_capacity(piece_work.length as usize);
+        for (url, first, last) in ranges {
+            let request = client
+                .get(url)
+                .header(reqwest::header::RANGE, format!("bytes={}-{}", first, last));
+            match request_data(request, piece_work, last - first + 1)? {
+                HttpSeedResponse::Piece(part) => data.extend_from_slice(&part),
+                busy => return Ok(busy),
+            }
+        }
+
+        Ok(HttpSeedResponse::Piece(data))
+    }
+
+    /// Receive from a channel, until web seed must stop.
+    ///
+    /// # Arguments
+    ///
+    /// * `chan` - The channel to receive from.
+    ///
+    fn recv_until_done<T>(&self, chan: &Receiver<T>) -> Option<T> {
+        select! {
+            recv(chan) -> msg => msg.ok(),
+            recv(self.done_chan) -> _ => None,
+        }
+    }
+}
+
+/// Build the URLs of the files of a torrent on a BEP 19 web seed, with their offset and size.
+///
+/// A single-file torrent is served at the URL itself, or under its name when the URL ends with a
+/// slash. Files of a multi-file torrent are served under the name of the torrent.
+///
+/// # Arguments
+///
+/// * `url` - The URL of the web seed.
+/// * `name` - The name of the torrent.
+/// * `files` - The files of the torrent.
+///
+pub fn build_file_urls(
+    url: &str,
+    name: &str,
+    files: &[TorrentFile],
+) -> Result<Vec<(String, u64, u64)>> {
+    // Parse web seed URL from torrent
+    let base_url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return Err(anyhow!("could not parse web seed url")),
+    };
+    let single_file = files.len() == 1 && files[0].path.as_os_str().is_empty();
+
+    let mut file_urls: Vec<(String, u64, u64)> = vec![];
+    for file in files {
+        // Append name of torrent and path of file, unless the URL is the file itself
+        let mut file_url = base_url.clone();
+        if !single_file || url.ends_with('/') {
+            let mut segments = match file_url.path_segments_mut() {
+                Ok(segments) => segments,
+                Err(_) => return Err(anyhow!("could not parse web seed url")),
+            };
+            segments.pop_if_empty().push(name);
+            for component in file.path.iter() {
+                segments.push(&component.to_string_lossy());
+            }
+        }
+        file_urls.push((file_url.to_string(), file.offset, file.length));
+    }
+
+    Ok(file_urls)
+}
+
+/// Build the byte ranges of files spanned by a piece, as URL, first and last byte of each file.
+///
+/// # Arguments
+///
+/// * `files` - The URL, offset and size of each file of the torrent.
+/// * `piece_length` - The length of pieces, but the last one.
+/// * `piece_work` - The piece to download.
+///
+fn build_piece_ranges<'a>(
+    files: &'a [(String, u64, u64)],
+    piece_length: u64,
+    piece_work: &PieceWork,
+) -> Result<Vec<(&'a str, u64, u64)>> {
+    let start = piece_work.index as u64 * piece_length;
+    let end = start + piece_work.length as u64;
+
+    // Keep the part of each file within the piece, skipping empty files
+    let mut ranges: Vec<(&str, u64, u64)> = vec![];
+    let mut covered: u64 = 0;
+    for (url, offset, length) in files {
+        let first = start.max(*offset);
+        let last = end.min(offset + length);
+        if first < last {
+            ranges.push((url, first - offset, last - offset - 1));
+            covered += last - first;
+        }
+    }
+    if covered != piece_work.length as u64 {
+        return Err(anyhow!(
+            "could not find files of piece {:?} on web seed",
+            piece_work.index
+        ));
+    }
+
+    Ok(ranges)
+}
+
+/// Send a request to web seed and read the data of its response.
+///
+/// # Arguments
+///
+/// * `request` - The request to send.
+/// * `piece_work` - The piece downloaded.
+/// * `length` - The number of bytes expected in response.
+///
+fn request_data(
+    request: reqwest::blocking::RequestBuilder,
+    piece_work: &PieceWork,
+    length: u64,
+) -> Result<HttpSeedResponse> {
+    // Send GET request to the web seed
+    let response = match request.send() {
+        Ok(response) => response,
+        Err(_) => return Err(anyhow!("could not send request to web seed")),
+    };
+
+    // Wait the number of seconds given in body when web seed is busy
+    let status = response.status();
+    if status == StatusCode::SERVICE_UNAVAILABLE {
+        let delay = match response.text() {
+            Ok(body) => parse_busy_delay(&body),
+            Err(_) => HTTP_SEED_RETRY,
+        };
+        return Ok(HttpSeedResponse::Busy(delay));
+    }
+    if !status.is_success() {
+        return Err(anyhow!("web seed answered {}", status));
+    }
+
+    // Check announced length, before reading any data
+    if let Some(content_length) = response.content_length() {
+        if content_length != length {
+            return Err(anyhow!(
+                "web seed announced {:?} bytes of piece {:?}, expected {:?} bytes",
+                content_length,
+                piece_work.index,
+                length
+            ));
+        }
+    }
+
+    // Read data, at most one byte more than expected to tell a longer body
+    let mut data: Vec<u8> = Vec::with_capacity(length as usize);
+    if response.take(length + 1).read_to_end(&mut data).is_err() {
+        return Err(anyhow!("could not read piece from web seed"));
+    }
+    if data.len() as u64 != length {
+        return Err(anyhow!(
+            "received {:?} bytes of piece {:?}, expected {:?} bytes",
+            data.len(),
+            piece_work.index,
+            length
+        ));
+    }
+
+    Ok(HttpSeedResponse::Piece(data))
+}
+
+/// Parse the number of seconds to wait, sent in body by a busy web seed.
+///
+/// # Arguments
+///
+/// * `body` - The body of the response.
+///
+fn parse_busy_delay(body: &str) -> Duration {
+    match body.trim().parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => HTTP_SEED_RETRY,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crossbeam_channel::{bounded, unbounded};
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+    use std::thread;
+
+    // Web seed of a torrent, with channels left unused
+    fn new_http_seed(url: &str, info_hash: Vec<u8>) -> HttpSeed {
+        new_seed(url, HttpSeedKind::Script, info_hash)
+    }
+
+    // Web seed of a kind, with channels left unused
+    fn new_seed(url: &str, kind: HttpSeedKind, info_hash: Vec<u8>) -> HttpSeed {
+        HttpSeed::new(
+            u32::MAX,
+            url.to_string(),
+            kind,
+            info_hash,
+            None,
+            unbounded(),
+            unbounded().0,
+            bounded(1),
+            Arc::new(AtomicBool::new(false)),
+            bounded(0).1,
+            Progress::new(),
+            Stats::new(),
+        )
+    }
+
+    #[test]
+    fn build_piece_url_with_raw_info_hash() {
+        let info_hash: Vec<u8> = vec![
+            0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf1, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd,
+            0xef, 0x12, 0x34, 0x56, 0x78, 0x9a,
+        ];
+        let seed = new_http_seed("http://seed.example.com/seed.php", info_hash);
+        assert_eq!(
+            seed.build_piece_url(7).unwrap(),
+            "http://seed.example.com/seed.php?info_hash=%124Vx%9A%BC%DE%F1%23Eg%89%AB%CD%EF%124Vx%9A&piece=7"
+        );
+
+        // Parameters are appended to an existing query
+        let seed = new_http_seed("http://seed.example.com/seed.php?key=1", vec![b'a'; 20]);
+        assert_eq!(
+            seed.build_piece_url(0).unwrap(),
+            "http://seed.example.com/seed.php?key=1&info_hash=aaaaaaaaaaaaaaaaaaaa&piece=0"
+        );
+
+        // An invalid URL is reported
+        let seed = new_http_seed("not a url", vec![0; 20]);
+        assert!(seed.build_piece_url(0).is_err());
+    }
+
+    #[test]
+    fn parse_delay_of_busy_web_seed() {
+        assert_eq!(parse_busy_delay("30"), Duration::from_secs(30));
+        assert_eq!(parse_busy_delay(" 5\r\n"), Duration::from_secs(5));
+        assert_eq!(parse_busy_delay(""), HTTP_SEED_RETRY);
+        assert_eq!(parse_busy_delay("busy"), HTTP_SEED_RETRY);
+        assert_eq!(parse_busy_delay("-1"), HTTP_SEED_RETRY);
+    }
+
+    #[test]
+    fn build_file_urls_of_torrent() {
+        // Single-file torrent is the URL itself, or under its name when URL is a directory
+        let files = vec![TorrentFile::new(PathBuf::new(), 10, 0)];
+        assert_eq!(
+            build_file_urls("http://seed.org/data.bin", "data.bin", &files).unwrap(),
+            vec![("http://seed.org/data.bin".to_string(), 0, 10)]
+        );
+        assert_eq!(
+            build_file_urls("http://seed.org/files/", "data.bin", &files).unwrap(),
+            vec![("http://seed.org/files/data.bin".to_string(), 0, 10)]
+        );
+
+        // Files of a multi-file torrent are under its name, with their path encoded
+        let files = vec![
+            TorrentFile::new(PathBuf::from("a b"), 10, 0),
+            TorrentFile::new(PathBuf::from("dir").join("c#1"), 20, 10),
+        ];
+        assert_eq!(
+            build_file_urls("http://seed.org/files", "my torrent", &files).unwrap(),
+            vec![
+                (
+                    "http://seed.org/files/my%20torrent/a%20b".to_string(),
+                    0,
+                    10
+                ),
+                (
+                    "http://seed.org/files/my%20torrent/dir/c%231".to_string(),
+                    10,
+                    20
+                ),
+            ]
+        );
+
+        // An invalid URL is reported
+        assert!(build_file_urls("not a url", "data.bin", &files).is_err());
+    }
+
+    #[test]
+    fn dispatch_piece_requests_by_kind() {
+        // Data of a torrent of two files, pieces of 8 bytes, the second one being served by each
+        let data: Vec<u8> = (0..20u8).collect();
+        let piece_work = PieceWork::new(1, vec![], 8);
+
+        // Web server answering each request with the bytes of the file range, or the whole piece
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let requests: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(vec![]));
+        let requests_copy = requests.clone();
+        let data_copy = data.clone();
+        let server = thread::spawn(move || {
+            for _ in 0..3 {
+                let (mut conn, _) = listener.accept().unwrap();
+                let mut head: Vec<u8> = vec![];
+                let mut byte = [0; 1];
+                while !head.ends_with(b"\r\n\r\n") {
+                    conn.read_exact(&mut byte).unwrap();
+                    head.push(byte[0]);
+                }
+                let head = String::from_utf8(head).unwrap().to_ascii_lowercase();
+                let path = head.split(' ').nth(1).unwrap().to_string();
+                let range = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("range: bytes="))
+                    .map(|range| range.to_string());
+                let body = match &range {
+                    Some(range) => {
+                        let (first, last) = range.split_once('-').unwrap();
+                        let (first, last): (usize, usize) =
+                            (first.parse().unwrap(), last.parse().unwrap());
+                        let offset = if path.ends_with("/b") { 12 } else { 0 };
+                        data_copy[offset + first..=offset + last].to_vec()
+                    }
+                    None => data_copy[8..16].to_vec(),
+                };
+                requests_copy.lock().unwrap().push(match range {
+                    Some(range) => format!("{} {}", path, range),
+                    None => path,
+                });
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                conn.write_all(&[head.as_bytes(), &body].concat()).unwrap();
+            }
+        });
+        let client = reqwest::blocking::Client::new();
+
+        // BEP 17 script is asked for the piece by its index
+        let seed = new_http_seed(&format!("{}/seed", base), vec![b'a'; 20]);
+        match seed.request_piece(&client, &piece_work).unwrap() {
+            HttpSeedResponse::Piece(piece) => assert_eq!(piece, data[8..16]),
+            HttpSeedResponse::Busy(_) => panic!("web seed is not busy"),
+        }
+
+        // BEP 19 server is asked for the range of each file the piece spans
+        let files = vec![
+            (format!("{}/t/a", base), 0, 12),
+            (format!("{}/t/b", base), 12, 8),
+        ];
+        let kind = HttpSeedKind::Files {
+            piece_length: 8,
+            files,
+        };
+        let seed = new_seed(&base, kind, vec![b'a'; 20]);
+        match seed.request_piece(&client, &piece_work).unwrap() {
+            HttpSeedResponse::Piece(piece) => assert_eq!(piece, data[8..16]),
+            HttpSeedResponse::Busy(_) => panic!("web seed is not busy"),
+        }
+
+        server.join().unwrap();
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                format!("/seed?info_hash={}&piece=1", "a".repeat(20)),
+                "/t/a 8-11".to_string(),
+                "/t/b 0-3".to_string(),
+            ]
+        );
+    }
+}
@@ -18,27 +18,270 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+use crate::bitfield::*;
 use crate::client::*;
+use crate::config::*;
 use crate::message::*;
 use crate::peer::*;
 use crate::piece::*;
+use crate::progress::*;
+use crate::stats::*;
+use crate::verify::*;
+use crate::webseed::*;
 
 use anyhow::{anyhow, Result};
-use boring::sha::Sha1;
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{
+    bounded, select, Receiver, RecvTimeoutError, Sender, TryRecvError, TrySendError,
+};
+
+use std::any::Any;
+use std::cell::Cell;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 // Maximum number of requests
 const NB_REQUESTS_MAX: u32 = 5;
 
-// Block size limit (2^14) in bytes
-const BLOCK_SIZE_MAX: u32 = 16384;
+// Number of requests of a piece a peer may reject, before the piece is given back
+const MAX_REJECTS_PER_PIECE: u32 = 4 * NB_REQUESTS_MAX;
+
+// Delay before looking for a piece again, when peer has none of the queued pieces
+pub(crate) const SKIP_BACKOFF: Duration = Duration::from_millis(500);
+
+// Number of reconnections to a peer after its connection was lost
+const MAX_RECONNECTS: usize = 3;
+
+// Delay before reconnecting to a peer, doubled after each reconnection
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+
+// Delay between checks of whether download is still paused
+pub(crate) const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Delay between keep-alives sent to peer while download is paused
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Maximum time to wait for workers to stop, once told to
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Delay between checks of whether a worker stopped
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 pub struct Worker {
     peer: Peer,
     peer_id: Vec<u8>,
     info_hash: Vec<u8>,
+    nb_pieces: u32,
+    config: TorrentConfig,
     work_chan: (Sender<PieceWork>, Receiver<PieceWork>),
-    result_chan: (Sender<PieceResult>, Receiver<PieceResult>),
+    verify_chan: Sender<VerifyJob>,
+    inflight_chan: (Sender<()>, Receiver<()>),
+    connect_chan: (Sender<()>, Receiver<()>),
+    peer_chan: (Sender<()>, Receiver<()>),
+    have: Arc<Mutex<Bitfield>>,
+    paused: Arc<AtomicBool>,
+    done_chan: Receiver<()>,
+    conns: Arc<Mutex<Vec<TcpStream>>>,
+    progress: Progress,
+    stats: Stats,
+    // Peer has none of the remaining pieces, so reconnecting to it would not help
+    exhausted: Cell<bool>,
+}
+
+/// Outcome of downloading pieces from a peer.
+enum DownloadOutcome {
+    // Pieces were downloaded
+    Complete,
+    // Peer kept this client choked for too long, pieces must be given back
+    Choked,
+    // Peer kept rejecting requests of a piece, pieces must be given back
+    Rejected,
+}
+
+/// WorkerPool structure, stopping workers when dropped.
+pub struct WorkerPool {
+    // Channel disconnected to tell workers to stop
+    done: Option<Sender<()>>,
+    // Channel watched by workers, never receiving anything until disconnected
+    done_chan: Receiver<()>,
+    // Connections of workers, shut down to interrupt pending reads
+    conns: Arc<Mutex<Vec<TcpStream>>>,
+    // Threads running workers
+    threads: Vec<JoinHandle<()>>,
+}
+
+/// Connection registered to be shut down when workers must stop, shut down and forgotten when dropped.
+///
+/// Connections are closed once their worker stops, rather than being kept open until download completes.
+///
+struct RegisteredConn<'a> {
+    // Connections shut down when workers must stop
+    conns: &'a Mutex<Vec<TcpStream>>,
+    // Local address of the connection, telling it apart from others
+    local_addr: SocketAddr,
+}
+
+impl<'a> RegisteredConn<'a> {
+    /// Register connection of a client.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - A client connected to a remote peer.
+    /// * `conns` - The connections shut down when workers must stop.
+    ///
+    fn new(client: &Client, conns: &'a Mutex<Vec<TcpStream>>) -> Result<RegisteredConn<'a>> {
+        let conn = client.try_clone_stream()?;
+        let local_addr = match conn.local_addr() {
+            Ok(local_addr) => local_addr,
+            Err(_) => return Err(anyhow!("could not get local address of connection")),
+        };
+        match conns.lock() {
+            Ok(mut conns) => conns.push(conn),
+            Err(_) => return Err(anyhow!("could not register connection")),
+        }
+
+        Ok(RegisteredConn { conns, local_addr })
+    }
+}
+
+impl Drop for RegisteredConn<'_> {
+    fn drop(&mut self) {
+        // Close connection, even though the client may still hold it
+        if let Ok(mut conns) = self.conns.lock() {
+            if let Some(position) = conns
+                .iter()
+                .position(|conn| conn.local_addr().ok() == Some(self.local_addr))
+            {
+                let conn = conns.swap_remove(position);
+                let _ = conn.shutdown(Shutdown::Both);
+            }
+        }
+    }
+}
+
+/// Slot taken from a channel of free slots, such as a piece in flight, given back when dropped.
+///
+/// A piece in flight keeps its slot along with its data, until the piece is written or discarded.
+///
+#[derive(Debug)]
+pub(crate) struct Slot {
+    pub(crate) chan: Sender<()>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        // Give back slot to its channel, unless download is over and nobody takes slots anymore
+        if let Err(TrySendError::Full(_)) = self.chan.try_send(()) {
+            error!("Error: could not release slot");
+        }
+    }
+}
+
+impl WorkerPool {
+    /// Build a new empty worker pool.
+    pub fn new() -> WorkerPool {
+        let (done, done_chan) = bounded(0);
+        WorkerPool {
+            done: Some(done),
+            done_chan,
+            conns: Arc::new(Mutex::new(vec![])),
+            threads: vec![],
+        }
+    }
+
+    /// Get the channel telling workers to stop once disconnected.
+    pub fn done_chan(&self) -> Receiver<()> {
+        self.done_chan.clone()
+    }
+
+    /// Get the connections of workers, shut down when workers are stopped.
+    pub fn conns(&self) -> Arc<Mutex<Vec<TcpStream>>> {
+        self.conns.clone()
+    }
+
+    /// Check whether every worker and web seed stopped.
+    pub fn all_stopped(&self) -> bool {
+        self.threads.iter().all(|thread| thread.is_finished())
+    }
+
+    /// Start worker in a new thread.
+    ///
+    /// # Arguments
+    ///
+    /// * `worker` - The worker to start.
+    ///
+    pub fn spawn(&mut self, worker: Worker) {
+        self.threads.push(thread::spawn(move || {
+            worker.start_download();
+        }));
+    }
+
+    /// Start web seed in a new thread.
+    ///
+    /// # Arguments
+    ///
+    /// * `seed` - The web seed to start.
+    ///
+    pub fn spawn_http_seed(&mut self, seed: HttpSeed) {
+        self.threads.push(thread::spawn(move || {
+            seed.start_download();
+        }));
+    }
+}
+
+impl Default for WorkerPool {
+    fn default() -> Self {
+        WorkerPool::new()
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // Tell workers to stop between pieces
+        self.done = None;
+
+        // Interrupt pending reads from peers
+        if let Ok(conns) = self.conns.lock() {
+            for conn in conns.iter() {
+                let _ = conn.shutdown(Shutdown::Both);
+            }
+        }
+
+        // Wait for workers to release their connections, until timeout
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        for thread in self.threads.drain(..) {
+            while !thread.is_finished() && Instant::now() < deadline {
+                thread::sleep(STOP_POLL_INTERVAL);
+            }
+            if !thread.is_finished() {
+                warn!("Worker did not stop within {:?}, leave it", STOP_TIMEOUT);
+                continue;
+            }
+
+            // Report worker that panicked, rather than losing it silently
+            if let Err(panic) = thread.join() {
+                warn!("Worker panicked: {}", panic_message(&panic));
+            }
+        }
+    }
+}
+
+/// Get the message a thread panicked with.
+///
+/// # Arguments
+///
+/// * `panic` - The payload of the panic.
+///
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
 }
 
 impl Worker {
@@ -47,39 +290,150 @@ impl Worker {
     /// # Arguments
     ///
     /// * `peer` - A remote peer to connect to.
+    /// * `peer_id` - Urlencoded 20-byte string used as a unique ID for the client.
+    /// * `info_hash` - 20-byte SHA-1 hash of the info key in the metainfo file.
+    /// * `nb_pieces` - The number of pieces in torrent.
+    /// * `config` - The options of the download, such as pieces downloaded at once from the peer.
     /// * `work_chan` - The channel to send and receive work pieces.
-    /// * `result_chan` - The channel to send result pieces.
+    /// * `verify_chan` - The channel to send downloaded pieces to verify.
+    /// * `inflight_chan` - The channel holding free in-flight piece slots.
+    /// * `connect_chan` - The channel holding free connection slots.
+    /// * `peer_chan` - The channel holding free connected peer slots.
+    /// * `have` - The bitfield of pieces already downloaded.
+    /// * `paused` - Whether download is paused, holding requests but keeping connection.
+    /// * `done_chan` - The channel disconnected when the worker must stop.
+    /// * `conns` - The connections shut down when the worker must stop.
+    /// * `progress` - The state and availability of pieces.
+    /// * `stats` - The transferred bytes and download rate.
     ///
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         peer: Peer,
         peer_id: Vec<u8>,
         info_hash: Vec<u8>,
+        nb_pieces: u32,
+        config: TorrentConfig,
         work_chan: (Sender<PieceWork>, Receiver<PieceWork>),
-        result_chan: (Sender<PieceResult>, Receiver<PieceResult>),
+        verify_chan: Sender<VerifyJob>,
+        inflight_chan: (Sender<()>, Receiver<()>),
+        connect_chan: (Sender<()>, Receiver<()>),
+        peer_chan: (Sender<()>, Receiver<()>),
+        have: Arc<Mutex<Bitfield>>,
+        paused: Arc<AtomicBool>,
+        done_chan: Receiver<()>,
+        conns: Arc<Mutex<Vec<TcpStream>>>,
+        progress: Progress,
+        stats: Stats,
     ) -> Result<Worker> {
         // Create a new worker
         let worker = Worker {
             peer,
             peer_id,
             info_hash,
+            nb_pieces,
+            config,
             work_chan,
-            result_chan,
+            verify_chan,
+            inflight_chan,
+            connect_chan,
+            peer_chan,
+            have,
+            paused,
+            done_chan,
+            conns,
+            progress,
+            stats,
+            exhausted: Cell::new(false),
         };
 
         Ok(worker)
     }
 
-    /// Start worker.
+    /// Start worker, reconnecting to peer after a transient failure.
     pub fn start_download(&self) {
+        // Last bitfield seen from peer, kept across connections
+        let mut cached: Option<Bitfield> = None;
+
+        let mut backoff = RECONNECT_BACKOFF;
+        let mut nb_reconnects = 0;
+        loop {
+            self.download_session(&mut cached);
+
+            // Reconnect only to a peer that was reached, and that still has missing pieces
+            let bitfield = match &cached {
+                Some(bitfield) => bitfield,
+                None => return,
+            };
+            if nb_reconnects >= MAX_RECONNECTS
+                || self.is_done()
+                || self.exhausted.get()
+                || !self.is_useful(bitfield)
+            {
+                return;
+            }
+            nb_reconnects += 1;
+
+            // Wait before reconnecting, until worker must stop
+            info!(
+                "Lost connection to peer {}, reconnect in {:?}",
+                self.peer, backoff
+            );
+            if let Err(RecvTimeoutError::Disconnected) = self.done_chan.recv_timeout(backoff) {
+                return;
+            }
+            backoff *= 2;
+        }
+    }
+
+    /// Connect to peer and download pieces, until connection is lost or peer can not help anymore.
+    ///
+    /// # Arguments
+    ///
+    /// * `cached` - The last bitfield seen from peer, restored on reconnection and updated.
+    ///
+    fn download_session(&self, cached: &mut Option<Bitfield>) {
         let peer_copy = self.peer.clone();
         let peer_id_copy = self.peer_id.clone();
         let info_hash_copy = self.info_hash.clone();
 
+        // Wait for a free connected peer slot, until peer is disconnected
+        if self.recv_until_done(&self.peer_chan.1).is_none() {
+            return;
+        }
+        let _peer_slot = Slot {
+            chan: self.peer_chan.0.clone(),
+        };
+
+        // Wait for a free connection slot, until handshake completes or fails
+        if self.recv_until_done(&self.connect_chan.1).is_none() {
+            return;
+        }
+        let connect_slot = Slot {
+            chan: self.connect_chan.0.clone(),
+        };
+
         // Create new client
-        let mut client = match Client::new(peer_copy, peer_id_copy, info_hash_copy) {
+        let mut client = match Client::new(
+            peer_copy,
+            peer_id_copy,
+            info_hash_copy,
+            self.nb_pieces,
+            self.config.nodelay,
+            self.config.bind,
+        ) {
             Ok(client) => client,
             Err(_) => return,
         };
+        client.set_stats(self.stats.clone());
+
+        // Keep a handle on connection to shut it down when worker must stop
+        let _registered = match RegisteredConn::new(&client, &self.conns) {
+            Ok(registered) => registered,
+            Err(_) => return,
+        };
+        if self.is_done() {
+            return;
+        }
 
         // Set connection timeout
         if client.set_connection_timeout(5).is_err() {
@@ -91,108 +445,535 @@ impl Worker {
             return;
         }
 
-        // Read bitfield from peer
+        // Give back connection slot
+        drop(connect_slot);
+
+        // Send bitfield of pieces saved to disk, which may be resumed from a previous download
+        let announced: Bitfield = match self.have.lock() {
+            Ok(have) => have.clone(),
+            Err(_) => return,
+        };
+        if client.send_bitfield(&announced).is_err() {
+            return;
+        }
+
+        // Read bitfield from peer, refreshing the one seen on a previous connection
+        if let Some(bitfield) = cached {
+            client.restore_bitfield(bitfield.clone());
+        }
         if client.read_bitfield().is_err() {
             return;
         }
+        *cached = Some(client.bitfield().clone());
+
+        // Count peer and pieces available from it, until it is disconnected
+        self.progress.add_peer(client.bitfield());
+        self.stats.add_peer();
+        self.download_from_peer(&mut client, announced);
+        self.stats.remove_peer();
+        self.progress.remove_peer(client.bitfield());
+
+        // Keep pieces announced by peer during download
+        *cached = Some(client.bitfield().clone());
+    }
 
+    /// Download pieces from a connected remote peer, until it can not help anymore.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - A client connected to a remote peer.
+    /// * `announced` - The bitfield of pieces already announced to remote peer.
+    ///
+    fn download_from_peer(&self, client: &mut Client, mut announced: Bitfield) {
         // Send unchoke
         if client.send_unchoke().is_err() {
             return;
         }
 
-        // Send interested
-        if client.send_interested().is_err() {
+        // Pieces downloaded by this worker, that may not be saved to disk yet
+        let mut completed = Bitfield::new(self.nb_pieces);
+
+        // Send interested only if peer has pieces that are still missing
+        let interest = if self.has_missing_pieces(client, &completed) {
+            client.send_interested()
+        } else {
+            client.send_not_interested()
+        };
+        if interest.is_err() {
             return;
         }
 
+        // Number of pieces skipped in a row, since peer does not have them
+        let mut nb_skipped: usize = 0;
+
         loop {
-            // Receive a piece from work channel
-            let mut piece_work: PieceWork = match self.work_chan.1.recv() {
-                Ok(piece_work) => piece_work,
-                Err(_) => {
-                    error!("Error: could not receive piece from channel");
-                    return;
-                }
+            // Hold requests while download is paused, keeping connection alive
+            if self.wait_while_paused(client).is_err() {
+                return;
+            }
+
+            // Wait for peer to unchoke this client, unless it allows some missing pieces meanwhile
+            if client.is_choked()
+                && !self.has_allowed_fast_pieces(client, &completed)
+                && self.wait_for_unchoke(client, &completed).is_err()
+            {
+                return;
+            }
+
+            // Wait for a free in-flight piece slot
+            if self.recv_until_done(&self.inflight_chan.1).is_none() {
+                return;
+            }
+            let slot = Slot {
+                chan: self.inflight_chan.0.clone(),
+            };
+
+            // Notify peer of pieces saved to disk since last announced
+            if self.announce_pieces(client, &mut announced).is_err() {
+                error!("Error: could not notify peer of downloaded pieces");
+                return;
+            }
+
+            // Receive a piece from work channel, until peer has none of the remaining pieces
+            let piece_work: PieceWork = match self.recv_work(client, &completed) {
+                Some(piece_work) => piece_work,
+                None => return,
             };
 
-            // Check if remote peer has piece
-            if !client.has_piece(piece_work.index) {
+            // Check if remote peer can send piece, did not send it corrupted before, and piece is not held back
+            if !self.can_request(client, &piece_work) {
                 // Resend piece to work channel
                 if self.work_chan.0.send(piece_work).is_err() {
                     error!("Error: could not send piece to channel");
                     return;
                 }
+
+                // Once every queued piece has been skipped, check if peer can still help
+                nb_skipped += 1;
+                if nb_skipped > self.work_chan.0.len() {
+                    if !self.has_missing_pieces(client, &completed) {
+                        self.lose_interest(client, &completed);
+                        return;
+                    }
+                    nb_skipped = 0;
+
+                    // Wait before looking for a piece again, until worker must stop
+                    if let Err(RecvTimeoutError::Disconnected) =
+                        self.done_chan.recv_timeout(SKIP_BACKOFF)
+                    {
+                        return;
+                    }
+                }
                 continue;
             }
+            nb_skipped = 0;
 
-            // Download piece
-            if self.download_piece(&mut client, &mut piece_work).is_err() {
-                // Resend piece to work channel
-                if self.work_chan.0.send(piece_work).is_err() {
-                    error!("Error: could not send piece to channel");
-                    return;
+            // Take more pieces that peer has, as long as in-flight slots are free
+            let mut slots: Vec<Slot> = vec![slot];
+            let mut pieces: Vec<PieceWork> = vec![piece_work];
+            while pieces.len() < self.config.max_pieces_per_peer {
+                // Take a free in-flight piece slot, without waiting
+                if self.inflight_chan.1.try_recv().is_err() {
+                    break;
                 }
+                let slot = Slot {
+                    chan: self.inflight_chan.0.clone(),
+                };
+
+                // Take a piece from work channel, without waiting
+                let piece_work: PieceWork = match self.work_chan.1.try_recv() {
+                    Ok(piece_work) => piece_work,
+                    Err(_) => break,
+                };
+
+                // Resend piece to work channel if remote peer can not send it, or piece is held back
+                if !self.can_request(client, &piece_work) {
+                    if self.work_chan.0.send(piece_work).is_err() {
+                        error!("Error: could not send piece to channel");
+                        return;
+                    }
+                    break;
+                }
+
+                slots.push(slot);
+                pieces.push(piece_work);
+            }
+
+            // Mark pieces as being downloaded
+            for piece_work in pieces.iter_mut() {
+                self.progress
+                    .set_state(piece_work.index, PieceState::InFlight);
+                piece_work.started = Some(Instant::now());
+                debug!("Peer {} started piece {:?}", self.peer, piece_work.index);
+            }
+
+            // Update interest in peer, as pieces are downloaded
+            if self.update_interest(client, &completed).is_err() {
+                // Resend pieces to work channel
+                self.resend_pieces(pieces);
                 return;
             }
 
-            // Verify piece integrity
-            if self.verify_piece_integrity(&mut piece_work).is_err() {
-                // Resend piece to work channel
-                if self.work_chan.0.send(piece_work).is_err() {
+            // Download pieces
+            match self.download_pieces(client, &mut pieces) {
+                Ok(DownloadOutcome::Complete) => {}
+                Ok(DownloadOutcome::Choked) => {
+                    // Give pieces back for other peers, then wait for peer to unchoke this client
+                    info!(
+                        "Peer {} kept client choked for {:?}, give back pieces {:?}",
+                        self.peer,
+                        self.config.choke_timeout,
+                        pieces.iter().map(|piece| piece.index).collect::<Vec<u32>>()
+                    );
+                    self.resend_pieces(pieces);
+                    drop(slots);
+                    if self.wait_for_unchoke(client, &completed).is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                Ok(DownloadOutcome::Rejected) => {
+                    // Give pieces back for other peers, the rejected piece not being requested again
+                    info!(
+                        "Peer {} kept rejecting requests, give back pieces {:?}",
+                        self.peer,
+                        pieces.iter().map(|piece| piece.index).collect::<Vec<u32>>()
+                    );
+                    self.resend_pieces(pieces);
+                    drop(slots);
+                    continue;
+                }
+                Err(_) => {
+                    // Free pieces data and resend pieces to work channel
+                    self.resend_pieces(pieces);
+                    return;
+                }
+            }
+
+            for (piece_work, slot) in pieces.into_iter().zip(slots) {
+                if let Some(started) = piece_work.started {
+                    debug!(
+                        "Peer {} finished piece {:?} in {:?} ms",
+                        self.peer,
+                        piece_work.index,
+                        started.elapsed().as_millis()
+                    );
+                }
+
+                // Mark piece as downloaded, to update interest in peer
+                completed.set_piece(piece_work.index);
+
+                // Send piece to be verified with its slot, without waiting for its hash
+                let job = VerifyJob::new(piece_work, self.peer.id, slot);
+                if self.verify_chan.send(job).is_err() {
                     error!("Error: could not send piece to channel");
                     return;
                 }
-                continue;
             }
+            if self.update_interest(client, &completed).is_err() {
+                error!("Error: could not update interest in peer");
+            }
+        }
+    }
 
-            // Notify peer that piece was downloaded
-            if client.send_have(piece_work.index).is_err() {
-                error!("Error: could not notify peer that piece was downloaded");
+    /// Receive from a channel, until worker must stop.
+    ///
+    /// # Arguments
+    ///
+    /// * `chan` - The channel to receive from.
+    ///
+    fn recv_until_done<T>(&self, chan: &Receiver<T>) -> Option<T> {
+        select! {
+            recv(chan) -> msg => match msg {
+                Ok(msg) => Some(msg),
+                Err(_) => {
+                    error!("Error: could not receive from channel");
+                    None
+                }
+            },
+            recv(self.done_chan) -> _ => None,
+        }
+    }
+
+    /// Receive a piece from work channel, until worker must stop or peer has none of the remaining pieces.
+    ///
+    /// Remaining pieces are checked again while waiting, as other workers download them.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - A client connected to a remote peer.
+    /// * `completed` - The bitfield of pieces downloaded by this worker.
+    ///
+    fn recv_work(&self, client: &mut Client, completed: &Bitfield) -> Option<PieceWork> {
+        loop {
+            select! {
+                recv(self.work_chan.1) -> msg => match msg {
+                    Ok(piece_work) => return Some(piece_work),
+                    Err(_) => {
+                        error!("Error: could not receive from channel");
+                        return None;
+                    }
+                },
+                recv(self.done_chan) -> _ => return None,
+                default(SKIP_BACKOFF) => {
+                    if !self.has_missing_pieces(client, completed) {
+                        self.lose_interest(client, completed);
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tell remote peer it has nothing more to offer, before worker stops.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - A client connected to a remote peer.
+    /// * `completed` - The bitfield of pieces downloaded by this worker.
+    ///
+    fn lose_interest(&self, client: &mut Client, completed: &Bitfield) {
+        if self.update_interest(client, completed).is_err() {
+            error!("Error: could not update interest in peer");
+        }
+        self.exhausted.set(true);
+        info!(
+            "Peer {} has none of the remaining pieces, stop worker",
+            self.peer
+        );
+    }
+
+    /// Wait while download is paused, sending keep-alives so peer keeps connection open.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - A client connected to a remote peer.
+    ///
+    fn wait_while_paused(&self, client: &mut Client) -> Result<()> {
+        let mut idle = Duration::from_secs(0);
+        while self.paused.load(Ordering::SeqCst) {
+            // Wait before checking again, until worker must stop
+            if let Err(RecvTimeoutError::Disconnected) =
+                self.done_chan.recv_timeout(PAUSE_POLL_INTERVAL)
+            {
+                return Err(anyhow!("worker stopped while paused"));
+            }
+
+            // Send keep-alive once in a while
+            idle += PAUSE_POLL_INTERVAL;
+            if idle >= KEEP_ALIVE_INTERVAL {
+                client.send_keep_alive()?;
+                idle = Duration::from_secs(0);
             }
+        }
+
+        Ok(())
+    }
+
+    /// Check if worker must stop.
+    fn is_done(&self) -> bool {
+        matches!(self.done_chan.try_recv(), Err(TryRecvError::Disconnected))
+    }
+
+    /// Check if a bitfield of peer has any piece that is not saved to disk yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `bitfield` - The bitfield of peer.
+    ///
+    fn is_useful(&self, bitfield: &Bitfield) -> bool {
+        let have = match self.have.lock() {
+            Ok(have) => have,
+            Err(_) => return false,
+        };
+        (0..self.nb_pieces).any(|index| !have.has_piece(index) && bitfield.has_piece(index))
+    }
+
+    /// Check if remote peer has any piece that is not downloaded yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - A client connected to a remote peer.
+    /// * `completed` - The bitfield of pieces downloaded by this worker.
+    ///
+    fn has_missing_pieces(&self, client: &Client, completed: &Bitfield) -> bool {
+        let have = match self.have.lock() {
+            Ok(have) => have,
+            Err(_) => return true,
+        };
+        (0..self.nb_pieces).any(|index| {
+            !have.has_piece(index) && !completed.has_piece(index) && client.has_piece(index)
+        })
+    }
+
+    /// Check if remote peer allows requesting any piece not downloaded yet while choking this client.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - A client connected to a remote peer.
+    /// * `completed` - The bitfield of pieces downloaded by this worker.
+    ///
+    fn has_allowed_fast_pieces(&self, client: &Client, completed: &Bitfield) -> bool {
+        let have = match self.have.lock() {
+            Ok(have) => have,
+            Err(_) => return false,
+        };
+        (0..self.nb_pieces).any(|index| {
+            !have.has_piece(index)
+                && !completed.has_piece(index)
+                && client.has_piece(index)
+                && client.is_allowed_fast(index)
+        })
+    }
+
+    /// Check if a piece can be requested from remote peer now.
+    ///
+    /// Peer must have the piece, not have sent it corrupted or kept rejecting it before, and allow
+    /// requesting it if it chokes this client. The piece must not be held back either.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - A client connected to a remote peer.
+    /// * `piece_work` - The piece to request.
+    ///
+    fn can_request(&self, client: &Client, piece_work: &PieceWork) -> bool {
+        client.has_piece(piece_work.index)
+            && !piece_work.failed_peers.contains(&self.peer.id)
+            && !piece_work.refused_peers.contains(&self.peer.id)
+            && (!client.is_choked() || client.is_allowed_fast(piece_work.index))
+            && self.progress.in_window(piece_work.index)
+    }
+
+    /// Notify remote peer of pieces saved to disk that were not announced yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - A client connected to a remote peer.
+    /// * `announced` - The bitfield of pieces already announced to remote peer.
+    ///
+    fn announce_pieces(&self, client: &mut Client, announced: &mut Bitfield) -> Result<()> {
+        let have: Bitfield = match self.have.lock() {
+            Ok(have) => have.clone(),
+            Err(_) => return Err(anyhow!("could not read bitfield")),
+        };
+        client.send_new_haves(&have, announced)
+    }
 
-            // Send piece to result channel
-            let piece_result =
-                PieceResult::new(piece_work.index, piece_work.length, piece_work.data);
-            if self.result_chan.0.send(piece_result).is_err() {
-                error!("Error: could not send piece to channel");
+    /// Update interest in remote peer, sending INTERESTED or NOT INTERESTED when it changes.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - A client connected to a remote peer.
+    /// * `completed` - The bitfield of pieces downloaded by this worker.
+    ///
+    fn update_interest(&self, client: &mut Client, completed: &Bitfield) -> Result<()> {
+        let interested = self.has_missing_pieces(client, completed);
+        if interested && !client.is_interested() {
+            client.send_interested()?;
+        } else if !interested && client.is_interested() {
+            client.send_not_interested()?;
+        }
+
+        Ok(())
+    }
+
+    /// Free data of pieces that could not be downloaded, and resend them to work channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `pieces` - The pieces to download again.
+    ///
+    fn resend_pieces(&self, pieces: Vec<PieceWork>) {
+        for piece_work in pieces {
+            if let Err(e) = piece_work.resend(&self.work_chan.0, &self.progress) {
+                error!("Error: {}", e);
                 return;
             }
         }
     }
 
-    /// Download a torrent piece.
+    /// Download torrent pieces at once from a remote peer.
+    ///
+    /// Requests are tracked per piece, and each block received is matched with its piece by index.
     ///
     /// # Arguments
     ///
     /// * `client` - A client connected to a remote peer.
-    /// * `piece_work` - A piece to download.
+    /// * `pieces` - The pieces to download.
     ///
-    fn download_piece(&self, client: &mut Client, piece_work: &mut PieceWork) -> Result<()> {
+    fn download_pieces(
+        &self,
+        client: &mut Client,
+        pieces: &mut [PieceWork],
+    ) -> Result<DownloadOutcome> {
+        // Check number of pieces downloaded at once from peer
+        if pieces.len() > self.config.max_pieces_per_peer {
+            return Err(anyhow!(
+                "could not download more than {:?} pieces at once from peer",
+                self.config.max_pieces_per_peer
+            ));
+        }
+
         // Set client connection timeout
         client.set_connection_timeout(120)?;
 
-        // Reset piece counters
-        piece_work.requests = 0;
-        piece_work.requested = 0;
-        piece_work.downloaded = 0;
+        for piece_work in pieces.iter_mut() {
+            // Allocate piece data
+            piece_work.data = vec![0; piece_work.length as usize];
+
+            // Reset piece counters
+            piece_work.requests = 0;
+            piece_work.requested = 0;
+            piece_work.downloaded = 0;
+            piece_work.rejected = vec![];
+            piece_work.rejects = 0;
+        }
+
+        // Time since peer keeps this client from requesting any block
+        let mut choked_since: Option<Instant> = None;
+
+        // Download torrent pieces
+        while pieces
+            .iter()
+            .any(|piece_work| piece_work.downloaded < piece_work.length)
+        {
+            let mut requests: Vec<(u32, u32, u32)> = vec![];
+
+            for piece_work in pieces.iter_mut() {
+                // Skip piece if client is choked by peer, and piece is not allowed while choked
+                if client.is_choked() && !client.is_allowed_fast(piece_work.index) {
+                    continue;
+                }
+
+                // Request again blocks rejected by peer, split to the current block size
+                let block_size_max = client.block_size();
+                while piece_work.requests < NB_REQUESTS_MAX {
+                    let (begin, mut length) = match piece_work.rejected.pop() {
+                        Some(block) => block,
+                        None => break,
+                    };
+                    if length > block_size_max {
+                        piece_work
+                            .rejected
+                            .push((begin + block_size_max, length - block_size_max));
+                        length = block_size_max;
+                    }
+                    requests.push((piece_work.index, begin, length));
+                    piece_work.requests += 1;
+                }
 
-        // Download torrent piece
-        while piece_work.downloaded < piece_work.length {
-            // If client is unchoked by peer
-            if !client.is_choked() {
                 while piece_work.requests < NB_REQUESTS_MAX
                     && piece_work.requested < piece_work.length
                 {
                     // Get block size to request
-                    let mut block_size = BLOCK_SIZE_MAX;
+                    let mut block_size = block_size_max;
                     let remaining = piece_work.length - piece_work.requested;
-                    if remaining < BLOCK_SIZE_MAX {
+                    if remaining < block_size_max {
                         block_size = remaining;
                     }
 
-                    // Send request for a block
-                    client.send_request(piece_work.index, piece_work.requested, block_size)?;
+                    // Add request for a block
+                    requests.push((piece_work.index, piece_work.requested, block_size));
 
                     // Update number of requests sent
                     piece_work.requests += 1;
@@ -202,50 +983,508 @@ impl Worker {
                 }
             }
 
-            // Listen peer
-            let message: Message = client.read_message()?;
+            // Send requests for all blocks at once
+            if !requests.is_empty() {
+                client.send_requests(&requests)?;
+            }
+
+            // Listen peer, until peer keeps this client choked for too long
+            let blocked = client.is_choked()
+                && !pieces.iter().any(|piece_work| {
+                    piece_work.downloaded < piece_work.length
+                        && client.is_allowed_fast(piece_work.index)
+                });
+            let message: MessageKind = if blocked {
+                let since = *choked_since.get_or_insert_with(Instant::now);
+                let remaining = self.config.choke_timeout.saturating_sub(since.elapsed());
+                if remaining.is_zero() {
+                    return Ok(DownloadOutcome::Choked);
+                }
+                match client.read_message_within(remaining)? {
+                    Some(message) => message,
+                    None => return Ok(DownloadOutcome::Choked),
+                }
+            } else {
+                choked_since = None;
+                client.read_message()?
+            };
+            let name = message.name();
+
+            // Parse message, a keep-alive only proving peer is still there
+            match message {
+                MessageKind::KeepAlive => {}
+                MessageKind::Choke => client.read_choke(),
+                MessageKind::Unchoke => client.read_unchoke(),
+                MessageKind::Interested => client.read_interested(),
+                MessageKind::NotInterested => client.read_not_interested(),
+                MessageKind::Have(index) => {
+                    // Count piece only if peer did not have it yet
+                    let had_piece = client.has_piece(index);
+                    client.read_have(index)?;
+                    if !had_piece && client.has_piece(index) {
+                        self.progress.add_piece(index);
+                    }
+                }
+                MessageKind::Piece {
+                    index,
+                    begin,
+                    block,
+                } => match pieces
+                    .iter_mut()
+                    .find(|piece_work| piece_work.index == index)
+                {
+                    Some(piece_work) => {
+                        client.read_piece(index, begin, &block, piece_work)?;
+                        self.stats.add_downloaded(block.len() as u64);
+                        client.recycle_block(block);
+                    }
+                    None => {
+                        return Err(anyhow!(
+                            "received MESSAGE_PIECE for piece {:?} not requested from peer",
+                            index
+                        ))
+                    }
+                },
+                MessageKind::RejectRequest {
+                    index,
+                    begin,
+                    length,
+                } => match pieces
+                    .iter_mut()
+                    .find(|piece_work| piece_work.index == index)
+                {
+                    Some(piece_work) => {
+                        client.read_reject_request(index, begin, length, piece_work)?;
 
-            // Parse message
-            match message.id {
-                MESSAGE_CHOKE => client.read_choke(),
-                MESSAGE_UNCHOKE => client.read_unchoke(),
-                MESSAGE_HAVE => client.read_have(message)?,
-                MESSAGE_PIECE => client.read_piece(message, piece_work)?,
-                _ => info!("received unknown message from peer"),
+                        // Give piece back once peer kept rejecting it, not to request it forever
+                        if piece_work.rejects >= MAX_REJECTS_PER_PIECE {
+                            piece_work.refused_peers.push(self.peer.id);
+                            return Ok(DownloadOutcome::Rejected);
+                        }
+                    }
+                    None => info!(
+                        "Ignore MESSAGE_REJECT_REQUEST for piece {:?} from peer {}",
+                        index, self.peer
+                    ),
+                },
+                MessageKind::AllowedFast(index) => client.read_allowed_fast(index)?,
+                // Requests are only served to incoming peers, and pieces of peer are already known
+                MessageKind::Request { .. }
+                | MessageKind::Bitfield(_)
+                | MessageKind::HaveAll
+                | MessageKind::HaveNone
+                | MessageKind::Unknown(_) => info!("Ignore {} from peer {}", name, self.peer),
             }
         }
 
-        info!("Successfully downloaded piece {:?}", piece_work.index);
+        for piece_work in pieces.iter() {
+            info!("Successfully downloaded piece {:?}", piece_work.index);
+        }
 
-        Ok(())
+        Ok(DownloadOutcome::Complete)
     }
 
-    /// Verify the integrity of a downloaded torrent piece.
+    /// Wait for peer to unchoke this client, following pieces it announces meanwhile.
+    ///
+    /// Waiting stops early once peer allows requesting a piece not downloaded yet while choked.
     ///
     /// # Arguments
     ///
-    /// * `piece_work` - A piece to download.
+    /// * `client` - A client connected to a remote peer.
+    /// * `completed` - The bitfield of pieces downloaded by this worker.
     ///
-    fn verify_piece_integrity(&self, piece_work: &mut PieceWork) -> Result<()> {
-        // Hash piece data
-        let mut hasher = Sha1::new();
-        hasher.update(&piece_work.data);
+    fn wait_for_unchoke(&self, client: &mut Client, completed: &Bitfield) -> Result<()> {
+        // Set client connection timeout, as while downloading
+        client.set_connection_timeout(120)?;
 
-        // Read hash digest
-        let hash = hasher.finish().to_vec();
+        while client.is_choked() && !self.has_allowed_fast_pieces(client, completed) {
+            let message = client.read_message()?;
+            let name = message.name();
+            match message {
+                MessageKind::KeepAlive => {}
+                MessageKind::Choke => client.read_choke(),
+                MessageKind::Unchoke => client.read_unchoke(),
+                MessageKind::Interested => client.read_interested(),
+                MessageKind::NotInterested => client.read_not_interested(),
+                MessageKind::Have(index) => {
+                    // Count piece only if peer did not have it yet
+                    let had_piece = client.has_piece(index);
+                    client.read_have(index)?;
+                    if !had_piece && client.has_piece(index) {
+                        self.progress.add_piece(index);
+                    }
+                }
+                MessageKind::AllowedFast(index) => client.read_allowed_fast(index)?,
+                // Blocks requested before choke are not needed anymore
+                _ => info!("Ignore {} from peer {}", name, self.peer),
+            }
+        }
 
-        // Compare hashes
-        if hash != piece_work.hash {
-            return Err(anyhow!(
-                "could not verify integrity of piece downloaded from peer"
-            ));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::handshake::*;
+
+    use crossbeam_channel::unbounded;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    fn new_worker() -> Worker {
+        let (verify_tx, _) = unbounded();
+        let (_, done_chan) = bounded(0);
+        Worker::new(
+            Peer::new(),
+            vec![],
+            vec![],
+            1,
+            TorrentConfig::default(),
+            unbounded(),
+            verify_tx,
+            bounded(1),
+            bounded(1),
+            bounded(1),
+            Arc::new(Mutex::new(Bitfield::new(1))),
+            Arc::new(AtomicBool::new(false)),
+            done_chan,
+            Arc::new(Mutex::new(vec![])),
+            Progress::new(),
+            Stats::new(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn download_piece_with_keep_alive_between_blocks() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut remote = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        let mut client = Client::from_stream(Peer::new(), vec![], vec![], 1, conn);
+        client.read_unchoke();
+
+        // Serve both requested blocks, with a keep-alive in between
+        let peer = std::thread::spawn(move || {
+            let mut request = [0; 17];
+            for _ in 0..2 {
+                remote.read_exact(&mut request).unwrap();
+            }
+            for (begin, keep_alive) in [(0, true), (16384, false)] {
+                let block = MessageKind::Piece {
+                    index: 0,
+                    begin,
+                    block: vec![0xAB; 16384],
+                };
+                remote.write_all(&block.serialize().unwrap()).unwrap();
+                if keep_alive {
+                    remote
+                        .write_all(&MessageKind::KeepAlive.serialize().unwrap())
+                        .unwrap();
+                }
+            }
+            remote
+        });
+
+        let worker = new_worker();
+        let mut pieces = vec![PieceWork::new(0, vec![], 32768)];
+        let outcome = worker.download_pieces(&mut client, &mut pieces).unwrap();
+        assert!(matches!(outcome, DownloadOutcome::Complete));
+        assert_eq!(pieces[0].data, vec![0xAB; 32768]);
+        peer.join().unwrap();
+    }
+
+    // Client connected to a remote peer supporting the fast extension, and unchoking this client
+    fn fast_client(nb_pieces: u32) -> (Client, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut remote = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        let mut client =
+            Client::from_stream(Peer::new(), vec![0; 20], vec![0xAB; 20], nb_pieces, conn);
+        let handshake = Handshake::new(vec![1; 20], vec![0xAB; 20]);
+        remote.write_all(&handshake.serialize().unwrap()).unwrap();
+        client.accept_handshake().unwrap();
+        let mut handshake = [0; 68];
+        remote.read_exact(&mut handshake).unwrap();
+        client.read_unchoke();
+        (client, remote)
+    }
+
+    // Read a request from client, as (index, begin, length)
+    fn read_request(remote: &mut TcpStream) -> (u32, u32, u32) {
+        let mut request = [0; 17];
+        remote.read_exact(&mut request).unwrap();
+        assert_eq!(request[4], MESSAGE_REQUEST);
+        let field = |i: usize| u32::from_be_bytes(request[i..i + 4].try_into().unwrap());
+        (field(5), field(9), field(13))
+    }
+
+    #[test]
+    fn request_rejected_block_again() {
+        let (mut client, mut remote) = fast_client(1);
+
+        // Rejecting an outstanding block frees it to be requested again
+        let mut piece_work = PieceWork::new(0, vec![], 16384);
+        piece_work.requests = 2;
+        piece_work.requested = 16384;
+        client
+            .read_reject_request(0, 0, 8192, &mut piece_work)
+            .unwrap();
+        assert_eq!(piece_work.requests, 1);
+        assert_eq!(piece_work.rejected, vec![(0, 8192)]);
+        assert_eq!(piece_work.rejects, 1);
+
+        // Reject the single block once, then serve it requested again in smaller blocks
+        let peer = std::thread::spawn(move || {
+            let (index, begin, length) = read_request(&mut remote);
+            let reject = MessageKind::RejectRequest {
+                index,
+                begin,
+                length,
+            };
+            remote.write_all(&reject.serialize().unwrap()).unwrap();
+            for expected in [(0, 0, 8192), (0, 8192, 8192)] {
+                assert_eq!(read_request(&mut remote), expected);
+            }
+            for begin in [0, 8192] {
+                let block = MessageKind::Piece {
+                    index: 0,
+                    begin,
+                    block: vec![0xAB; 8192],
+                };
+                remote.write_all(&block.serialize().unwrap()).unwrap();
+            }
+            remote
+        });
+
+        let worker = new_worker();
+        let mut pieces = vec![PieceWork::new(0, vec![], 16384)];
+        let outcome = worker.download_pieces(&mut client, &mut pieces).unwrap();
+        assert!(matches!(outcome, DownloadOutcome::Complete));
+        assert_eq!(pieces[0].data, vec![0xAB; 16384]);
+        assert!(pieces[0].refused_peers.is_empty());
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn give_back_piece_rejected_by_peer() {
+        let (mut client, mut remote) = fast_client(1);
+        client.restore_bitfield({
+            let mut bitfield = Bitfield::new(1);
+            bitfield.set_piece(0);
+            bitfield
+        });
+
+        // Reject every request, until client gives piece back
+        let peer = std::thread::spawn(move || {
+            for _ in 0..MAX_REJECTS_PER_PIECE {
+                let (index, begin, length) = read_request(&mut remote);
+                let reject = MessageKind::RejectRequest {
+                    index,
+                    begin,
+                    length,
+                };
+                remote.write_all(&reject.serialize().unwrap()).unwrap();
+            }
+            remote
+        });
+
+        let worker = new_worker();
+        let mut pieces = vec![PieceWork::new(0, vec![], 16384)];
+        let outcome = worker.download_pieces(&mut client, &mut pieces).unwrap();
+        assert!(matches!(outcome, DownloadOutcome::Rejected));
+        assert_eq!(pieces[0].rejects, MAX_REJECTS_PER_PIECE);
+
+        // Piece is not requested again from peer
+        assert_eq!(pieces[0].refused_peers, vec![worker.peer.id]);
+        assert!(!worker.can_request(&client, &pieces[0]));
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn download_allowed_fast_piece_while_choked() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut remote = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        let mut client = Client::from_stream(Peer::new(), vec![0; 20], vec![0xAB; 20], 2, conn);
+
+        // Peer announces fast extension in handshake
+        let handshake = Handshake::new(vec![1; 20], vec![0xAB; 20]);
+        remote.write_all(&handshake.serialize().unwrap()).unwrap();
+        client.accept_handshake().unwrap();
+        let mut handshake = [0; 68];
+        remote.read_exact(&mut handshake).unwrap();
+        let mut bitfield = Bitfield::new(2);
+        bitfield.set_piece(0);
+        bitfield.set_piece(1);
+        client.restore_bitfield(bitfield);
+
+        // Keep client choked, only allowing piece 1 and serving its single block
+        let peer = std::thread::spawn(move || {
+            let mut unchoke_interested = [0; 10];
+            remote.read_exact(&mut unchoke_interested).unwrap();
+            remote
+                .write_all(&MessageKind::AllowedFast(1).serialize().unwrap())
+                .unwrap();
+            let mut request = [0; 17];
+            remote.read_exact(&mut request).unwrap();
+            assert_eq!(request[4], MESSAGE_REQUEST);
+            assert_eq!(&request[5..9], &[0, 0, 0, 1]);
+            let block = MessageKind::Piece {
+                index: 1,
+                begin: 0,
+                block: vec![0xAB; 16384],
+            };
+            remote.write_all(&block.serialize().unwrap()).unwrap();
+        });
+
+        let (verify_tx, verify_rx) = unbounded();
+        let (_done, done_chan) = bounded(0);
+        let work_chan = unbounded();
+        // Verified piece holds its slot, leave another one for the next piece
+        let inflight_chan = bounded(2);
+        inflight_chan.0.send(()).unwrap();
+        inflight_chan.0.send(()).unwrap();
+        let worker = Worker::new(
+            Peer::new(),
+            vec![],
+            vec![],
+            2,
+            TorrentConfig::default(),
+            work_chan.clone(),
+            verify_tx,
+            inflight_chan,
+            bounded(1),
+            bounded(1),
+            Arc::new(Mutex::new(Bitfield::new(2))),
+            Arc::new(AtomicBool::new(false)),
+            done_chan,
+            Arc::new(Mutex::new(vec![])),
+            Progress::new(),
+            Stats::new(),
+        )
+        .unwrap();
+        for index in 0..2 {
+            work_chan
+                .0
+                .send(PieceWork::new(index, vec![], 16384))
+                .unwrap();
         }
 
-        info!(
-            "Successfully verified integrity of piece {:?}",
-            piece_work.index
-        );
+        // Allowed piece is downloaded, then worker waits for unchoke until peer leaves
+        worker.download_from_peer(&mut client, Bitfield::new(2));
+        peer.join().unwrap();
+        assert_eq!(verify_rx.try_iter().count(), 1);
+        assert_eq!(work_chan.1.try_recv().unwrap().index, 0);
+        assert!(work_chan.1.try_recv().is_err());
+    }
 
-        Ok(())
+    #[test]
+    fn report_panicked_worker_when_stopped() {
+        let mut workers = WorkerPool::new();
+        let done_chan = workers.done_chan();
+        workers
+            .threads
+            .push(thread::spawn(|| panic!("worker failed")));
+        workers.threads.push(thread::spawn(move || {
+            let _ = done_chan.recv();
+        }));
+
+        // Pool waits for workers told to stop, without propagating a panic
+        let started = Instant::now();
+        drop(workers);
+        assert!(started.elapsed() < STOP_TIMEOUT);
+
+        let panic = thread::spawn(|| panic!("worker {} failed", 1))
+            .join()
+            .unwrap_err();
+        assert_eq!(panic_message(&panic), "worker 1 failed");
+    }
+
+    #[test]
+    fn close_registered_connection_when_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut remote = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        let client = Client::from_stream(Peer::new(), vec![], vec![], 8, conn);
+        let other = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let conns = Mutex::new(vec![other]);
+
+        let registered = RegisteredConn::new(&client, &conns).unwrap();
+        assert_eq!(conns.lock().unwrap().len(), 2);
+        drop(registered);
+
+        // Only the connection of the client is forgotten, and peer sees it closed
+        assert_eq!(conns.lock().unwrap().len(), 1);
+        let mut buf = [0; 1];
+        assert_eq!(remote.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn share_connected_peer_slots_between_torrents() {
+        // Workers of two torrents share a single connected peer slot
+        let peer_chan = bounded(1);
+        peer_chan.0.send(()).unwrap();
+        let (_done, done_chan) = bounded::<()>(0);
+        let spawn_worker = |listener: &TcpListener, info_hash: Vec<u8>| {
+            let addr = listener.local_addr().unwrap();
+            let connect_chan = bounded(1);
+            connect_chan.0.send(()).unwrap();
+            let (verify_tx, _) = unbounded();
+            let worker = Worker::new(
+                Peer {
+                    id: 0,
+                    ip: addr.ip(),
+                    port: addr.port(),
+                    seed: None,
+                },
+                vec![0; 20],
+                info_hash,
+                1,
+                TorrentConfig::default(),
+                unbounded(),
+                verify_tx,
+                bounded(1),
+                connect_chan,
+                peer_chan.clone(),
+                Arc::new(Mutex::new(Bitfield::new(1))),
+                Arc::new(AtomicBool::new(false)),
+                done_chan.clone(),
+                Arc::new(Mutex::new(vec![])),
+                Progress::new(),
+                Stats::new(),
+            )
+            .unwrap();
+            thread::spawn(move || worker.start_download())
+        };
+
+        // First torrent connects to its peer, holding the slot while connected
+        let first_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let first = spawn_worker(&first_listener, vec![1; 20]);
+        let (first_conn, _) = first_listener.accept().unwrap();
+
+        // Second torrent waits for the slot rather than connecting to its peer
+        let second_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        second_listener.set_nonblocking(true).unwrap();
+        let second = spawn_worker(&second_listener, vec![2; 20]);
+        thread::sleep(Duration::from_millis(300));
+        assert!(second_listener.accept().is_err());
+
+        // Slot is given back once first peer is disconnected
+        drop(first_conn);
+        first.join().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let second_conn = loop {
+            match second_listener.accept() {
+                Ok((conn, _)) => break conn,
+                Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                Err(e) => panic!("second torrent did not connect: {}", e),
+            }
+        };
+        drop(second_conn);
+        second.join().unwrap();
+        assert!(peer_chan.1.try_recv().is_ok());
     }
 }